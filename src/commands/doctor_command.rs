@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use clap::Parser;
+use console::style;
+
+use crate::commands::RootOpts;
+use crate::error::Result;
+use crate::models::PortAllocation;
+use crate::utils::diagnosis::{diagnose, FamilyReport, Mapping, Outlook, RTT_PROBES, verdict};
+use crate::utils::socket::resolve;
+
+#[derive(Parser, Debug)]
+pub struct DoctorOpts {}
+
+/// Run the `doctor` command to find out which ways to connect to a peer work from this machine.
+pub fn run(root_opts: &RootOpts, _doctor_opts: &DoctorOpts) -> Result<()> {
+    println!(
+        "{} Probing the relay-server {} (port {})...",
+        style("[~]").bold().yellow(),
+        style(&root_opts.relay_host).cyan(),
+        root_opts.relay_port
+    );
+    let relay_addrs = resolve(&root_opts.relay_host, root_opts.relay_port)?;
+    debug!("Relay-server addresses: {:?}", relay_addrs);
+    let diagnosis = diagnose(&relay_addrs)?;
+
+    print_family("IPv4", diagnosis.ipv4.as_ref());
+    print_family("IPv6", diagnosis.ipv6.as_ref());
+    if diagnosis.global_ipv6.is_empty() {
+        println!("{} This machine has no global IPv6 address", style("[i]").bold().blue());
+    } else {
+        let ips: Vec<String> = diagnosis.global_ipv6.iter().map(ToString::to_string).collect();
+        println!(
+            "{} Global IPv6 addresses: {}",
+            style("[i]").bold().blue(),
+            style(ips.join(", ")).dim()
+        );
+    }
+
+    println!();
+    println!("{}", style("Verdict:").bold());
+    for strategy in verdict(&diagnosis) {
+        let symbol = match strategy.outlook {
+            Outlook::Works => style("[✔]").bold().green(),
+            Outlook::Depends => style("[~]").bold().yellow(),
+            Outlook::Fails => style("[✗]").bold().red(),
+            Outlook::Unknown => style("[?]").bold().dim(),
+        };
+        println!("{} {}: {}", symbol, style(strategy.name).bold(), strategy.reason);
+    }
+    Ok(())
+}
+
+/// Prints what the probes over one address family showed
+fn print_family(family: &str, report: Option<&FamilyReport>) {
+    let Some(report) = report else {
+        println!("{} {}: the relay-server has no {} address", style("[i]").bold().blue(), family, family);
+        return;
+    };
+    let Some(public) = report.public else {
+        println!(
+            "{} {}: the relay-server at {} does not answer",
+            style("[✗]").bold().red(),
+            family,
+            style(report.relay_addr).dim()
+        );
+        return;
+    };
+
+    let min = report.rtts.iter().min().copied().unwrap_or_default();
+    let mean = report.rtts.iter().sum::<Duration>() / report.rtts.len().max(1) as u32;
+    println!(
+        "{} {}: the relay-server at {} answered {}/{} probes (RTT min {:.1}ms, mean {:.1}ms)",
+        style("[✔]").bold().green(),
+        family,
+        style(report.relay_addr).dim(),
+        report.rtts.len(),
+        RTT_PROBES,
+        min.as_secs_f64() * 1000.0,
+        mean.as_secs_f64() * 1000.0
+    );
+    println!("    Public address: {} (local port {})", style(public).cyan(), report.local_port);
+    println!("    NAT: {}", describe_mapping(report.mapping));
+    if let Some(allocation) = report.allocation {
+        println!("    Port allocation: {}", describe_allocation(allocation));
+    }
+    if let Some(hairpin) = report.hairpin {
+        println!("    Hairpinning: {}", if hairpin { "supported" } else { "not supported" });
+    }
+}
+
+fn describe_mapping(mapping: Mapping) -> &'static str {
+    match mapping {
+        Mapping::NoNat => "none, the relay-server sees the address of this machine",
+        Mapping::EndpointIndependent => "endpoint-independent, the same public port for every destination",
+        Mapping::EndpointDependent => "symmetric, another public port for every destination",
+        Mapping::Unknown => "unknown, the second port of the relay-server does not answer",
+    }
+}
+
+fn describe_allocation(allocation: PortAllocation) -> String {
+    match allocation {
+        PortAllocation::Preserving => "preserving, the public port is the local port".to_string(),
+        PortAllocation::Sequential { delta, .. } => format!("sequential, in steps of {:+}", delta),
+        PortAllocation::Random => "random".to_string(),
+    }
+}
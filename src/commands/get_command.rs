@@ -1,27 +1,49 @@
-use std::fs::OpenOptions;
-use std::io::{Seek, Write};
-use std::net::{Ipv4Addr, UdpSocket};
-use std::path::Path;
+use std::fs::File;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::thread;
 
 use clap::Parser;
 use console::style;
 use dialoguer::Confirm;
 use humansize::{DECIMAL, format_size};
-use crate::commands::RootOpts;
+use indicatif::ProgressBar;
+use crate::commands::{print_routes, RootOpts};
+use crate::config::Config;
 
 use crate::error::NudgeError;
-use crate::models::FileInfo;
+use crate::models::{FileInfo, LocalCandidates, X2SSenderConnectToReceiverMessage};
+use crate::models::R2XRegisterStreamMessage;
 use crate::models::R2XRequestSenderConnectionMessage;
 use crate::models::R2XRequestFileInfoMessage;
+use crate::models::X2RStreamRegisteredMessage;
 use crate::utils::passphrase::Passphrase;
-use crate::utils::reliable_udp::ReliableUdpSocket;
-use crate::utils::{current_unix_millis, hash_file_and_seek};
+use crate::utils::reliable_udp::{MetadataKind, ReliableUdpSocket};
+use crate::utils::reliable_udp::stats::TransferStats;
+use crate::utils::report::{Role, StatsFormat, TransferReport};
+use crate::utils::attributes::FileAttributes;
+use crate::utils::current_unix_millis;
+use crate::utils::delta::{Basis, DeltaOp, MIN_DELTA_CHUNK_SIZE};
+use crate::utils::disk::available_space;
+use crate::utils::file_name::{path_in_dir, sanitize_file_name};
 use crate::utils::hide_or_get_hostname;
+use crate::utils::mtu::{MAX_CHUNK_SIZE, negotiate_chunk_size_as_receiver};
 use crate::utils::new_downloader_progressbar;
+use crate::utils::part_file::{part_path, PartFile};
 use crate::utils::question_theme;
-use crate::utils::DEFAULT_CHUNK_SIZE;
-use crate::utils::serialize::{receive_and_parse_and_expect, serialize_and_send};
-use crate::utils::socket::init_socket;
+use crate::utils::rate::Rate;
+use crate::utils::size::ByteSize;
+use crate::utils::serialize::{receive_and_parse_and_expect, serialize_and_send, serialize_and_send_to};
+use crate::utils::discovery;
+use crate::utils::interfaces::{local_ips, shared_ips};
+use crate::utils::socket::{
+    bind_dual_stack, connect_to_host, connect_to_peer, coordinate_punch, peer_candidates, reachable_addr, split_host_port,
+    PeerRoute, PunchPlan, Rendezvous,
+};
+use crate::utils::streams::{join_streams, RANGE_ALIGNMENT, split_ranges};
+use crate::utils::verify::{Outboard, RangeVerifier};
+use crate::utils::writer::ChunkWriter;
 
 #[derive(Parser, Debug)]
 pub struct GetOpts {
@@ -32,8 +54,15 @@ pub struct GetOpts {
     #[clap(short = 'o', long)]
     out_file: Option<String>,
 
-    #[clap(short, long, default_value = "500")]
-    delay: u64,
+    /// Directory to save the file in (optional)
+    ///
+    /// The output file never ends up outside of it, an output file passed with -o is taken relative to it.
+    #[clap(long)]
+    out_dir: Option<PathBuf>,
+
+    /// Has no effect, the sending rate is controlled by the sender
+    #[clap(short, long, hide = true)]
+    delay: Option<u64>,
 
     /// If enabled, won't ask for confirmation before downloading the file
     #[clap(short, long, default_value = "false")]
@@ -57,56 +86,139 @@ pub struct GetOpts {
     #[clap(long, default_value = "false")]
     skip_hash: bool,
 
-    /// Chunk size to read from the socket
-    #[clap(short, long, default_value = DEFAULT_CHUNK_SIZE)]
-    chunk_size: u32,
+    /// Chunk size in bytes (optional)
+    ///
+    /// By default, the chunk size is negotiated with the sender. If both sides set one, they have to match.
+    #[clap(short, long, value_parser = clap::value_parser!(u32).range(1..=MAX_CHUNK_SIZE as i64))]
+    chunk_size: Option<u32>,
+
+    /// Bandwidth limit the sender is asked to respect, e.g. 5MB/s (optional)
+    #[clap(long)]
+    limit: Option<Rate>,
+
+    /// Format of the statistics printed after the transfer
+    #[clap(long, value_enum, default_value = "text")]
+    stats: StatsFormat,
+
+    /// Older version of the file to rebuild the new one from (optional)
+    ///
+    /// Only the blocks which differ from it are transferred. It must not be the output file.
+    #[clap(long)]
+    basis: Option<PathBuf>,
+
+    /// If enabled, keeps the .nudge-part file of a failed transfer instead of removing it
+    ///
+    /// The data is written to it and only renamed to the output file once the transfer succeeded.
+    #[clap(long, default_value = "false")]
+    keep_partial: bool,
+
+    /// If enabled, won't apply the permissions, times and extended attributes of the sent file
+    ///
+    /// setuid and setgid bits are never applied.
+    #[clap(long, default_value = "false")]
+    no_preserve: bool,
+
+    /// Largest file to accept, e.g. 10GB (optional)
+    ///
+    /// Larger files are rejected without asking, even with -f. Overrides the limit of the config file.
+    #[clap(long)]
+    max_size: Option<ByteSize>,
+
+    /// If enabled, finds the sender on the local network instead of through the relay-server
+    ///
+    /// The sender has to use send --local.
+    #[clap(long, default_value = "false")]
+    local: bool,
+
+    /// Address of a sender to connect to directly, e.g. host:9000 (optional)
+    ///
+    /// Skips the relay-server and hole punching, the sender has to use send --listen.
+    #[clap(long, conflicts_with = "local")]
+    connect: Option<String>,
 }
 
 
 /// Run the `get` command to download a file using the provided options.
 pub fn run(root_opts: &RootOpts, get_opts: &GetOpts) -> Result<(), NudgeError> {
-    let local_bind_address = (Ipv4Addr::from(0u32), 0);
-    debug!("Binding UDP socket to local address: {:?}", local_bind_address);
-    let socket = UdpSocket::bind(local_bind_address)?;
+    let config = Config::load(root_opts.config.as_deref())?;
+    let max_size = get_opts.max_size.or(config.get.max_size);
 
-    let relay_address = format!("{}:{}", root_opts.relay_host, root_opts.relay_port);
-    debug!("Connecting to relay-server: {}...", relay_address);
-    socket.connect(relay_address)?;
+    let socket = bind_dual_stack()?;
+    debug!("Bound UDP socket to local address: {}", socket.local_addr()?);
 
-    // Send request for file information
     let passphrase = Passphrase::from(get_opts.passphrase.clone());
-    debug!("Sending R2XRequestFileInfoMessage with passphrase: {}...", passphrase.0);
-    serialize_and_send(&socket, "R2X_RFI", &R2XRequestFileInfoMessage {
-        passphrase: passphrase.clone(),
-    })?;
-
-    debug!("Waiting for FileInfo...");
-    let file_info: FileInfo = receive_and_parse_and_expect(&socket, "X2R_AFI")?;
+    let file_info: FileInfo = if let Some(connect) = &get_opts.connect {
+        let (host, port) = split_host_port(connect)?;
+        let sender_addr = reachable_addr(&socket, host, port)?;
+        debug!("Connecting to the sender at {}...", sender_addr);
+        discovery::connect_to_sender(&socket, &passphrase, sender_addr)?
+    } else if get_opts.local {
+        debug!("Looking for the sender on the local network...");
+        discovery::find_session(&socket, &passphrase)?
+    } else {
+        debug!("Connecting to relay-server: {} (port {})...", root_opts.relay_host, root_opts.relay_port);
+        connect_to_host(&socket, &root_opts.relay_host, root_opts.relay_port)?;
+
+        // Send request for file information
+        debug!("Sending R2XRequestFileInfoMessage with passphrase: {}...", passphrase.0);
+        serialize_and_send(&socket, "R2X_RFI", &R2XRequestFileInfoMessage {
+            passphrase: passphrase.clone(),
+        })?;
+
+        debug!("Waiting for FileInfo...");
+        receive_and_parse_and_expect(&socket, "X2R_AFI")?
+    };
     debug!("Received FileInfo: {:?}", file_info);
 
+    // The name is chosen by the sender, it is never shown or used without sanitizing it
+    let sanitized_name = sanitize_file_name(&file_info.file_name);
     println!(
         "{} Meta: {} by {} [{}]",
         style("[✔]").bold().green(),
-        style(&file_info.file_name).yellow(),
+        style(sanitized_name.as_deref().unwrap_or("<invalid name>")).yellow(),
         style(&file_info.sender_host).cyan(),
         format_size(file_info.file_size, DECIMAL)
     );
+    if sanitized_name.as_ref().is_ok_and(|name| *name != file_info.file_name) {
+        println!(
+            "{} The file name was sanitized, it was sent as {}",
+            style("[i]").bold().blue(),
+            style(format!("{:?}", file_info.file_name)).dim()
+        );
+    }
 
-    let out_file_name = get_opts.out_file.as_deref().unwrap_or_else(|| {
-        // Use the file name from the sender if output file is not specified
-        file_info.file_name.split("/").last().expect("File name is empty")
-    });
+    // oversized offers are rejected before anything is asked
+    if let Some(max_size) = max_size.filter(|max_size| file_info.file_size > max_size.0) {
+        println!(
+            "{} The file exceeds the maximum size of {}, rejecting it",
+            style("[✗]").bold().red(),
+            style(max_size).yellow()
+        );
+        return Err(NudgeError::FileTooLarge(file_info.file_size, max_size));
+    }
+
+    let out_path = output_path(get_opts, sanitized_name)?;
+    println!(
+        "{} Saving to {}",
+        style("[i]").bold().blue(),
+        style(out_path.display()).yellow()
+    );
+
+    if let Some(basis_path) = &get_opts.basis {
+        check_basis_is_not_output(basis_path, &out_path)?;
+    }
+    check_available_space(&out_path, file_info.file_size)?;
 
     // Check if the file already exists and ask for confirmation to overwrite
-    if !get_opts.overwrite_file && Path::new(out_file_name).exists() {
+    if !get_opts.overwrite_file && out_path.exists() {
         if get_opts.no_prompt {
-            println!("File {} already exists. Use -o <file> to specify a different output file.", out_file_name);
+            println!("File {} already exists. Use -o <file> to specify a different output file.", out_path.display());
             return Err(NudgeError::NoPromptExit);
         }
 
         // Ask for confirmation to overwrite the file
         if !Confirm::with_theme(&question_theme())
-            .with_prompt(format!("File {} already exists. Overwrite?", out_file_name))
+            .with_prompt(format!("File {} already exists. Overwrite?", out_path.display()))
             .interact()
             .unwrap()
         {
@@ -134,13 +246,43 @@ pub fn run(root_opts: &RootOpts, get_opts: &GetOpts) -> Result<(), NudgeError> {
         }
     }
 
-    let mut file = OpenOptions::new()
-        .truncate(false)
-        .write(true)
-        .create(true)
-        .read(true)
-        .open(out_file_name)?;
-    file.set_len(file_info.file_size)?;
+    let basis = get_opts.basis.as_deref()
+        .map(|basis_path| {
+            println!(
+                "{} Computing block signatures of {}...",
+                style("[~]").bold().yellow(),
+                style(basis_path.display()).yellow()
+            );
+            Basis::open(basis_path)
+        })
+        .transpose()?;
+
+    // The data goes to a temporary file, a failed transfer leaves the output file untouched
+    // -o may name a subdirectory of the output directory, which is created along with it
+    if get_opts.out_dir.is_some() {
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    let part_file = PartFile::create(&out_path, file_info.file_size, get_opts.keep_partial)?;
+    debug!("Writing to {}", part_file.path().display());
+    let file = part_file.file();
+
+    // The sender decides how many streams are used, every one of them needs its own socket on our side
+    if file_info.sender_stream_addrs.len() + 1 != file_info.streams as usize {
+        return Err(NudgeError::StreamsIncomplete);
+    }
+    // without a relay-server, the sender learns the addresses of our sockets from our request
+    let without_relay = get_opts.local || get_opts.connect.is_some();
+    let mut sockets = vec![socket];
+    for stream_index in 1..file_info.streams {
+        let stream_socket = bind_dual_stack()?;
+        if !without_relay {
+            connect_to_host(&stream_socket, &root_opts.relay_host, root_opts.relay_port)?;
+            register_stream(&stream_socket, &passphrase, stream_index)?;
+        }
+        sockets.push(stream_socket);
+    }
 
     // Request sender to connect
     let hostname = hide_or_get_hostname(get_opts.hide_hostname)?;
@@ -148,11 +290,33 @@ pub fn run(root_opts: &RootOpts, get_opts: &GetOpts) -> Result<(), NudgeError> {
         "Requesting sender to connect to us ({})...",
         hostname
     );
-    serialize_and_send(&socket, "R2X_RSC", &R2XRequestSenderConnectionMessage {
-        passphrase,
-        file_hash: file_info.file_hash.clone(),
-        receiver_host: hostname,
-    })?;
+    let limit = get_opts.limit.and_then(|limit| limit.bytes_per_sec());
+    let local_ips = if without_relay {
+        // the sender learns our IP from the packet, only the ports are sent
+        serialize_and_send_to(&sockets[0], file_info.sender_addr, "R2S_SCON", &X2SSenderConnectToReceiverMessage {
+            receiver_addr: sockets[0].local_addr()?,
+            receiver_host: hostname,
+            receiver_stream_addrs: sockets[1..].iter().map(UdpSocket::local_addr).collect::<std::io::Result<_>>()?,
+            limit,
+            basis: basis.is_some(),
+            receiver_local: LocalCandidates::default(),
+            same_network: true,
+        })?;
+        Vec::new()
+    } else {
+        // A sender in the same network can reach us through our local addresses
+        let local_ips = local_ips(&sockets[0]);
+        debug!("Local addresses: {:?}", local_ips);
+        serialize_and_send(&sockets[0], "R2X_RSC", &R2XRequestSenderConnectionMessage {
+            passphrase: passphrase.clone(),
+            receiver_host: hostname,
+            limit,
+            basis: basis.is_some(),
+            local_port: sockets[0].local_addr()?.port(),
+            local_ips: local_ips.clone(),
+        })?;
+        local_ips
+    };
 
     println!(
         "{} Connecting to {} ({})...",
@@ -160,60 +324,131 @@ pub fn run(root_opts: &RootOpts, get_opts: &GetOpts) -> Result<(), NudgeError> {
         style(&file_info.sender_host).cyan(),
         style(&file_info.sender_addr).dim()
     );
-    socket.connect(file_info.sender_addr)?;
 
-    debug!("Initializing socket connection...");
-    init_socket(&socket)?;
+    // The relay-server tells both peers when to punch, without it the sender starts once it got our request
+    let plan = if without_relay {
+        PunchPlan::immediate()
+    } else {
+        coordinate_punch(&sockets[0], &passphrase, false)?
+    };
+
+    let public_addrs: Vec<SocketAddr> = std::iter::once(file_info.sender_addr)
+        .chain(file_info.sender_stream_addrs.iter().copied())
+        .collect();
+    let rendezvous = &Rendezvous {
+        plan,
+        relay_addr: sockets[0].peer_addr().ok(),
+        direct: get_opts.connect.is_some(),
+        peers: peer_candidates(
+            &public_addrs,
+            &file_info.sender_local,
+            &shared_ips(&local_ips, file_info.same_network),
+            false,
+        ),
+        passphrase,
+    };
+
+    // Punch all streams in parallel, they all follow the same plan
+    let (chunk_sizes, routes): (Vec<u32>, Vec<PeerRoute>) = thread::scope(|scope| {
+        let handles: Vec<_> = sockets.iter_mut()
+            .zip(&rendezvous.peers)
+            .map(|(socket, peer)| scope.spawn(move || -> Result<(u32, PeerRoute), NudgeError> {
+                let route = connect_to_peer(socket, peer, rendezvous)?;
+                Ok((negotiate_chunk_size_as_receiver(socket, get_opts.chunk_size)?, route))
+            }))
+            .collect();
+        join_streams(handles)
+    })?.into_iter().unzip();
     debug!("Ready to receive data!");
 
-    // Wrap the socket in a "reliable udp socket"
-    let mut safe_connection = ReliableUdpSocket::new(socket);
+    print_routes(&routes);
+    if let Some(grant) = routes.iter().find_map(PeerRoute::relay_grant) {
+        if file_info.file_size > grant.quota {
+            return Err(NudgeError::RelayQuotaTooSmall(file_info.file_size, grant.quota));
+        }
+    }
+
+    if let Some(basis) = &basis {
+        if chunk_sizes[0] < MIN_DELTA_CHUNK_SIZE {
+            return Err(NudgeError::ChunkSizeTooSmall(chunk_sizes[0], MIN_DELTA_CHUNK_SIZE));
+        }
+        send_signature(&sockets[0], basis, chunk_sizes[0])?;
+    }
+
+    // The sender sends the attributes of the file first, they are applied once all data is there
+    debug!("Waiting for the attributes of the file...");
+    let encoded = ReliableUdpSocket::receive_metadata(sockets[0].try_clone()?, MetadataKind::Attributes, chunk_sizes[0] as usize)?;
+    let attributes = FileAttributes::decode(&encoded)?;
+    debug!("File attributes: {:?}", attributes);
+
+    // The sender sends the hash tree of the file once it is done hashing
+    let outboard = if file_info.hashed {
+        debug!("Waiting for the hash tree of the file...");
+        let encoded = ReliableUdpSocket::receive_metadata(sockets[0].try_clone()?, MetadataKind::HashTree, chunk_sizes[0] as usize)?;
+        if get_opts.skip_hash {
+            None
+        } else {
+            let outboard = Outboard::decode(file_info.file_size, &encoded)?;
+            debug!("File hash: {}", outboard.root());
+            Some(outboard)
+        }
+    } else {
+        None
+    };
 
     println!(
-        "{} Receiving {} (chunk-size: {})...",
+        "{} Receiving {} (chunk-size: {}, streams: {})...",
         style("[~]").bold().yellow(),
         format_size(file_info.file_size, DECIMAL),
-        style(format_size(get_opts.chunk_size, DECIMAL)).dim()
+        style(format_size(chunk_sizes[0], DECIMAL)).dim(),
+        style(file_info.streams).dim()
     );
 
     let progress_bar = new_downloader_progressbar(file_info.file_size);
+    let ranges = split_ranges(file_info.file_size, file_info.streams, RANGE_ALIGNMENT);
 
     // Used for calculating the total time taken
     let start_time = current_unix_millis();
 
-    // Used for updating the progress bar
-    let mut bytes_received: u64 = 0;
-
-    // Update progress every 25 KiB
-    let update_progress_rate = (1024 * 25) / get_opts.chunk_size;
-    let mut current_progress = 0;
-
-    let mut buffer: Vec<u8> = vec![0; get_opts.chunk_size as usize];
-
-    loop {
-        let (read_buffer, bytes_read) = safe_connection.read(&mut buffer)?;
-        if bytes_read == 0 {
-            progress_bar.finish_with_message("Transfer complete! 🎉");
-            break;
-        }
-
-        let buffer = &read_buffer[..bytes_read];
-        file.write_all(buffer)?;
-        file.flush()?;
-
-        bytes_received += bytes_read as u64;
-
-        current_progress += 1;
-        if current_progress % update_progress_rate == 0 {
-            progress_bar.set_position(bytes_received);
-        }
-    }
+    let stream_stats = thread::scope(|scope| {
+        let handles: Vec<_> = sockets.into_iter()
+            .zip(ranges)
+            .zip(chunk_sizes.iter().copied())
+            .map(|((socket, range), chunk_size)| {
+                let (basis, outboard, progress_bar) = (basis.as_ref(), outboard.as_ref(), &progress_bar);
+                let mut safe_connection = ReliableUdpSocket::new(socket);
+                if file_info.fec {
+                    safe_connection = safe_connection.with_fec_decoder();
+                }
+                scope.spawn(move || receive_range(safe_connection, file, range, chunk_size, basis, outboard, progress_bar))
+            })
+            .collect();
+        join_streams(handles)
+    })?;
+    progress_bar.finish_with_message("Transfer complete! 🎉");
 
+    let duration_millis = current_unix_millis() - start_time;
     println!(
         "{} File received successfully in {}s!",
         style("[✔]").bold().green(),
-        (current_unix_millis() - start_time) as f64 / 1000.0
+        duration_millis as f64 / 1000.0
     );
+    TransferReport::new(Role::Receiver, file_info.file_size, chunk_sizes[0], duration_millis, &stream_stats)
+        .print(get_opts.stats)?;
+
+    // the data is complete, the attributes must not be changed by any write after them
+    if !get_opts.no_preserve {
+        if let Err(err) = attributes.apply(part_file.file()) {
+            println!(
+                "{} {}",
+                style("[✗]").bold().red(),
+                err
+            );
+        }
+    }
+
+    // only complete data gets the real name
+    part_file.persist()?;
 
     if get_opts.skip_hash {
         // if the hash is skipped, we don't need to check it
@@ -222,7 +457,7 @@ pub fn run(root_opts: &RootOpts, get_opts: &GetOpts) -> Result<(), NudgeError> {
 
     // If no hash was sent, display warning to the user
     // we only treat this case as a warning, not an error
-    if file_info.file_hash.0.is_none() {
+    if !file_info.hashed {
         println!(
             "{} Sender did not send a hash! Skipping hash check...",
             style("[✗]").bold().red()
@@ -230,31 +465,171 @@ pub fn run(root_opts: &RootOpts, get_opts: &GetOpts) -> Result<(), NudgeError> {
         return Ok(());
     }
 
+    // every part of the file was checked against the hash while it was received
     println!(
-        "{} Checking file hash...",
-        style("[~]").bold().yellow(),
+        "{} Hash check successful!",
+        style("[✔]").bold().green(),
     );
 
-    file.seek(std::io::SeekFrom::Start(0))?;
-    let actual_hash = hash_file_and_seek(&mut file)?;
-    file.seek(std::io::SeekFrom::Start(0))?;
+    Ok(())
+}
 
-    let expected_hash = file_info.file_hash.0.unwrap();
+/// Registers an additional stream socket for the transfer at the relay-server.
+fn register_stream(socket: &UdpSocket, passphrase: &Passphrase<'static>, stream_index: u8) -> Result<(), NudgeError> {
+    debug!("Registering stream {}...", stream_index);
+    serialize_and_send(socket, "R2X_RS", &R2XRegisterStreamMessage {
+        passphrase: passphrase.clone(),
+        stream_index,
+        local_port: socket.local_addr()?.port(),
+    })?;
+    let _: X2RStreamRegisteredMessage = receive_and_parse_and_expect(socket, "X2R_RSA")?;
+    Ok(())
+}
 
-    if expected_hash != actual_hash {
-        println!(
-            "{} Hash mismatch!\n\t\tExpected: {},\n\t\tReceived: {}",
-            style("[✗]").bold().red(),
-            expected_hash,
-            actual_hash
-        );
-        return Err(NudgeError::HashMismatch(expected_hash, actual_hash));
+/// Refuses a basis which is the output file, since writing the new version would overwrite blocks still to be copied.
+fn check_basis_is_not_output(basis_path: &Path, out_path: &Path) -> Result<(), NudgeError> {
+    match (basis_path.canonicalize(), out_path.canonicalize()) {
+        (Ok(basis), Ok(out)) if basis == out => Err(NudgeError::BasisIsOutput(basis)),
+        _ => Ok(()),
     }
+}
 
-    println!(
-        "{} Hash check successful!",
-        style("[✔]").bold().green(),
-    );
-
+/// Makes sure the file fits on the file system it is saved to.
+///
+/// The space of a temporary file left by an earlier transfer counts as available, it is reused.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InsufficientSpace` if the file does not fit
+fn check_available_space(out_path: &Path, file_size: u64) -> Result<(), NudgeError> {
+    let Some(available) = available_space(out_path)? else {
+        return Ok(());
+    };
+    let reused = std::fs::metadata(part_path(out_path)).map_or(0, |metadata| metadata.len());
+    let available = available.saturating_add(reused);
+    debug!("{} bytes available for the file", available);
+    if file_size > available {
+        return Err(NudgeError::InsufficientSpace(file_size, available));
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Chooses where the file is saved: the `-o` option or the sanitized name of the sender, inside `--out-dir` if passed.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidFileName` if the sent name is needed but unusable,
+/// or `NudgeError::OutputOutsideDir` if the output file would leave the output directory.
+fn output_path(get_opts: &GetOpts, sanitized_name: Result<String, NudgeError>) -> Result<PathBuf, NudgeError> {
+    let out_file = match &get_opts.out_file {
+        Some(out_file) => PathBuf::from(out_file),
+        None => PathBuf::from(sanitized_name?),
+    };
+    match &get_opts.out_dir {
+        Some(out_dir) => path_in_dir(out_dir, &out_file),
+        None => Ok(out_file),
+    }
+}
+
+/// Sends the block signatures of the basis to the sender, which needs them to compute the delta.
+fn send_signature(socket: &UdpSocket, basis: &Basis, chunk_size: u32) -> Result<(), NudgeError> {
+    let signature = basis.signature();
+    debug!("Sending signatures of {} blocks of {} bytes...", signature.blocks.len(), signature.block_size);
+
+    ReliableUdpSocket::send_metadata(socket.try_clone()?, MetadataKind::Signature, &signature.encode(), chunk_size as usize)
+}
+
+/// Receives one byte range of the file over a single stream and writes it at its offset.
+///
+/// The chunks are written by a separate thread, so the socket keeps being serviced while the disk is busy.
+/// With a basis, the sender sends delta instructions instead of the plain chunks.
+/// With a hash tree, the data is verified before it is written, and the transfer stops at the first corrupt part.
+/// Returns the statistics of the stream.
+fn receive_range(
+    mut safe_connection: ReliableUdpSocket,
+    file: &File,
+    range: Range<u64>,
+    chunk_size: u32,
+    basis: Option<&Basis>,
+    outboard: Option<&Outboard>,
+    progress_bar: &ProgressBar,
+) -> Result<TransferStats, NudgeError> {
+    let mut verifier = outboard.map(|outboard| RangeVerifier::new(outboard, range.clone()));
+
+    thread::scope(|scope| {
+        let writer = ChunkWriter::spawn(scope, file, progress_bar);
+        let received = match basis {
+            Some(basis) => receive_delta(&mut safe_connection, &writer, range.start, chunk_size, basis, &mut verifier),
+            None => receive_chunks(&mut safe_connection, &writer, range.start, chunk_size, &mut verifier),
+        };
+        let received = received.and_then(|_| verifier.map_or(Ok(()), RangeVerifier::finish));
+        // a failed write also stops the receiving, so its error is the actual cause
+        writer.finish().and(received)
+    })?;
+    Ok(safe_connection.stats().clone())
+}
+
+/// Hands the received chunks to the writer until the sender ends the session.
+fn receive_chunks(
+    safe_connection: &mut ReliableUdpSocket,
+    writer: &ChunkWriter,
+    mut offset: u64,
+    chunk_size: u32,
+    verifier: &mut Option<RangeVerifier>,
+) -> Result<(), NudgeError> {
+    loop {
+        let mut buffer = writer.buffer();
+        let bytes_read = safe_connection.read(&mut buffer, chunk_size as usize)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        if let Some(verifier) = verifier {
+            verifier.update(&buffer)?;
+        }
+
+        writer.write(offset, buffer)?;
+        offset += bytes_read as u64;
+    }
+}
+
+/// Rebuilds the range from delta instructions: literal data is written as is, copied blocks are read from the basis.
+fn receive_delta(
+    safe_connection: &mut ReliableUdpSocket,
+    writer: &ChunkWriter,
+    mut offset: u64,
+    chunk_size: u32,
+    basis: &Basis,
+    verifier: &mut Option<RangeVerifier>,
+) -> Result<(), NudgeError> {
+    loop {
+        let mut buffer = writer.buffer();
+        if safe_connection.read(&mut buffer, chunk_size as usize)? == 0 {
+            return Ok(());
+        }
+
+        match DeltaOp::decode(&buffer)? {
+            DeltaOp::Literal(data) => {
+                let len = data.len();
+                if let Some(verifier) = verifier {
+                    verifier.update(data)?;
+                }
+                // drop the tag, the data stays in place
+                buffer.truncate(len);
+                writer.write(offset, buffer)?;
+                offset += len as u64;
+            }
+            DeltaOp::Copy { block, count } => {
+                for index in block..block + count as u64 {
+                    let mut block_buffer = writer.buffer();
+                    basis.read_block(index, &mut block_buffer)?;
+                    if let Some(verifier) = verifier {
+                        verifier.update(&block_buffer)?;
+                    }
+                    let len = block_buffer.len();
+                    writer.write(offset, block_buffer)?;
+                    offset += len as u64;
+                }
+            }
+        }
+    }
+}
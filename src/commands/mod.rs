@@ -1,10 +1,15 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
+use console::style;
 
+use crate::utils::socket::PeerRoute;
 use crate::utils::{DEFAULT_RELAY_HOST, DEFAULT_RELAY_PORT};
 
 pub mod send_command;
 pub mod get_command;
 pub mod server_command;
+pub mod doctor_command;
 
 #[derive(Parser, Debug)]
 #[clap(name = "nudge")]
@@ -19,6 +24,10 @@ pub struct RootOpts {
     #[clap(short, long, default_value = "false")]
     pub(crate) verbose: bool,
 
+    /// Path of the config file (default: nudge/config.toml in the user's config directory)
+    #[clap(long, env = "NUDGE_CONFIG")]
+    pub(crate) config: Option<PathBuf>,
+
     #[clap(subcommand)]
     pub(crate) subcmd: SubCommand,
 }
@@ -28,4 +37,36 @@ pub enum SubCommand {
     Serve(server_command::RelayServerOpts),
     Send(send_command::SendOpts),
     Get(get_command::GetOpts),
-}
\ No newline at end of file
+    Doctor(doctor_command::DoctorOpts),
+}
+
+/// Tells the user how the peer was reached, if it was not the usual hole punching.
+///
+/// # Arguments
+///
+/// * `routes` - The route of every stream, the first one belongs to the first stream.
+pub(crate) fn print_routes(routes: &[PeerRoute]) {
+    if let Some(PeerRoute::Local(local_addr)) = routes.first() {
+        println!(
+            "{} Connected through the local network ({})",
+            style("[i]").bold().blue(),
+            style(local_addr).dim()
+        );
+    }
+    if let Some(predicted_addr) = routes.iter().find_map(|route| match route {
+        PeerRoute::Predicted(addr) => Some(addr),
+        _ => None,
+    }) {
+        println!(
+            "{} Connected through a predicted port of the peer's NAT ({})",
+            style("[i]").bold().blue(),
+            style(predicted_addr).dim()
+        );
+    }
+    if routes.iter().any(|route| route.relay_grant().is_some()) {
+        println!(
+            "{} Hole punching failed, the data goes through the relay-server",
+            style("[i]").bold().blue()
+        );
+    }
+}
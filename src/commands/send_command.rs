@@ -1,35 +1,68 @@
 use std::fs::File;
-use std::io::{Read, Seek};
-use std::net::{Ipv4Addr, UdpSocket};
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use console::style;
 use humansize::{DECIMAL, format_size};
+use indicatif::ProgressBar;
 
-use crate::commands::RootOpts;
-use crate::error::Result;
+use crate::commands::{print_routes, RootOpts};
+use crate::config::Config;
+use crate::error::{NudgeError, Result};
+use crate::models::{FileInfo, LocalCandidates};
 use crate::models::X2SPassphraseProvidedMessage;
+use crate::models::S2XRegisterStreamMessage;
 use crate::models::S2XRequestPassphraseMessage;
 use crate::models::X2SSenderConnectToReceiverMessage;
-use crate::utils::reliable_udp::ReliableUdpSocket;
-use crate::utils::AnonymousString;
+use crate::models::X2SStreamRegisteredMessage;
+use crate::utils::passphrase::{Passphrase, PassphraseGenerator};
+use crate::utils::reliable_udp::{MetadataKind, ReliableUdpSocket};
+use crate::utils::rate::{Rate, RateSchedule};
+use crate::utils::reliable_udp::batch::SendPath;
+use crate::utils::reliable_udp::fec::FEC_OVERHEAD;
+use crate::utils::reliable_udp::limit::RateLimiter;
+use crate::utils::reliable_udp::stats::TransferStats;
+use crate::utils::report::{Role, StatsFormat, TransferReport};
+use crate::utils::attributes::FileAttributes;
 use crate::utils::current_unix_millis;
-use crate::utils::hash_file_and_seek;
+use crate::utils::delta::{DeltaOp, encode_range, MIN_DELTA_CHUNK_SIZE, Signature, SignatureIndex};
 use crate::utils::hide_or_get_hostname;
-use crate::utils::new_downloader_progressbar;
-use crate::utils::DEFAULT_CHUNK_SIZE;
+use crate::utils::mtu::{MAX_CHUNK_SIZE, negotiate_chunk_size_as_sender};
+use crate::utils::{new_downloader_progressbar, new_hashing_progressbar};
 use crate::utils::serialize::{receive_and_parse_and_expect, serialize_and_send};
-use crate::utils::socket::init_socket;
+use crate::utils::discovery;
+use crate::utils::interfaces::{local_ips, shared_ips};
+use crate::utils::socket::{bind_dual_stack, bind_to, connect_to_host, connect_to_peer, coordinate_punch, peer_candidates, PeerRoute, PunchPlan, Rendezvous};
+use crate::utils::streams::{join_streams, MAX_STREAMS, RANGE_ALIGNMENT, read_at, split_ranges};
+use crate::utils::verify::Outboard;
+
+/// How often the hashing thread is checked while the peer waits for the hash
+const HASH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval of the packets which keep the NAT mappings open while the peer waits for the hash
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
 pub struct SendOpts {
     file: String,
 
-    #[clap(short, long, default_value = "500")]
-    delay: u64,
+    /// Minimum delay between two packets in microseconds (optional)
+    ///
+    /// The sending rate is chosen automatically from the measured RTT and loss,
+    /// this only caps it.
+    #[clap(short, long)]
+    delay: Option<u64>,
 
-    #[clap(short, long, default_value = DEFAULT_CHUNK_SIZE)]
-    chunk_size: u32,
+    /// Chunk size in bytes (optional)
+    ///
+    /// By default, the largest chunk size which fits through the path MTU is negotiated with the receiver.
+    #[clap(short, long, value_parser = clap::value_parser!(u32).range(1..=MAX_CHUNK_SIZE as i64))]
+    chunk_size: Option<u32>,
 
     /// If enabled, won't send the hostname to the receiver
     #[clap(long, default_value = "false")]
@@ -38,75 +71,303 @@ pub struct SendOpts {
     /// If enabled, won't create a hash of the file
     #[clap(long, default_value = "false")]
     skip_hash: bool,
+
+    /// Number of parallel UDP streams to send the file over
+    #[clap(long, default_value = "1", value_parser = clap::value_parser!(u8).range(1..=MAX_STREAMS as i64))]
+    streams: u8,
+
+    /// Bandwidth limit, e.g. 5MB/s or unlimited (optional)
+    ///
+    /// Overrides the limit and schedule of the config file.
+    #[clap(long)]
+    limit: Option<Rate>,
+
+    /// Format of the statistics printed after the transfer
+    #[clap(long, value_enum, default_value = "text")]
+    stats: StatsFormat,
+
+    /// How packets are handed to the kernel (useful to compare throughput)
+    #[clap(long, value_enum, default_value = "auto")]
+    send_path: SendPath,
+
+    /// If enabled, sends parity packets so the receiver can rebuild lost packets without a retransmit
+    ///
+    /// Useful on links with high latency and loss. The share of parity packets follows the measured loss.
+    #[clap(long, default_value = "false")]
+    fec: bool,
+
+    /// If enabled, announces the file on the local network instead of using the relay-server
+    ///
+    /// The receiver has to use get --local. Only a hash of the passphrase is announced.
+    #[clap(long, default_value = "false")]
+    local: bool,
+
+    /// Address to listen on for a receiver which connects directly, e.g. 0.0.0.0:9000 (optional)
+    ///
+    /// Skips the relay-server and hole punching, the receiver has to use get --connect.
+    /// Every further stream listens on the next port.
+    #[clap(long, conflicts_with = "local")]
+    listen: Option<SocketAddr>,
 }
 
 pub fn run(root_opts: &RootOpts, send_opts: &SendOpts) -> Result<()> {
     // check if the file exists and open it
-    let mut file = File::open(&send_opts.file)?;
-    let file_name = send_opts.file.split('/').last().unwrap_or_default();
+    let file = File::open(&send_opts.file)?;
+    let file_name = send_opts.file.split('/').next_back().unwrap_or_default();
     let file_size = file.metadata()?.len();
+    // collected before hashing, which reads the file and may change its access time
+    let attributes = FileAttributes::collect(&file)?;
+    debug!("File attributes: {:?}", attributes);
 
-    let socket = bind_socket()?;
-    connect_to_relay_server(&socket, root_opts)?;
+    let config = Config::load(root_opts.config.as_deref())?;
+    let rate_schedule = match send_opts.limit {
+        Some(limit) => RateSchedule::fixed(limit),
+        None => config.send.rate_schedule(),
+    };
 
     // Get the hostname of the sender
     let sender_host = hide_or_get_hostname(send_opts.hide_hostname)?;
     debug!("Sender hostname: {}", sender_host);
 
-    let file_hash = compute_file_hash(send_opts.skip_hash, &mut file)?;
-    debug!("File hash: {}", file_hash);
+    // Hashing a large file takes a while, the passphrase can be shared in the meantime
+    let hashing = if send_opts.skip_hash {
+        None
+    } else {
+        Some(BackgroundHash::start(&file, file_size)?)
+    };
 
-    // Request a passphrase from the relay-server
-    serialize_and_send(&socket, "S2X_RP", &S2XRequestPassphraseMessage {
+    let offer = S2XRequestPassphraseMessage {
         sender_host,
         file_size,
-        file_hash,
+        hashed: hashing.is_some(),
+        fec: send_opts.fec,
         file_name: file_name.to_string(),
-    })?;
+        streams: send_opts.streams,
+        local_ips: Vec::new(),
+        local_port: 0,
+    };
+    let (mut sockets, conn_req, rendezvous) = if send_opts.local || send_opts.listen.is_some() {
+        offer_without_relay(offer, hashing.as_ref(), send_opts.listen)?
+    } else {
+        offer_through_relay(root_opts, offer, hashing.as_ref())?
+    };
+
+    send_file(&mut sockets, &conn_req, &rendezvous, SourceFile { file, attributes, hashing }, send_opts, rate_schedule)?;
+    Ok(())
+}
+
+/// Offers the file through the relay-server, which hands out the passphrase and introduces the receiver
+///
+/// # Returns
+///
+/// `Result<(Vec<UdpSocket>, X2SSenderConnectToReceiverMessage, Rendezvous)>` - One socket per stream,
+/// the receiver which accepted the offer and how to reach it.
+///
+/// # Errors
+///
+/// Returns `NudgeError` if the relay-server cannot be reached or does not answer as expected
+fn offer_through_relay(
+    root_opts: &RootOpts,
+    mut offer: S2XRequestPassphraseMessage,
+    hashing: Option<&BackgroundHash>,
+) -> Result<(Vec<UdpSocket>, X2SSenderConnectToReceiverMessage, Rendezvous)> {
+    let socket = bind_socket()?;
+    connect_to_relay_server(&socket, root_opts)?;
+
+    // A receiver in the same network can reach us through our local addresses
+    let local_ips = local_ips(&socket);
+    debug!("Local addresses: {:?}", local_ips);
+    offer.local_ips = local_ips.clone();
+    offer.local_port = socket.local_addr()?.port();
+
+    // Request a passphrase from the relay-server
+    serialize_and_send(&socket, "S2X_RP", &offer)?;
 
     // (Hopefully) receive the passphrase from the relay-server
     let passphrase_message: X2SPassphraseProvidedMessage = receive_and_parse_and_expect(
         &socket,
         "X2S_PPM",
     )?;
+    let passphrase = passphrase_message.passphrase;
+
+    // Every additional stream gets its own socket, the relay-server has to know its address
+    let mut sockets = vec![socket];
+    for stream_index in 1..offer.streams {
+        let stream_socket = bind_socket()?;
+        connect_to_relay_server(&stream_socket, root_opts)?;
+        register_stream(&stream_socket, &passphrase, stream_index)?;
+        sockets.push(stream_socket);
+    }
 
-    println!(
+    print_while_hashing(hashing, format!(
         "{} Passphrase: {}",
         style("[✔]").bold().green(),
-        style(&passphrase_message.passphrase).cyan()
-    );
+        style(&passphrase).cyan()
+    ));
 
     debug!("Waiting for connection request...");
     let conn_req: X2SSenderConnectToReceiverMessage = receive_and_parse_and_expect(
-        &socket,
+        &sockets[0],
         "X2S_SCON",
     )?;
+    if conn_req.receiver_stream_addrs.len() + 1 != sockets.len() {
+        return Err(NudgeError::StreamsIncomplete);
+    }
+    print_connecting(hashing, &conn_req);
+
+    // The relay-server tells both peers when to punch
+    let plan = coordinate_punch(&sockets[0], &passphrase, true)?;
+
+    let rendezvous = Rendezvous {
+        plan,
+        relay_addr: sockets[0].peer_addr().ok(),
+        direct: false,
+        peers: peer_candidates(
+            &receiver_addrs(&conn_req),
+            &conn_req.receiver_local,
+            &shared_ips(&local_ips, conn_req.same_network),
+            true,
+        ),
+        passphrase,
+    };
+    Ok((sockets, conn_req, rendezvous))
+}
 
-    println!(
+/// Announces the file on the local network, or listens on `listen` for a receiver which connects directly
+///
+/// Either way, the receiver finds the file without a relay-server.
+///
+/// # Returns
+///
+/// `Result<(Vec<UdpSocket>, X2SSenderConnectToReceiverMessage, Rendezvous)>` - One socket per stream,
+/// the receiver which accepted the offer and how to reach it.
+///
+/// # Errors
+///
+/// Returns `NudgeError` if no passphrase can be generated or the sockets fail
+fn offer_without_relay(
+    offer: S2XRequestPassphraseMessage,
+    hashing: Option<&BackgroundHash>,
+    listen: Option<SocketAddr>,
+) -> Result<(Vec<UdpSocket>, X2SSenderConnectToReceiverMessage, Rendezvous)> {
+    let passphrase = PassphraseGenerator::new()?.generate()
+        .ok_or(NudgeError::PassphraseGenerationError)?;
+    let sockets = match listen {
+        Some(addr) => (0..offer.streams).map(|index| bind_listener(addr, index)).collect::<Result<Vec<_>>>()?,
+        None => (0..offer.streams).map(|_| bind_socket()).collect::<Result<Vec<_>>>()?,
+    };
+
+    // the receiver learns our IP from the packets, only the ports are sent
+    let file_info = FileInfo {
+        file_size: offer.file_size,
+        file_name: offer.file_name,
+        hashed: offer.hashed,
+        fec: offer.fec,
+        sender_host: offer.sender_host,
+        created_at: current_unix_millis(),
+        sender_addr: sockets[0].local_addr()?,
+        streams: offer.streams,
+        sender_stream_addrs: sockets[1..].iter().map(UdpSocket::local_addr).collect::<std::io::Result<_>>()?,
+        receiver_stream_addrs: Vec::new(),
+        sender_local: LocalCandidates::default(),
+        receiver_local: LocalCandidates::default(),
+        same_network: true,
+        sender_proof: None,
+    };
+
+    let conn_req = match listen {
+        Some(addr) => {
+            print_while_hashing(hashing, format!(
+                "{} Passphrase: {} (listening on {})",
+                style("[✔]").bold().green(),
+                style(&passphrase).cyan(),
+                style(addr).dim()
+            ));
+            debug!("Listening for a receiver on {}...", sockets[0].local_addr()?);
+            discovery::listen(&sockets[0], &passphrase, &file_info)?
+        }
+        None => {
+            print_while_hashing(hashing, format!(
+                "{} Passphrase: {} (on the local network)",
+                style("[✔]").bold().green(),
+                style(&passphrase).cyan()
+            ));
+            debug!("Announcing the session on the local network...");
+            discovery::announce(&sockets[0], &passphrase, &file_info)?
+        }
+    };
+    if conn_req.receiver_stream_addrs.len() + 1 != sockets.len() {
+        return Err(NudgeError::StreamsIncomplete);
+    }
+    print_connecting(hashing, &conn_req);
+
+    // The receiver starts punching as soon as it asked us to connect, unless it connects directly
+    let rendezvous = Rendezvous {
+        plan: PunchPlan::immediate(),
+        relay_addr: None,
+        direct: listen.is_some(),
+        peers: peer_candidates(&receiver_addrs(&conn_req), &LocalCandidates::default(), &[], true),
+        passphrase,
+    };
+    Ok((sockets, conn_req, rendezvous))
+}
+
+/// The addresses of the receiver's sockets, by stream
+fn receiver_addrs(conn_req: &X2SSenderConnectToReceiverMessage) -> Vec<SocketAddr> {
+    std::iter::once(conn_req.receiver_addr)
+        .chain(conn_req.receiver_stream_addrs.iter().copied())
+        .collect()
+}
+
+fn print_connecting(hashing: Option<&BackgroundHash>, conn_req: &X2SSenderConnectToReceiverMessage) {
+    print_while_hashing(hashing, format!(
         "{} Connecting to peer {} ({})...",
         style("[~]").bold().yellow(),
         style(&conn_req.receiver_host).cyan(),
         style(&conn_req.receiver_addr).dim()
-    );
-    socket.connect(conn_req.receiver_addr)?;
-
-    debug!("Initializing socket connection...");
-    init_socket(&socket)?;
-    debug!("Ready to send data!");
+    ));
+}
 
-    send_file(&socket, &mut file, send_opts, file_size)?;
+/// Registers an additional stream socket for the transfer at the relay-server
+///
+/// # Errors
+///
+/// Returns `NudgeError` if the relay-server rejects the stream or does not answer as expected
+fn register_stream(socket: &UdpSocket, passphrase: &Passphrase<'static>, stream_index: u8) -> Result<()> {
+    debug!("Registering stream {}...", stream_index);
+    serialize_and_send(socket, "S2X_RS", &S2XRegisterStreamMessage {
+        passphrase: passphrase.clone(),
+        stream_index,
+        local_port: socket.local_addr()?.port(),
+    })?;
+    let _: X2SStreamRegisteredMessage = receive_and_parse_and_expect(socket, "X2S_RSA")?;
     Ok(())
 }
 
-/// Binds a UDP socket to a local address
+/// Binds a UDP socket for IPv4 and IPv6
 ///
 /// # Errors
 ///
 /// Returns `NudgeError::Io` if binding fails
 fn bind_socket() -> Result<UdpSocket> {
-    let local_bind_address = (Ipv4Addr::from(0u32), 0);
-    debug!("Binding UDP socket to local address: {:?}", local_bind_address);
-    Ok(UdpSocket::bind(&local_bind_address)?)
+    let socket = bind_dual_stack()?;
+    debug!("Bound UDP socket to local address: {}", socket.local_addr()?);
+    Ok(socket)
+}
+
+/// Binds the UDP socket of a stream to the address to listen on, every stream on the next port
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if binding fails, e.g. because the port is taken
+fn bind_listener(addr: SocketAddr, stream_index: u8) -> Result<UdpSocket> {
+    let port = match addr.port() {
+        0 => 0,
+        port => port.checked_add(stream_index as u16).ok_or(NudgeError::InvalidStreamIndex(stream_index))?,
+    };
+    let socket = bind_to(SocketAddr::new(addr.ip(), port))?;
+    debug!("Bound UDP socket to local address: {}", socket.local_addr()?);
+    Ok(socket)
 }
 
 /// Connects the UDP socket to the relay server
@@ -120,73 +381,238 @@ fn bind_socket() -> Result<UdpSocket> {
 ///
 /// Returns `NudgeError::Io` if connection fails
 fn connect_to_relay_server(socket: &UdpSocket, root_opts: &RootOpts) -> Result<()> {
-    let relay_address = format!("{}:{}", root_opts.relay_host, root_opts.relay_port);
-    debug!("Connecting to relay-server: {}...", relay_address);
-    Ok(socket.connect(&relay_address)?)
+    debug!("Connecting to relay-server: {} (port {})...", root_opts.relay_host, root_opts.relay_port);
+    connect_to_host(socket, &root_opts.relay_host, root_opts.relay_port)
 }
 
-/// Computes the hash of the file if not skipped
-///
-/// # Arguments
-///
-/// * `skip_hash` - Boolean flag to skip hashing
-/// * `file` - Mutable reference to the file to be hashed
-///
-/// # Errors
-///
-/// Returns `NudgeError::Io` if hashing or seeking fails
-fn compute_file_hash(skip_hash: bool, file: &mut File) -> Result<AnonymousString> {
-    if skip_hash {
-        Ok(AnonymousString(None))
-    } else {
+/// The file being sent, with what the receiver learns about it before the data
+struct SourceFile {
+    file: File,
+    /// Permissions, times and extended attributes of the file
+    attributes: FileAttributes,
+    /// Hashing of the file, its hash tree lets the receiver verify the data as it arrives
+    hashing: Option<BackgroundHash>,
+}
+
+/// Computes the hash tree of the file on another thread, its root is the hash of the file
+struct BackgroundHash {
+    handle: JoinHandle<Result<Outboard>>,
+    progress_bar: ProgressBar,
+}
+
+impl BackgroundHash {
+    /// Starts hashing the file, the progress is shown in its own progress bar
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the file handle cannot be cloned for the hashing thread
+    fn start(file: &File, file_size: u64) -> Result<BackgroundHash> {
         debug!("Creating hash of file...");
-        let hash = hash_file_and_seek(file)?;
-        file.seek(std::io::SeekFrom::Start(0))?;
-        Ok(AnonymousString(Some(hash)))
+        let file = file.try_clone()?;
+        let progress_bar = new_hashing_progressbar(file_size);
+        let handle = {
+            let progress_bar = progress_bar.clone();
+            thread::spawn(move || Outboard::compute(&file, |len| progress_bar.inc(len)))
+        };
+        Ok(BackgroundHash { handle, progress_bar })
+    }
+
+    /// Waits until the file is hashed
+    ///
+    /// The peer is idle in the meantime, so the sockets send keep-alive packets to hold the NAT mappings open.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if reading the file fails, or `NudgeError::HashingPanicked` if the thread panicked
+    fn wait(self, sockets: &[UdpSocket]) -> Result<Outboard> {
+        let mut kept_alive_at = Instant::now();
+        while !self.handle.is_finished() {
+            thread::sleep(HASH_POLL_INTERVAL);
+            if kept_alive_at.elapsed() >= KEEPALIVE_INTERVAL {
+                for socket in sockets {
+                    let _ = socket.send(&[0]);
+                }
+                kept_alive_at = Instant::now();
+            }
+        }
+        let outboard = self.handle.join().unwrap_or(Err(NudgeError::HashingPanicked))?;
+        self.progress_bar.finish();
+        debug!("File hash: {}", outboard.root());
+        Ok(outboard)
+    }
+}
+
+/// Prints a line without breaking the progress bar of the hashing, if it is still running
+fn print_while_hashing(hashing: Option<&BackgroundHash>, line: String) {
+    match hashing {
+        Some(hashing) => hashing.progress_bar.suspend(|| println!("{}", line)),
+        None => println!("{}", line),
     }
 }
 
 
-/// Sends the file to the peer in chunks
+/// Sends the file to the peer, one byte range per stream in parallel
 ///
 /// # Arguments
 ///
-/// * `socket` - The UDP socket
-/// * `file` - Mutable reference to the file to be sent
+/// * `sockets` - One UDP socket per stream, replaced by another one if that one reached the receiver
+/// * `conn_req` - The receiver's addresses and what it asked for
+/// * `rendezvous` - When and where to punch the holes to the receiver
+/// * `source` - The file to be sent and its metadata
 /// * `send_opts` - Send options containing delay, chunk size, etc.
-/// * `file_size` - Size of the file to be sent
+/// * `rate_schedule` - Our bandwidth limit, capped by the receiver and the relay-server
 ///
 /// # Errors
 ///
-/// Returns `NudgeError` if any step of the sending process fails
-fn send_file(socket: &UdpSocket, file: &mut File, send_opts: &SendOpts, file_size: u64) -> Result<()> {
-    let mut safe_connection = ReliableUdpSocket::new(socket.try_clone()?);
+/// Returns `NudgeError` if any step of the sending process fails on any stream
+fn send_file(
+    sockets: &mut [UdpSocket],
+    conn_req: &X2SSenderConnectToReceiverMessage,
+    rendezvous: &Rendezvous,
+    source: SourceFile,
+    send_opts: &SendOpts,
+    rate_schedule: RateSchedule,
+) -> Result<()> {
+    let SourceFile { file, attributes, hashing } = source;
+    let file = &file;
+    let file_size = file.metadata()?.len();
+
+    // Punch all streams in parallel, they all follow the same plan
+    let (chunk_sizes, routes): (Vec<u32>, Vec<PeerRoute>) = thread::scope(|scope| {
+        let handles: Vec<_> = sockets.iter_mut()
+            .zip(&rendezvous.peers)
+            .map(|(socket, peer)| scope.spawn(move || -> Result<(u32, PeerRoute)> {
+                let route = connect_to_peer(socket, peer, rendezvous)?;
+                Ok((negotiate_chunk_size_as_sender(socket, send_opts.chunk_size)?, route))
+            }))
+            .collect();
+        join_streams(handles)
+    })?.into_iter().unzip();
+    debug!("Ready to send data!");
+
+    print_routes(&routes);
+
+    // The receiver may ask for a lower limit than ours, and so may the relay-server
+    let mut cap = Rate(conn_req.limit);
+    if let Some(grant) = routes.iter().find_map(PeerRoute::relay_grant) {
+        if file_size > grant.quota {
+            return Err(NudgeError::RelayQuotaTooSmall(file_size, grant.quota));
+        }
+        cap = cap.min(Rate(grant.limit));
+    }
+    let rate_limiter = &Arc::new(RateLimiter::new(rate_schedule, cap));
+
+    // parity packets are a bit larger than the data packets they protect and have to fit the chunk size as well
+    let payload_sizes: Vec<u32> = if send_opts.fec {
+        chunk_sizes.iter()
+            .map(|chunk_size| match chunk_size.checked_sub(FEC_OVERHEAD as u32) {
+                Some(payload_size) if payload_size > 0 => Ok(payload_size),
+                _ => Err(NudgeError::ChunkSizeTooSmall(*chunk_size, FEC_OVERHEAD as u32 + 1)),
+            })
+            .collect::<Result<_>>()?
+    } else {
+        chunk_sizes.clone()
+    };
+
+    let index = if conn_req.basis {
+        if payload_sizes[0] < MIN_DELTA_CHUNK_SIZE {
+            return Err(NudgeError::ChunkSizeTooSmall(payload_sizes[0], MIN_DELTA_CHUNK_SIZE));
+        }
+        Some(receive_signature(&sockets[0], chunk_sizes[0])?)
+    } else {
+        None
+    };
+
+    debug!("Sending the attributes of the file...");
+    ReliableUdpSocket::send_metadata(sockets[0].try_clone()?, MetadataKind::Attributes, &attributes.encode()?, chunk_sizes[0] as usize)?;
+
+    if let Some(hashing) = hashing {
+        if !hashing.handle.is_finished() {
+            print_while_hashing(Some(&hashing), format!(
+                "{} Waiting for the hash of the file...",
+                style("[~]").bold().yellow()
+            ));
+        }
+        let outboard = hashing.wait(sockets)?;
+        debug!("Sending the hash tree of the file...");
+        ReliableUdpSocket::send_metadata(sockets[0].try_clone()?, MetadataKind::HashTree, &outboard.encode(), chunk_sizes[0] as usize)?;
+    }
+
     println!(
-        "{} Sending {} bytes (chunk-size: {})...",
+        "{} Sending {} bytes (chunk-size: {}, streams: {}, limit: {})...",
         style("[~]").bold().yellow(),
         file_size,
-        style(format_size(send_opts.chunk_size, DECIMAL)).dim()
+        style(format_size(chunk_sizes[0], DECIMAL)).dim(),
+        style(sockets.len()).dim(),
+        style(rate_limiter.current_rate()).dim()
     );
 
     let progress_bar = new_downloader_progressbar(file_size);
+    let ranges = split_ranges(file_size, sockets.len() as u8, RANGE_ALIGNMENT);
 
     // Used for calculating the total time taken
     let start_time = current_unix_millis();
 
-    // Used for updating the progressbar
-    let mut bytes_sent: u64 = 0;
+    let stream_stats = thread::scope(|scope| {
+        let handles: Vec<_> = sockets.iter()
+            .zip(ranges)
+            .zip(payload_sizes.iter().copied())
+            .map(|((socket, range), chunk_size)| {
+                let (index, progress_bar) = (index.as_ref(), &progress_bar);
+                let rate_limiter = Arc::clone(rate_limiter);
+                scope.spawn(move || -> Result<TransferStats> {
+                    let mut safe_connection = ReliableUdpSocket::with_send_path(socket.try_clone()?, send_opts.send_path)
+                        .with_rate_limiter(rate_limiter);
+                    if send_opts.fec {
+                        safe_connection = safe_connection.with_fec();
+                    }
+                    debug!("Sending through {:?}", safe_connection.send_path());
+                    match index {
+                        Some(index) => send_delta(safe_connection, file, range, chunk_size, send_opts, index, progress_bar),
+                        None => send_range(safe_connection, file, range, chunk_size, send_opts, progress_bar),
+                    }
+                })
+            })
+            .collect();
+        join_streams(handles)
+    })?;
+    progress_bar.finish_with_message("Transfer complete! 🎉");
 
-    // update progress every 25 KiB
-    let update_progress_rate = (1024 * 25) / send_opts.chunk_size;
-    let mut current_progress = 0;
+    let duration_millis = current_unix_millis() - start_time;
+    println!(
+        "{} File sent successfully in {}s!",
+        style("[✔]").bold().green(),
+        duration_millis as f64 / 1000.0
+    );
 
-    let mut buffer: Vec<u8> = vec![0; send_opts.chunk_size as usize];
+    TransferReport::new(Role::Sender, file_size, chunk_sizes[0], duration_millis, &stream_stats)
+        .print(send_opts.stats)
+}
 
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
+/// Sends one byte range of the file over a single stream in chunks
+///
+/// # Returns
+///
+/// `Result<TransferStats>` - Statistics of the stream
+///
+/// # Errors
+///
+/// Returns `NudgeError` if reading the file or sending a chunk fails
+fn send_range(
+    mut safe_connection: ReliableUdpSocket,
+    file: &File,
+    range: Range<u64>,
+    chunk_size: u32,
+    send_opts: &SendOpts,
+    progress_bar: &ProgressBar,
+) -> Result<TransferStats> {
+    let mut buffer: Vec<u8> = vec![0; chunk_size as usize];
+    let mut offset = range.start;
+
+    while offset < range.end {
+        let len = (range.end - offset).min(buffer.len() as u64) as usize;
+        let bytes_read = read_at(file, &mut buffer[..len], offset)?;
         if bytes_read == 0 {
-            progress_bar.finish_with_message("Transfer complete! 🎉");
-            safe_connection.end();
             break;
         }
 
@@ -194,21 +620,68 @@ fn send_file(socket: &UdpSocket, file: &mut File, send_opts: &SendOpts, file_siz
         safe_connection.write_and_flush(
             &buffer[..bytes_read],
             false,
-            send_opts.delay,
+            send_opts.delay.unwrap_or_default(),
         )?;
 
-        bytes_sent += bytes_read as u64;
-
-        current_progress += 1;
-        if current_progress % update_progress_rate == 0 {
-            progress_bar.set_position(bytes_sent);
-        }
+        offset += bytes_read as u64;
+        progress_bar.inc(bytes_read as u64);
     }
 
+    Ok(safe_connection.end())
+}
+
+/// Receives the block signatures of the receiver's basis file
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidSignature` if the signatures cannot be decoded
+fn receive_signature(socket: &UdpSocket, chunk_size: u32) -> Result<SignatureIndex> {
+    debug!("Waiting for the signatures of the basis...");
+    let encoded = ReliableUdpSocket::receive_metadata(socket.try_clone()?, MetadataKind::Signature, chunk_size as usize)?;
+    let signature = Signature::decode(&encoded)?;
     println!(
-        "{} File sent successfully in {}s!",
+        "{} Receiver has a basis of {} blocks of {}",
         style("[✔]").bold().green(),
-        (current_unix_millis() - start_time) as f64 / 1000.0
+        signature.blocks.len(),
+        format_size(signature.block_size, DECIMAL)
     );
-    Ok(())
-}
\ No newline at end of file
+    Ok(SignatureIndex::new(&signature))
+}
+
+/// Sends one byte range of the file as delta instructions against the receiver's basis
+///
+/// Blocks the basis already contains are sent as references, everything else as literal data.
+///
+/// # Returns
+///
+/// `Result<TransferStats>` - Statistics of the stream
+///
+/// # Errors
+///
+/// Returns `NudgeError` if reading the file or sending an instruction fails
+fn send_delta(
+    mut safe_connection: ReliableUdpSocket,
+    file: &File,
+    range: Range<u64>,
+    chunk_size: u32,
+    send_opts: &SendOpts,
+    index: &SignatureIndex,
+    progress_bar: &ProgressBar,
+) -> Result<TransferStats> {
+    let mut packet = Vec::with_capacity(chunk_size as usize);
+    // one byte of every packet is the tag of the instruction
+    let max_literal = chunk_size as usize - 1;
+
+    encode_range(file, range, index, max_literal, |op| {
+        let covered = match op {
+            DeltaOp::Literal(data) => data.len() as u64,
+            DeltaOp::Copy { count, .. } => count as u64 * index.block_size() as u64,
+        };
+        op.encode(&mut packet);
+        safe_connection.write_and_flush(&packet, false, send_opts.delay.unwrap_or_default())?;
+        progress_bar.inc(covered);
+        Ok(())
+    })?;
+
+    Ok(safe_connection.end())
+}
@@ -1,6 +1,11 @@
+use std::borrow::Borrow;
 use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::str;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use crate::commands::RootOpts;
@@ -8,28 +13,83 @@ use crate::commands::RootOpts;
 use crate::error::{NudgeError, Result};
 use crate::error::NudgeError::UnknownCommand;
 use crate::utils::passphrase::{Passphrase, PassphraseGenerator};
-use crate::utils::{AnonymousString, current_unix_millis};
+use crate::utils::{current_unix_micros, current_unix_millis};
+use crate::utils::prediction::classify;
+use crate::utils::rate::Rate;
+use crate::utils::reliable_udp::limit::TokenBucket;
+use crate::utils::size::ByteSize;
+use crate::utils::socket::{bind_to, canonical, mapped_for, resolve, PUNCH_ATTEMPTS};
 use crate::models::*;
 
+/// Time between telling the peers when to punch and the start, on top of the one way delay to the slower peer
+const PUNCH_LEAD: Duration = Duration::from_millis(200);
+
+/// Punch coordinations are forgotten after this time
+const PUNCH_COORDINATION_TTL: Duration = Duration::from_secs(60);
+
+/// How long to wait for lost probes after the answer to the ping, before the punching starts without them
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+
+/// Probes recorded per peer at most
+const MAX_PROBES: usize = 16;
+
+/// Relayed transfers are closed after this time without any data
+const RELAY_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Large enough for any datagram, relayed data packets are larger than the messages of the protocol
+const RECEIVE_BUFFER_SIZE: usize = 65536;
+
+/// Datagrams received but not handled yet, the receiving threads wait once it is full
+const RECEIVE_QUEUE: usize = 1024;
+
 #[derive(Parser, Debug)]
-pub struct RelayServerOpts {}
+pub struct RelayServerOpts {
+    /// Forward the data of peers which cannot punch a hole to each other
+    #[clap(long)]
+    allow_relaying: bool,
 
-pub fn run(root_opts: &RootOpts, _: &RelayServerOpts) -> Result<()> {
-    let passphrase_generator = PassphraseGenerator::new()?;
-    let mut client_map = HashMap::new();
+    /// Bytes forwarded per relayed transfer, in both directions
+    #[clap(long, default_value = "1GB")]
+    relay_quota: ByteSize,
+
+    /// Bandwidth per relayed transfer, e.g. 5MB/s or unlimited
+    #[clap(long, default_value = "unlimited")]
+    relay_limit: Rate,
 
-    let bind_addr = format!("{}:{}", root_opts.relay_host, root_opts.relay_port);
-    info!("Starting server on {}", bind_addr);
+    /// Second port which only answers the probes of `nudge doctor`, 0 for any free port
+    ///
+    /// A NAT which maps every destination to another public port shows a different address there.
+    #[clap(long, default_value = "0")]
+    probe_port: u16,
+}
 
-    let listener = UdpSocket::bind(&bind_addr)?;
+pub fn run(root_opts: &RootOpts, server_opts: &RelayServerOpts) -> Result<()> {
+    let passphrase_generator = PassphraseGenerator::new()?;
+    let mut client_map = HashMap::new();
+    let mut punch_map = HashMap::new();
+    let mut relays = Relays::new(server_opts.allow_relaying.then_some(RelayPolicy {
+        quota: server_opts.relay_quota.0,
+        limit: server_opts.relay_limit.bytes_per_sec(),
+    }));
 
-    let mut buf = [0u8; 1024];
+    let listener = Listeners::bind(&root_opts.relay_host, root_opts.relay_port)?;
+    let received = listener.receive()?;
+    let doctor = DoctorInfo {
+        probe_port: serve_probes(&root_opts.relay_host, server_opts.probe_port, relays.relaying()),
+        relaying: relays.relaying(),
+    };
 
     loop {
-        let (len, addr) = listener.recv_from(&mut buf)?;
+        let (buf, addr) = received.recv().map_err(io::Error::other)??;
+        let len = buf.len();
+
+        // data of peers which could not punch a hole is forwarded as it is
+        if relays.forward(&listener, &addr, &buf) {
+            continue;
+        }
         info!("Received {} bytes from {}", len, addr);
 
-        let received_str = match str::from_utf8(&buf[..len]) {
+        let received_str = match str::from_utf8(&buf) {
             Ok(s) => s,
             Err(e) => {
                 warn!("({}) Error converting to string: {}", addr, e);
@@ -38,7 +98,15 @@ pub fn run(root_opts: &RootOpts, _: &RelayServerOpts) -> Result<()> {
         };
         info!("({}) Received Data: {:?}", addr, received_str);
 
-        match handle_message(received_str, &listener, &addr, &passphrase_generator, &mut client_map) {
+        // the probes of doctor are answered without any state, the same way as on the second port
+        if let Some(payload) = received_str.strip_prefix("D2X_PROBE ") {
+            if let Err(e) = handle_doctor_probe(&listener, &addr, payload, &doctor) {
+                warn!("({}) Cannot answer the probe: {}", addr, e);
+            }
+            continue;
+        }
+
+        match handle_message(received_str, &listener, &addr, &passphrase_generator, &mut client_map, &mut punch_map, &mut relays) {
             Ok(_) => info!("Handled message without error"),
             Err(e) => {
                 warn!("Handled message with error: {}", e);
@@ -54,23 +122,51 @@ pub fn run(root_opts: &RootOpts, _: &RelayServerOpts) -> Result<()> {
 
 fn handle_message(
     received_str: &str,
-    listener: &UdpSocket,
+    listener: &Listeners,
     addr: &SocketAddr,
     passphrase_generator: &PassphraseGenerator,
     client_map: &mut HashMap<Passphrase<'static>, FileInfo>,
+    punch_map: &mut HashMap<Passphrase<'static>, PunchCoordination>,
+    relays: &mut Relays,
 ) -> Result<()> {
-    match received_str.split_whitespace().next() {
+    // the payload follows the command after a space, a message without it has an empty payload which fails to parse
+    let (command, payload) = received_str.split_once(' ').unwrap_or((received_str, ""));
+    match command {
         // Sender -> Server; Request Passphrase
-        Some("S2X_RP") => handle_sender_request_passphrase_message(
-            &listener, &addr, &received_str[7..], &passphrase_generator, client_map,
+        "S2X_RP" => handle_sender_request_passphrase_message(
+            listener, addr, payload, passphrase_generator, client_map,
+        ),
+        // Sender -> Server; Register Stream
+        "S2X_RS" => handle_sender_register_stream(
+            listener, addr, payload, client_map,
+        ),
+        // Receiver -> Server; Register Stream
+        "R2X_RS" => handle_receiver_register_stream(
+            listener, addr, payload, client_map,
         ),
         // Receiver -> Server; Request File Info
-        Some("R2X_RFI") => handle_receiver_request_file_info(
-            &listener, &addr, &received_str[8..], client_map,
+        "R2X_RFI" => handle_receiver_request_file_info(
+            listener, addr, payload, client_map,
         ),
         // Receiver -> Server; Accept Connection
-        Some("R2X_RSC") => handle_receiver_accept(
-            &listener, &addr, &received_str[8..], client_map,
+        "R2X_RSC" => handle_receiver_accept(
+            listener, addr, payload, client_map, punch_map,
+        ),
+        // Peer -> Server; Answer to a ping
+        "P2X_PONG" => handle_peer_pong(
+            listener, addr, payload, punch_map,
+        ),
+        // Peer -> Server; Still waiting for the time to punch
+        "P2X_PR" => handle_peer_punch_ready(
+            listener, addr, payload, punch_map,
+        ),
+        // Peer -> Server; Probe socket, shows the port allocation of the peer's NAT
+        "P2X_PROBE" => handle_peer_probe(
+            listener, addr, payload, punch_map,
+        ),
+        // Peer -> Server; Hole punching failed, relay the data
+        "P2X_RR" => handle_peer_request_relay(
+            listener, addr, payload, punch_map, relays,
         ),
         _ => Err(UnknownCommand)
     }
@@ -78,7 +174,7 @@ fn handle_message(
 
 /// Handle a SEND_REQ packet
 fn handle_sender_request_passphrase_message(
-    listener: &UdpSocket,
+    listener: &Listeners,
     addr: &SocketAddr,
     payload_str: &str,
     passphrase_generator: &PassphraseGenerator,
@@ -89,10 +185,18 @@ fn handle_sender_request_passphrase_message(
     let file_info = FileInfo {
         file_size: payload.file_size,
         file_name: payload.file_name,
-        file_hash: payload.file_hash,
+        hashed: payload.hashed,
+        fec: payload.fec,
         created_at: current_unix_millis(),
         sender_host: payload.sender_host,
         sender_addr: *addr,
+        streams: payload.streams.max(1),
+        sender_stream_addrs: Vec::new(),
+        receiver_stream_addrs: Vec::new(),
+        sender_local: LocalCandidates { ips: payload.local_ips, ports: vec![payload.local_port] },
+        receiver_local: LocalCandidates::default(),
+        same_network: false,
+        sender_proof: None,
     };
 
     let passphrase = passphrase_generator.generate()
@@ -103,7 +207,7 @@ fn handle_sender_request_passphrase_message(
 }
 
 fn send_passphrase_to_sender(
-    listener: &UdpSocket,
+    listener: &Listeners,
     addr: &SocketAddr,
     passphrase: Passphrase<'static>,
 ) -> Result<()> {
@@ -113,8 +217,67 @@ fn send_passphrase_to_sender(
     Ok(())
 }
 
+/// Records the address of an additional sender stream.
+/// Streams have to be registered in order, re-registering an existing stream replaces its address.
+fn handle_sender_register_stream(
+    listener: &Listeners,
+    addr: &SocketAddr,
+    payload_str: &str,
+    client_map: &mut HashMap<Passphrase<'static>, FileInfo>,
+) -> Result<()> {
+    let payload: S2XRegisterStreamMessage = serde_json::from_str(payload_str)?;
+
+    let file_info = client_map.get_mut(&payload.passphrase)
+        .ok_or(NudgeError::PassphraseNotFound)?;
+    register_stream_addr(&mut file_info.sender_stream_addrs, file_info.streams, payload.stream_index, addr)?;
+    file_info.sender_local.set_port(payload.stream_index, payload.local_port);
+
+    let response_payload = X2SStreamRegisteredMessage { stream_index: payload.stream_index };
+    let response = format!("X2S_RSA {}\n", serde_json::to_string(&response_payload)?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+/// Records the address of an additional receiver stream.
+fn handle_receiver_register_stream(
+    listener: &Listeners,
+    addr: &SocketAddr,
+    payload_str: &str,
+    client_map: &mut HashMap<Passphrase<'static>, FileInfo>,
+) -> Result<()> {
+    let payload: R2XRegisterStreamMessage = serde_json::from_str(payload_str)?;
+
+    let file_info = client_map.get_mut(&payload.passphrase)
+        .ok_or(NudgeError::PassphraseNotFound)?;
+    register_stream_addr(&mut file_info.receiver_stream_addrs, file_info.streams, payload.stream_index, addr)?;
+    file_info.receiver_local.set_port(payload.stream_index, payload.local_port);
+
+    let response_payload = X2RStreamRegisteredMessage { stream_index: payload.stream_index };
+    let response = format!("X2R_RSA {}\n", serde_json::to_string(&response_payload)?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+fn register_stream_addr(
+    stream_addrs: &mut Vec<SocketAddr>,
+    streams: u8,
+    stream_index: u8,
+    addr: &SocketAddr,
+) -> Result<()> {
+    if stream_index == 0 || stream_index >= streams {
+        return Err(NudgeError::InvalidStreamIndex(stream_index));
+    }
+    let position = stream_index as usize - 1;
+    match position.cmp(&stream_addrs.len()) {
+        std::cmp::Ordering::Less => stream_addrs[position] = *addr,
+        std::cmp::Ordering::Equal => stream_addrs.push(*addr),
+        std::cmp::Ordering::Greater => return Err(NudgeError::InvalidStreamIndex(stream_index)),
+    }
+    Ok(())
+}
+
 fn handle_receiver_request_file_info(
-    listener: &UdpSocket,
+    listener: &Listeners,
     addr: &SocketAddr,
     payload_str: &str,
     client_map: &HashMap<Passphrase<'static>, FileInfo>,
@@ -129,20 +292,24 @@ fn handle_receiver_request_file_info(
 }
 
 fn send_file_info_to_receiver(
-    listener: &UdpSocket,
+    listener: &Listeners,
     addr: &SocketAddr,
     file_info: &FileInfo,
 ) -> Result<()> {
-    let response = format!("X2R_AFI {}\n", serde_json::to_string(file_info)?);
+    let mut file_info = file_info.clone();
+    file_info.same_network = same_network(&file_info.sender_addr, addr);
+    file_info.sender_local = file_info.sender_local.shared(file_info.same_network);
+    let response = format!("X2R_AFI {}\n", serde_json::to_string(&file_info)?);
     listener.send_to(response.as_bytes(), addr)?;
     Ok(())
 }
 
 fn handle_receiver_accept(
-    listener: &UdpSocket,
+    listener: &Listeners,
     addr: &SocketAddr,
     payload_str: &str,
     client_map: &mut HashMap<Passphrase<'static>, FileInfo>,
+    punch_map: &mut HashMap<Passphrase<'static>, PunchCoordination>,
 ) -> Result<()> {
     let payload: R2XRequestSenderConnectionMessage = serde_json::from_str(payload_str)?;
 
@@ -152,40 +319,572 @@ fn handle_receiver_accept(
         None => return Err(NudgeError::PassphraseNotFound),
     };
 
-    // make sure the file hash matches
-    if file_info.file_hash == payload.file_hash {
-        info!(
-            "({}) File hash matches, sending sender ({}) to receiver ({})",
-            addr, file_info.sender_addr, addr
-        );
+    info!(
+        "({}) Sending sender ({}) to receiver ({})",
+        addr, file_info.sender_addr, addr
+    );
 
-        // Clone the necessary info before removing the entry
-        let sender_addr = file_info.sender_addr;
+    // every stream needs a peer on the receiver side as well
+    if file_info.receiver_stream_addrs.len() + 1 < file_info.streams as usize {
+        return Err(NudgeError::StreamsIncomplete);
+    }
 
-        client_map.remove(&payload.passphrase);
+    // Clone the necessary info before removing the entry
+    let sender_addr = file_info.sender_addr;
+    let receiver_stream_addrs = std::mem::take(&mut file_info.receiver_stream_addrs);
+    let mut receiver_local = std::mem::take(&mut file_info.receiver_local);
+    receiver_local.ips = payload.local_ips;
+    receiver_local.set_port(0, payload.local_port);
+    // private addresses only help a peer in the same network, they stay private otherwise
+    let same_network = same_network(&sender_addr, addr);
+    let receiver_local = receiver_local.shared(same_network);
+    let streams: Vec<(SocketAddr, SocketAddr)> = std::iter::once((sender_addr, *addr))
+        .chain(file_info.sender_stream_addrs.iter().copied().zip(receiver_stream_addrs.iter().copied()))
+        .collect();
 
-        send_sender_connect_to_receiver(listener, &sender_addr, addr, payload.receiver_host)
-    } else {
-        Err(NudgeError::PassphraseNotFound)
+    client_map.remove(&payload.passphrase);
+
+    send_sender_connect_to_receiver(listener, &sender_addr, X2SSenderConnectToReceiverMessage {
+        receiver_addr: *addr,
+        receiver_host: payload.receiver_host,
+        receiver_stream_addrs,
+        limit: payload.limit,
+        basis: payload.basis,
+        receiver_local,
+        same_network,
+    })?;
+
+    // both peers get pinged, so they can be told when to punch
+    punch_map.retain(|_, coordination| coordination.created_at.elapsed() < PUNCH_COORDINATION_TTL);
+    let coordination = PunchCoordination::new(streams);
+    for peer in &coordination.peers {
+        send_ping(listener, &peer.addr, &payload.passphrase)?;
+    }
+    punch_map.insert(payload.passphrase, coordination);
+    Ok(())
+}
+
+/// Round trip times of both peers of a transfer, so their punching can start at the same time
+struct PunchCoordination {
+    created_at: Instant,
+    /// The sender and the receiver
+    peers: [PeerTiming; 2],
+    /// Addresses of the sender and the receiver, per stream
+    streams: Vec<(SocketAddr, SocketAddr)>,
+    /// Set once the round trip times of both peers are known
+    start: Option<Instant>,
+    /// Stream addresses which asked for a relay, since their hole punching failed
+    relay_requests: Vec<SocketAddr>,
+}
+
+struct PeerTiming {
+    addr: SocketAddr,
+    pinged_at: Instant,
+    rtt: Option<Duration>,
+    /// Number of probe sockets the peer announced
+    expected_probes: u8,
+    /// Index, local and public port of the probe sockets which were seen
+    probes: Vec<(u8, u16, u16)>,
+}
+
+impl PeerTiming {
+    /// Whether all probes arrived, or the lost ones were waited for long enough
+    fn probed(&self) -> bool {
+        self.probes.len() >= self.expected_probes as usize
+            || self.rtt.is_some_and(|rtt| (self.pinged_at + rtt).elapsed() >= PROBE_WAIT)
+    }
+
+    /// How the NAT of the peer allocates ports, `None` if it did not probe
+    fn allocation(&self) -> Option<PortAllocation> {
+        let mut probes = self.probes.clone();
+        probes.sort_unstable();
+        let ports: Vec<(u16, u16)> = probes.into_iter().map(|(_, local, public)| (local, public)).collect();
+        classify(&ports)
+    }
+}
+
+impl PunchCoordination {
+    /// The first stream is the one the peers coordinate on
+    fn new(streams: Vec<(SocketAddr, SocketAddr)>) -> Self {
+        let now = Instant::now();
+        let peer = |addr| PeerTiming { addr, pinged_at: now, rtt: None, expected_probes: 0, probes: Vec::new() };
+        let (sender_addr, receiver_addr) = streams[0];
+        PunchCoordination {
+            created_at: now,
+            peers: [peer(sender_addr), peer(receiver_addr)],
+            streams,
+            start: None,
+            relay_requests: Vec::new(),
+        }
+    }
+
+    fn peer_mut(&mut self, addr: &SocketAddr) -> Result<&mut PeerTiming> {
+        self.peers.iter_mut()
+            .find(|peer| peer.addr == *addr)
+            .ok_or(NudgeError::PassphraseNotFound)
+    }
+
+    /// Tells both peers when to punch, once their round trip times are known and their probes arrived.
+    ///
+    /// Both get the instruction at about the same time as the slower one, minus half of their own round trip time.
+    fn try_start(&mut self, listener: &Listeners) -> Result<()> {
+        if self.start.is_some() || !self.peers.iter().all(|peer| peer.rtt.is_some() && peer.probed()) {
+            return Ok(());
+        }
+        let slowest = self.peers.iter().filter_map(|peer| peer.rtt).max().unwrap_or_default();
+        self.start = Some(Instant::now() + slowest / 2 + PUNCH_LEAD);
+        for index in 0..self.peers.len() {
+            send_start_punching(listener, self, index)?;
+        }
+        Ok(())
+    }
+}
+
+fn send_ping(listener: &Listeners, addr: &SocketAddr, passphrase: &Passphrase<'static>) -> Result<()> {
+    let response_payload = X2PPingMessage { passphrase: passphrase.clone() };
+    let response = format!("X2P_PING {}\n", serde_json::to_string(&response_payload)?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+/// Records the round trip time of a peer, once both are known both peers are told when to punch.
+fn handle_peer_pong(
+    listener: &Listeners,
+    addr: &SocketAddr,
+    payload_str: &str,
+    punch_map: &mut HashMap<Passphrase<'static>, PunchCoordination>,
+) -> Result<()> {
+    let payload: P2XPongMessage = serde_json::from_str(payload_str)?;
+    let coordination = punch_map.get_mut(&payload.passphrase)
+        .ok_or(NudgeError::PassphraseNotFound)?;
+
+    let peer = coordination.peer_mut(addr)?;
+    if peer.rtt.is_none() {
+        peer.rtt = Some(peer.pinged_at.elapsed());
+        peer.expected_probes = payload.probes;
+        info!("({}) Round trip time: {:?}", addr, peer.rtt.unwrap_or_default());
+    }
+    coordination.try_start(listener)
+}
+
+/// A peer is still waiting: its ping or the instruction got lost, so it gets either of them again.
+fn handle_peer_punch_ready(
+    listener: &Listeners,
+    addr: &SocketAddr,
+    payload_str: &str,
+    punch_map: &mut HashMap<Passphrase<'static>, PunchCoordination>,
+) -> Result<()> {
+    let payload: P2XPunchReadyMessage = serde_json::from_str(payload_str)?;
+    let coordination = punch_map.get_mut(&payload.passphrase)
+        .ok_or(NudgeError::PassphraseNotFound)?;
+
+    if coordination.start.is_some() {
+        let index = coordination.peers.iter()
+            .position(|peer| peer.addr == *addr)
+            .ok_or(NudgeError::PassphraseNotFound)?;
+        return send_start_punching(listener, coordination, index);
+    }
+
+    let peer = coordination.peer_mut(addr)?;
+    if peer.rtt.is_none() {
+        peer.pinged_at = Instant::now();
+        return send_ping(listener, addr, &payload.passphrase);
+    }
+    // the probes which are still missing got lost
+    coordination.try_start(listener)
+}
+
+/// Records the mapping of a probe socket, the port allocation of the peer's NAT is derived from them.
+///
+/// The probes come from the same IP as the peer, but from other ports. They are not answered.
+fn handle_peer_probe(
+    listener: &Listeners,
+    addr: &SocketAddr,
+    payload_str: &str,
+    punch_map: &mut HashMap<Passphrase<'static>, PunchCoordination>,
+) -> Result<()> {
+    let payload: P2XProbeMessage = serde_json::from_str(payload_str)?;
+    let coordination = punch_map.get_mut(&payload.passphrase)
+        .ok_or(NudgeError::PassphraseNotFound)?;
+
+    let peer = &mut coordination.peers[if payload.sender { 0 } else { 1 }];
+    if peer.addr.ip() != addr.ip() {
+        return Err(NudgeError::PassphraseNotFound);
+    }
+    // every probe is sent twice
+    if peer.probes.len() < MAX_PROBES && !peer.probes.iter().any(|(index, _, _)| *index == payload.index) {
+        peer.probes.push((payload.index, payload.local_port, addr.port()));
+    }
+    coordination.try_start(listener)
+}
+
+/// Tells a peer when to punch, and how the NATs of both peers allocate ports.
+///
+/// # Arguments
+///
+/// * `index` - The peer in `coordination.peers`, the other one is its peer.
+fn send_start_punching(listener: &Listeners, coordination: &PunchCoordination, index: usize) -> Result<()> {
+    let Some(start) = coordination.start else {
+        return Ok(());
+    };
+    let peer = &coordination.peers[index];
+    let other = &coordination.peers[1 - index];
+    let now = Instant::now();
+    let one_way = peer.rtt.unwrap_or_default() / 2;
+    // negative if the punching started already
+    let start_in_ms = start.saturating_duration_since(now).as_millis() as i64
+        - now.saturating_duration_since(start).as_millis() as i64
+        - one_way.as_millis() as i64;
+
+    let response_payload = X2PStartPunchingMessage {
+        start_in_ms,
+        attempts: PUNCH_ATTEMPTS.to_vec(),
+        allocation: peer.allocation(),
+        peer_allocation: other.allocation(),
+    };
+    let response = format!("X2P_SP {}\n", serde_json::to_string(&response_payload)?);
+    listener.send_to(response.as_bytes(), peer.addr)?;
+    Ok(())
+}
+
+/// Starts forwarding the data of a stream whose hole punching failed, in both directions.
+///
+/// Only the two addresses of a stream of a current transfer are relayed, and only once both of them asked,
+/// so nobody can use the relay-server to send packets to an address of their choice.
+/// Until then the request stays unanswered, the peers repeat it.
+fn handle_peer_request_relay(
+    listener: &Listeners,
+    addr: &SocketAddr,
+    payload_str: &str,
+    punch_map: &mut HashMap<Passphrase<'static>, PunchCoordination>,
+    relays: &mut Relays,
+) -> Result<()> {
+    let payload: P2XRequestRelayMessage = serde_json::from_str(payload_str)?;
+    let policy = relays.policy.ok_or(NudgeError::RelayingNotAllowed)?;
+
+    let coordination = punch_map.get_mut(&payload.passphrase)
+        .filter(|coordination| coordination.created_at.elapsed() < PUNCH_COORDINATION_TTL)
+        .ok_or(NudgeError::PassphraseNotFound)?;
+    let (sender_addr, receiver_addr) = coordination.streams.iter()
+        .find(|(sender_addr, receiver_addr)| sender_addr == addr || receiver_addr == addr)
+        .copied()
+        .ok_or(NudgeError::PassphraseNotFound)?;
+
+    if !coordination.relay_requests.contains(addr) {
+        coordination.relay_requests.push(*addr);
+    }
+    let other = if *addr == sender_addr { receiver_addr } else { sender_addr };
+    if !coordination.relay_requests.contains(&other) {
+        debug!("({}) Waiting for {} to ask for the relay as well", addr, other);
+        return Ok(());
     }
+
+    info!("({}) Relaying between {} and {}", addr, sender_addr, receiver_addr);
+    relays.open(&payload.passphrase, sender_addr, receiver_addr);
+
+    let response_payload = X2PRelayAcceptedMessage { quota: policy.quota, limit: policy.limit };
+    let response = format!("X2P_RA {}\n", serde_json::to_string(&response_payload)?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+/// What the relay-server grants every relayed transfer
+#[derive(Clone, Copy)]
+struct RelayPolicy {
+    quota: u64,
+    /// Bytes per second, `None` if unlimited
+    limit: Option<u64>,
+}
+
+/// Transfers whose data the relay-server forwards, because the peers could not punch a hole
+struct Relays {
+    /// `None` if relaying is not allowed
+    policy: Option<RelayPolicy>,
+    /// Where the packets of a peer address go
+    routes: HashMap<SocketAddr, RelayRoute>,
+    sessions: HashMap<Passphrase<'static>, RelaySession>,
+}
+
+struct RelayRoute {
+    peer: SocketAddr,
+    passphrase: Passphrase<'static>,
+}
+
+/// The quota and bandwidth used by all streams of a relayed transfer
+struct RelaySession {
+    forwarded: u64,
+    /// Set once the quota is used up
+    exhausted: bool,
+    bucket: Option<TokenBucket>,
+    last_active: Instant,
+}
+
+impl Relays {
+    fn new(policy: Option<RelayPolicy>) -> Self {
+        Relays { policy, routes: HashMap::new(), sessions: HashMap::new() }
+    }
+
+    fn relaying(&self) -> bool {
+        self.policy.is_some()
+    }
+
+    /// Routes the packets of a stream between both peers, idle transfers are closed on the way
+    fn open(&mut self, passphrase: &Passphrase<'static>, sender_addr: SocketAddr, receiver_addr: SocketAddr) {
+        let idle: Vec<Passphrase<'static>> = self.sessions.iter()
+            .filter(|(_, session)| session.last_active.elapsed() >= RELAY_IDLE_TIMEOUT)
+            .map(|(passphrase, _)| passphrase.clone())
+            .collect();
+        for passphrase in idle {
+            self.close(&passphrase);
+        }
+
+        let limit = self.policy.and_then(|policy| policy.limit);
+        self.sessions.entry(passphrase.clone()).or_insert_with(|| RelaySession {
+            forwarded: 0,
+            exhausted: false,
+            bucket: limit.map(|limit| TokenBucket::new(limit, current_unix_micros())),
+            last_active: Instant::now(),
+        });
+        self.routes.insert(sender_addr, RelayRoute { peer: receiver_addr, passphrase: passphrase.clone() });
+        self.routes.insert(receiver_addr, RelayRoute { peer: sender_addr, passphrase: passphrase.clone() });
+    }
+
+    fn close(&mut self, passphrase: &Passphrase<'static>) {
+        info!("Closing relayed transfer {}", passphrase.0);
+        self.sessions.remove(passphrase);
+        self.routes.retain(|_, route| route.passphrase != *passphrase);
+    }
+
+    /// Forwards a packet of a relayed peer to the other one.
+    ///
+    /// Packets above the bandwidth limit are dropped, the congestion control of the peers backs off then.
+    /// Once the quota is used up, everything is dropped until the transfer is idle and closed.
+    ///
+    /// # Returns
+    ///
+    /// `bool` - Whether the packet belonged to a relayed transfer, otherwise it is a message for the relay-server.
+    fn forward(&mut self, listener: &Listeners, addr: &SocketAddr, packet: &[u8]) -> bool {
+        let Some(route) = self.routes.get(addr) else {
+            return false;
+        };
+        // the answer to the request got lost
+        if packet.starts_with(b"P2X_RR ") {
+            return false;
+        }
+        let passphrase = route.passphrase.clone();
+        let peer = route.peer;
+        let (Some(session), Some(policy)) = (self.sessions.get_mut(&passphrase), self.policy) else {
+            return false;
+        };
+
+        // the address may belong to someone else by now
+        if session.last_active.elapsed() >= RELAY_IDLE_TIMEOUT {
+            self.close(&passphrase);
+            return false;
+        }
+        session.last_active = Instant::now();
+
+        if session.bucket.as_mut().is_some_and(|bucket| !bucket.try_take(packet.len(), current_unix_micros())) {
+            return true;
+        }
+        if session.forwarded + packet.len() as u64 > policy.quota {
+            if !session.exhausted {
+                warn!("Relayed transfer {} used up its quota of {}", passphrase.0, ByteSize(policy.quota));
+                session.exhausted = true;
+            }
+            return true;
+        }
+        session.forwarded += packet.len() as u64;
+
+        if let Err(err) = listener.send_to(packet, peer) {
+            debug!("({}) Cannot relay to {}: {}", addr, peer, err);
+        }
+        true
+    }
+}
+
+/// What the relay-server tells `nudge doctor` about itself
+struct DoctorInfo {
+    /// The second port which answers probes, `None` if it cannot be bound
+    probe_port: Option<u16>,
+    relaying: bool,
+}
+
+/// Answers the probe of `nudge doctor` with the address it came from.
+fn handle_doctor_probe(
+    listener: &Listeners,
+    addr: &SocketAddr,
+    payload_str: &str,
+    doctor: &DoctorInfo,
+) -> Result<()> {
+    let probe: D2XProbeMessage = serde_json::from_str(payload_str)?;
+    let response_payload = X2DObservationMessage {
+        nonce: probe.nonce,
+        observed: *addr,
+        probe_port: doctor.probe_port,
+        relaying: doctor.relaying,
+    };
+    let response = format!("X2D_OBS {}\n", serde_json::to_string(&response_payload)?);
+    listener.send_to(response.as_bytes(), addr)?;
+    Ok(())
+}
+
+/// Answers the probes of `nudge doctor` on a second port in the background, nothing else is handled there.
+///
+/// # Returns
+///
+/// `Option<u16>` - The port, `None` if it cannot be bound
+fn serve_probes(host: &str, port: u16, relaying: bool) -> Option<u16> {
+    let bound = Listeners::bind(host, port).and_then(|listener| {
+        let received = listener.receive()?;
+        Ok((listener, received))
+    });
+    let (listener, received) = match bound {
+        Ok(bound) => bound,
+        Err(e) => {
+            warn!("Cannot answer the probes of doctor on a second port: {}", e);
+            return None;
+        }
+    };
+    let doctor = DoctorInfo { probe_port: listener.port(), relaying };
+    info!("Answering the probes of doctor on port {}", doctor.probe_port.unwrap_or_default());
+    let probe_port = doctor.probe_port;
+
+    thread::spawn(move || {
+        while let Ok(Ok((buf, addr))) = received.recv() {
+            let Some(payload) = buf.strip_prefix(b"D2X_PROBE ").and_then(|payload| str::from_utf8(payload).ok()) else {
+                continue;
+            };
+            if let Err(e) = handle_doctor_probe(&listener, &addr, payload, &doctor) {
+                warn!("({}) Cannot answer the probe: {}", addr, e);
+            }
+        }
+    });
+    probe_port
 }
 
 fn send_sender_connect_to_receiver(
-    listener: &UdpSocket,
+    listener: &Listeners,
     sender_addr: &SocketAddr,
-    receiver_addr: &SocketAddr,
-    sender_host: AnonymousString,
+    response_payload: X2SSenderConnectToReceiverMessage,
 ) -> Result<()> {
-    let response_payload = X2SSenderConnectToReceiverMessage {
-        receiver_addr: *receiver_addr,
-        receiver_host: sender_host,
-    };
     let response = format!("X2S_SCON {}\n", serde_json::to_string(&response_payload)?);
     listener.send_to(response.as_bytes(), sender_addr)?;
     Ok(())
 }
 
-fn send_error(listener: &UdpSocket, addr: &SocketAddr, error: &str) -> Result<()> {
+/// Whether the peers are in the same network, only then they can reach each other's private addresses.
+///
+/// IPv4 peers behind the same NAT share their public address, IPv6 peers in the same network share the /64 prefix.
+fn same_network(sender_addr: &SocketAddr, receiver_addr: &SocketAddr) -> bool {
+    match (sender_addr.ip(), receiver_addr.ip()) {
+        (IpAddr::V6(sender), IpAddr::V6(receiver)) => sender.segments()[..4] == receiver.segments()[..4],
+        (sender, receiver) => sender == receiver,
+    }
+}
+
+/// A received packet and the canonical address of its sender
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// The sockets the relay-server listens on, at most one per address family
+struct Listeners {
+    ipv4: Option<UdpSocket>,
+    /// Also reaches IPv4 peers if it is bound to the unspecified address
+    ipv6: Option<UdpSocket>,
+}
+
+impl Listeners {
+    /// Binds the first address of each family `host` resolves to.
+    ///
+    /// The unspecified address of either family binds a single socket for IPv4 and IPv6,
+    /// or one for IPv4 only where IPv6 is unavailable.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the address cannot be resolved or bound
+    fn bind(host: &str, port: u16) -> Result<Self> {
+        let addrs = resolve(host, port)?;
+        let first = |ipv6: bool| addrs.iter().copied().find(|addr| addr.is_ipv6() == ipv6);
+
+        if let Some(addr) = addrs.iter().find(|addr| addr.ip().is_unspecified()) {
+            let dual_stack = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), addr.port());
+            match bind_to(dual_stack) {
+                Ok(socket) => {
+                    info!("Starting server on {} (IPv4 and IPv6)", dual_stack);
+                    return Ok(Listeners { ipv4: None, ipv6: Some(socket) });
+                }
+                Err(err) => warn!("Cannot listen on IPv6, only on IPv4: {}", err),
+            }
+            let ipv4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port());
+            info!("Starting server on {}", ipv4);
+            return Ok(Listeners { ipv4: Some(bind_to(ipv4)?), ipv6: None });
+        }
+
+        let mut listeners = Listeners { ipv4: None, ipv6: None };
+        // a port chosen by the system is used for both families
+        let mut port = port;
+        for addr in [first(false), first(true)].into_iter().flatten() {
+            let addr = SocketAddr::new(addr.ip(), port);
+            let socket = bind_to(addr)?;
+            port = socket.local_addr()?.port();
+            info!("Starting server on {}", SocketAddr::new(addr.ip(), port));
+            let socket = Some(socket);
+            if addr.is_ipv6() {
+                listeners.ipv6 = socket;
+            } else {
+                listeners.ipv4 = socket;
+            }
+        }
+        if listeners.ipv4.is_none() && listeners.ipv6.is_none() {
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, format!("{} resolves to no address", host)).into());
+        }
+        Ok(listeners)
+    }
+
+    /// The port all sockets are bound to.
+    fn port(&self) -> Option<u16> {
+        self.ipv4.iter().chain(&self.ipv6).find_map(|socket| socket.local_addr().ok()).map(|addr| addr.port())
+    }
+
+    /// Receives the datagrams of all sockets, each socket on its own thread.
+    ///
+    /// The addresses are canonical, IPv4 peers have their IPv4 address even if they reached the IPv6 socket.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if a socket cannot be shared with its thread
+    fn receive(&self) -> Result<mpsc::Receiver<io::Result<Datagram>>> {
+        let (sender, receiver) = mpsc::sync_channel(RECEIVE_QUEUE);
+        for socket in self.ipv4.iter().chain(&self.ipv6) {
+            let socket = socket.try_clone()?;
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let mut buffer = vec![0u8; RECEIVE_BUFFER_SIZE];
+                loop {
+                    let received = socket.recv_from(&mut buffer)
+                        .map(|(len, addr)| (buffer[..len].to_vec(), canonical(addr)));
+                    let failed = received.is_err();
+                    if sender.send(received).is_err() || failed {
+                        return;
+                    }
+                }
+            });
+        }
+        Ok(receiver)
+    }
+
+    /// Sends from the socket of the family of `addr`, which is the one the peer talks to.
+    fn send_to(&self, buf: &[u8], addr: impl Borrow<SocketAddr>) -> io::Result<usize> {
+        let addr = *addr.borrow();
+        match (addr, &self.ipv4, &self.ipv6) {
+            (SocketAddr::V4(_), Some(socket), _) | (SocketAddr::V6(_), _, Some(socket)) => socket.send_to(buf, addr),
+            (SocketAddr::V4(_), None, Some(socket)) => socket.send_to(buf, mapped_for(socket, addr)),
+            _ => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, format!("Not listening on the family of {}", addr))),
+        }
+    }
+}
+
+fn send_error(listener: &Listeners, addr: &SocketAddr, error: &str) -> Result<()> {
     let response = format!("ERROR {}\n", error);
     listener.send_to(response.as_bytes(), addr)?;
     Ok(())
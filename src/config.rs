@@ -0,0 +1,160 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::rate::{Rate, RateSchedule, ScheduleEntry};
+use crate::utils::size::ByteSize;
+
+/// Name of the config file inside the platform's config directory
+const CONFIG_FILE: &str = "nudge/config.toml";
+
+/// Settings read from the config file, see the README for an example
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub(crate) send: SendConfig,
+    pub(crate) get: GetConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendConfig {
+    /// Bandwidth limit of sent files, before the first schedule entry of the day
+    pub(crate) limit: Option<Rate>,
+
+    /// Time-of-day dependent bandwidth limits
+    pub(crate) schedule: Vec<ScheduleEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GetConfig {
+    /// Offers of larger files are rejected without asking, even with `--force`
+    pub(crate) max_size: Option<ByteSize>,
+}
+
+impl SendConfig {
+    /// The bandwidth limits configured for sending.
+    pub fn rate_schedule(&self) -> RateSchedule {
+        RateSchedule::new(self.limit.unwrap_or(Rate::UNLIMITED), self.schedule.clone())
+    }
+}
+
+impl Config {
+    /// Loads the config file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the config file; if not set, the default location is used.
+    ///
+    /// # Returns
+    ///
+    /// `Result<Config>` - The config, or the defaults if there is no file at the default location.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if an explicitly passed file cannot be read,
+    /// or `NudgeError::InvalidConfig` if the file is not valid.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(Config::default()),
+            Err(e) => return Err(e.into()),
+        };
+        debug!("Loaded config from {}", path.display());
+        Config::parse(&content).map_err(|e| NudgeError::InvalidConfig(path, Box::new(e)))
+    }
+
+    fn parse(content: &str) -> std::result::Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+}
+
+/// Location of the config file, e.g. `~/.config/nudge/config.toml` on Linux.
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(CONFIG_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::rate::TimeOfDay;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_empty() {
+        let config = Config::parse("").unwrap();
+        assert!(config.send.limit.is_none());
+        assert!(config.send.schedule.is_empty());
+        assert!(config.get.max_size.is_none());
+    }
+
+    #[test]
+    fn test_parse_max_size() {
+        let config = Config::parse("[get]\nmax_size = \"10GB\"").unwrap();
+        assert_eq!(config.get.max_size, Some(ByteSize(10_000_000_000)));
+        assert!(Config::parse("[get]\nmax_size = \"huge\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        let config = Config::parse(r#"
+            [send]
+            limit = "5MB/s"
+
+            [[send.schedule]]
+            from = "19:00"
+            limit = "unlimited"
+
+            [[send.schedule]]
+            from = "07:00"
+            limit = "5MB/s"
+        "#).unwrap();
+
+        assert_eq!(config.send.limit, Some(Rate(Some(5_000_000))));
+        let schedule = config.send.rate_schedule();
+        assert_eq!(schedule.rate_at(TimeOfDay(20 * 60)), Rate::UNLIMITED);
+        assert_eq!(schedule.rate_at(TimeOfDay(8 * 60)), Rate(Some(5_000_000)));
+    }
+
+    #[test]
+    fn test_parse_single_entry_schedule() {
+        let config = Config::parse(r#"
+            [send]
+            limit = "5MB/s"
+
+            [[send.schedule]]
+            from = "19:00"
+            limit = "unlimited"
+        "#).unwrap();
+
+        let schedule = config.send.rate_schedule();
+        assert_eq!(schedule.rate_at(TimeOfDay(0)), Rate(Some(5_000_000)));
+        assert_eq!(schedule.rate_at(TimeOfDay(12 * 60)), Rate(Some(5_000_000)));
+        assert_eq!(schedule.rate_at(TimeOfDay(19 * 60)), Rate::UNLIMITED);
+        assert_eq!(schedule.rate_at(TimeOfDay(23 * 60 + 59)), Rate::UNLIMITED);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Config::parse("[send]\nlimit = \"fast\"").is_err());
+        assert!(Config::parse("[send]\nunknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_load_missing_explicit_file() {
+        let result = Config::load(Some(Path::new("/nonexistent/nudge.toml")));
+        assert!(matches!(result, Err(NudgeError::Io(_))));
+    }
+}
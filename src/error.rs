@@ -46,6 +46,102 @@ pub enum NudgeError {
 
     #[error("Unknown command")]
     UnknownCommand,
+
+    #[error("Invalid stream index: {0}")]
+    InvalidStreamIndex(u8),
+
+    #[error("Not all streams of the transfer are registered yet")]
+    StreamsIncomplete,
+
+    #[error("A transfer stream terminated unexpectedly")]
+    StreamPanicked,
+
+    #[error("Hashing the file terminated unexpectedly")]
+    HashingPanicked,
+
+    #[error("Chunk size mismatch: sender uses {0} bytes, receiver expects {1} bytes. Use the same --chunk-size on both sides or omit it.")]
+    ChunkSizeMismatch(u32, u32),
+
+    #[error("Peer did not answer the chunk size negotiation")]
+    NegotiationTimeout,
+
+    #[error("Could not reach the peer, hole punching failed")]
+    PunchFailed,
+
+    #[error("The disk writer stopped unexpectedly")]
+    WriterStopped,
+
+    #[error("Invalid rate: {0} (expected e.g. 5MB/s, 500KiB/s, 100Mbit/s or unlimited)")]
+    InvalidRate(String),
+
+    #[error("Invalid size: {0} (expected e.g. 10GB or 500MiB)")]
+    InvalidSize(String),
+
+    #[error("Invalid time of day: {0} (expected HH:MM)")]
+    InvalidTimeOfDay(String),
+
+    #[error("Invalid config file {0}: {1}")]
+    InvalidConfig(std::path::PathBuf, Box<toml::de::Error>),
+
+    #[error("Received an invalid block signature of the basis file")]
+    InvalidSignature,
+
+    #[error("Received an invalid delta packet")]
+    InvalidDeltaPacket,
+
+    #[error("Delta packet refers to block {0}, which is not part of the basis file")]
+    InvalidBasisBlock(u64),
+
+    #[error("Chunk size of {0} bytes is too small, at least {1} bytes are needed")]
+    ChunkSizeTooSmall(u32, u32),
+
+    #[error("Received an invalid hash tree of the file")]
+    InvalidOutboard,
+
+    #[error("Received data at offset {0} does not match the file hash")]
+    CorruptData(u64),
+
+    #[error("Could not apply the attributes of the sent file: {0}")]
+    AttributesNotApplied(String),
+
+    #[error("The sent file name {0:?} cannot be used, specify an output file with -o <file>")]
+    InvalidFileName(String),
+
+    #[error("The output path {0} is not inside the output directory {1}")]
+    OutputOutsideDir(std::path::PathBuf, std::path::PathBuf),
+
+    #[error("The file is {0} bytes large, which exceeds the maximum size of {1}")]
+    FileTooLarge(u64, crate::utils::size::ByteSize),
+
+    #[error("Not enough disk space: {0} bytes needed, {1} bytes available")]
+    InsufficientSpace(u64, u64),
+
+    #[error("The basis file must not be the file being received: {0}")]
+    BasisIsOutput(std::path::PathBuf),
+
+    #[error("This relay-server does not relay data, it was started without --allow-relaying")]
+    RelayingNotAllowed,
+
+    #[error("Hole punching failed and the relay-server does not relay the data: {0}")]
+    RelayRefused(String),
+
+    #[error("The file is {0} bytes large, but the relay-server only relays {1} bytes per transfer")]
+    RelayQuotaTooSmall(u64, u64),
+
+    #[error("No sender on the local network offers this passphrase, it has to be sent with --local")]
+    LocalSessionNotFound,
+
+    #[error("The sender at {0} does not answer, it has to be started with --listen and the passphrase has to match")]
+    DirectPeerUnreachable(std::net::SocketAddr),
+
+    #[error("The peer at {0} does not know the passphrase")]
+    PeerNotAuthenticated(std::net::SocketAddr),
+
+    #[error("Invalid address: {0} (expected host:port)")]
+    InvalidAddress(String),
+
+    #[error("The relay-server does not answer the probes of doctor, it has to be updated")]
+    DiagnosisNotSupported,
 }
 
 pub type Result<T> = std::result::Result<T, NudgeError>;
@@ -58,7 +154,7 @@ mod tests {
 
     #[test]
     fn test_io_error() {
-        let io_error = io::Error::new(io::ErrorKind::Other, "some IO error");
+        let io_error = io::Error::other("some IO error");
         let nudge_error: NudgeError = io_error.into();
         assert!(matches!(nudge_error, NudgeError::Io(_)));
     }
@@ -5,8 +5,9 @@ use clap::{Parser};
 use simple_log::LogConfigBuilder;
 
 use crate::error::Result;
-use crate::commands::{SubCommand, server_command, send_command, get_command};
+use crate::commands::{SubCommand, server_command, send_command, get_command, doctor_command};
 
+mod config;
 mod error;
 mod utils;
 
@@ -31,9 +32,10 @@ fn main() -> Result<()> {
     simple_log::new(log_config).expect("Failed to initialize logger");
 
     match match &opts.subcmd {
-        SubCommand::Serve(server_opts) => server_command::run(&opts, &server_opts),
-        SubCommand::Send(send_opts) => send_command::run(&opts, &send_opts),
-        SubCommand::Get(get_opts) => get_command::run(&opts, &get_opts),
+        SubCommand::Serve(server_opts) => server_command::run(&opts, server_opts),
+        SubCommand::Send(send_opts) => send_command::run(&opts, send_opts),
+        SubCommand::Get(get_opts) => get_command::run(&opts, get_opts),
+        SubCommand::Doctor(doctor_opts) => doctor_command::run(&opts, doctor_opts),
     } {
         Err(e) => {
             error!("Error: {}", e);
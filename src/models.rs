@@ -1,9 +1,10 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use serde::{Deserialize, Serialize};
 use crate::utils::passphrase::Passphrase;
 use crate::utils::AnonymousString;
+use crate::utils::interfaces::shared_ips;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     /// Size of the file in bytes
     pub(crate) file_size: u64,
@@ -11,8 +12,13 @@ pub struct FileInfo {
     /// Name of the file
     pub(crate) file_name: String,
 
-    /// Hash of the file (optional)
-    pub(crate) file_hash: AnonymousString,
+    /// Whether the sender hashes the file, the hash is sent to the receiver before the data
+    #[serde(default)]
+    pub(crate) hashed: bool,
+
+    /// Whether the sender adds parity packets, which the receiver then uses to rebuild lost packets
+    #[serde(default)]
+    pub(crate) fec: bool,
 
     /// Hostname of the sender (optional)
     pub(crate) sender_host: AnonymousString,
@@ -22,6 +28,73 @@ pub struct FileInfo {
 
     /// Address of the sender
     pub(crate) sender_addr: SocketAddr,
+
+    /// Number of parallel streams the file is sent over
+    #[serde(default = "default_streams")]
+    pub(crate) streams: u8,
+
+    /// Addresses of the additional sender streams (stream 1 onwards)
+    #[serde(default)]
+    pub(crate) sender_stream_addrs: Vec<SocketAddr>,
+
+    /// Addresses of the additional receiver streams, only known to the relay-server
+    #[serde(skip)]
+    pub(crate) receiver_stream_addrs: Vec<SocketAddr>,
+
+    /// Local addresses of the sender, the private ones only if both peers are in the same network
+    #[serde(default)]
+    pub(crate) sender_local: LocalCandidates,
+
+    /// Whether the relay-server saw both peers in the same network, so their private addresses are passed on
+    #[serde(default)]
+    pub(crate) same_network: bool,
+
+    /// Local addresses of the receiver, only known to the relay-server
+    #[serde(skip)]
+    pub(crate) receiver_local: LocalCandidates,
+
+    /// Proof that the sender knows the passphrase, only sent to a receiver which connects directly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sender_proof: Option<String>,
+}
+
+fn default_streams() -> u8 {
+    1
+}
+
+/// Addresses of the interfaces of a peer, which another peer can reach without NAT traversal:
+/// private ones from within the same network, global IPv6 ones from anywhere
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocalCandidates {
+    /// Private and global IPv6 addresses of the interfaces
+    pub(crate) ips: Vec<IpAddr>,
+
+    /// Local port of every stream socket by stream index, 0 if unknown
+    pub(crate) ports: Vec<u16>,
+}
+
+impl LocalCandidates {
+    /// Records the local port of a stream
+    pub fn set_port(&mut self, stream_index: u8, port: u16) {
+        let index = stream_index as usize;
+        if self.ports.len() <= index {
+            self.ports.resize(index + 1, 0);
+        }
+        self.ports[index] = port;
+    }
+
+    /// The addresses passed on to the other peer, the private ones only if both peers are in the same network
+    pub fn shared(&self, same_network: bool) -> LocalCandidates {
+        LocalCandidates { ips: shared_ips(&self.ips, same_network), ports: self.ports.clone() }
+    }
+
+    /// The local addresses of a stream socket
+    pub fn stream_addrs(&self, stream_index: usize) -> Vec<SocketAddr> {
+        match self.ports.get(stream_index) {
+            Some(&port) if port != 0 => self.ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect(),
+            _ => Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,11 +105,28 @@ pub struct S2XRequestPassphraseMessage {
     /// Name of the file
     pub(crate) file_name: String,
 
-    /// Hash of the file (optional)
-    pub(crate) file_hash: AnonymousString,
+    /// Whether the sender hashes the file, the hashing may still be running
+    #[serde(default)]
+    pub(crate) hashed: bool,
+
+    /// Whether the sender adds parity packets
+    #[serde(default)]
+    pub(crate) fec: bool,
 
     /// Hostname of the sender (optional)
     pub(crate) sender_host: AnonymousString,
+
+    /// Number of parallel streams the file will be sent over
+    #[serde(default = "default_streams")]
+    pub(crate) streams: u8,
+
+    /// Private and global IPv6 addresses of the sender's interfaces
+    #[serde(default)]
+    pub(crate) local_ips: Vec<IpAddr>,
+
+    /// Local port of the socket (0 if unknown)
+    #[serde(default)]
+    pub(crate) local_port: u16,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -45,22 +135,73 @@ pub struct X2SPassphraseProvidedMessage {
     pub(crate) passphrase: Passphrase<'static>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2XRegisterStreamMessage {
+    /// Passphrase of the transfer the stream belongs to
+    pub(crate) passphrase: Passphrase<'static>,
+
+    /// Index of the stream (starting at 1, stream 0 is the socket which requested the passphrase)
+    pub(crate) stream_index: u8,
+
+    /// Local port of the stream socket (0 if unknown)
+    #[serde(default)]
+    pub(crate) local_port: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2SStreamRegisteredMessage {
+    /// Index of the stream which was registered
+    pub(crate) stream_index: u8,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct R2XRequestFileInfoMessage {
     /// Passphrase to access the file
     pub(crate) passphrase: Passphrase<'static>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct R2XRegisterStreamMessage {
+    /// Passphrase of the transfer the stream belongs to
+    pub(crate) passphrase: Passphrase<'static>,
+
+    /// Index of the stream (starting at 1, stream 0 is the socket which requests the connection)
+    pub(crate) stream_index: u8,
+
+    /// Local port of the stream socket (0 if unknown)
+    #[serde(default)]
+    pub(crate) local_port: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2RStreamRegisteredMessage {
+    /// Index of the stream which was registered
+    pub(crate) stream_index: u8,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct R2XRequestSenderConnectionMessage {
     /// Passphrase to access the file
     pub(crate) passphrase: Passphrase<'static>,
 
-    /// Size of the file in bytes (optional)
-    pub(crate) file_hash: AnonymousString,
-
     /// Hostname of the receiver (optional)
     pub(crate) receiver_host: AnonymousString,
+
+    /// Bandwidth limit the receiver asks the sender for, in bytes per second (optional)
+    #[serde(default)]
+    pub(crate) limit: Option<u64>,
+
+    /// Whether the receiver has a basis file and wants a delta transfer
+    #[serde(default)]
+    pub(crate) basis: bool,
+
+    /// Private and global IPv6 addresses of the receiver's interfaces
+    #[serde(default)]
+    pub(crate) local_ips: Vec<IpAddr>,
+
+    /// Local port of the socket (0 if unknown)
+    #[serde(default)]
+    pub(crate) local_port: u16,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -68,4 +209,200 @@ pub struct X2SSenderConnectToReceiverMessage {
     /// Address of the receiver
     pub(crate) receiver_addr: SocketAddr,
     pub(crate) receiver_host: AnonymousString,
+
+    /// Addresses of the additional receiver streams (stream 1 onwards)
+    #[serde(default)]
+    pub(crate) receiver_stream_addrs: Vec<SocketAddr>,
+
+    /// Bandwidth limit requested by the receiver, in bytes per second (optional)
+    #[serde(default)]
+    pub(crate) limit: Option<u64>,
+
+    /// Whether the receiver sends block signatures of a basis file for a delta transfer
+    #[serde(default)]
+    pub(crate) basis: bool,
+
+    /// Local addresses of the receiver, the private ones only if both peers are in the same network
+    #[serde(default)]
+    pub(crate) receiver_local: LocalCandidates,
+
+    /// Whether the relay-server saw both peers in the same network, so their private addresses are passed on
+    #[serde(default)]
+    pub(crate) same_network: bool,
+}
+
+// Messages between the relay-server and either peer (P), which agree on when to punch the hole
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2PPingMessage {
+    /// Passphrase of the transfer, echoed in the reply
+    pub(crate) passphrase: Passphrase<'static>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct P2XPongMessage {
+    /// Passphrase of the transfer the ping belonged to
+    pub(crate) passphrase: Passphrase<'static>,
+
+    /// Number of probe sockets the peer sent from, the relay-server waits for their probes before the punching starts
+    #[serde(default)]
+    pub(crate) probes: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct P2XProbeMessage {
+    /// Passphrase of the transfer the probing peer belongs to
+    pub(crate) passphrase: Passphrase<'static>,
+
+    /// Whether the probing peer is the sender
+    pub(crate) sender: bool,
+
+    /// Order in which the probe sockets were opened
+    pub(crate) index: u8,
+
+    /// Local port of the probe socket
+    pub(crate) local_port: u16,
+}
+
+/// How the NAT of a peer picks the public port of a new mapping, observed by the relay-server from the probe sockets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PortAllocation {
+    /// The public port is the local port
+    Preserving,
+
+    /// Every mapping gets a port at least `delta` after the previous one, the last probe got `last_port`
+    Sequential { last_port: u16, delta: i32 },
+
+    /// No pattern, the port of a mapping can only be guessed
+    Random,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct P2XPunchReadyMessage {
+    /// Passphrase of the transfer, the peer still waits for the time to start punching
+    pub(crate) passphrase: Passphrase<'static>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2PStartPunchingMessage {
+    /// Milliseconds until the punching starts, negative if it started already
+    pub(crate) start_in_ms: i64,
+
+    /// Attempts which follow each other if the previous one failed
+    pub(crate) attempts: Vec<PunchAttempt>,
+
+    /// Port allocation of the NAT of the peer the message goes to, if it probed
+    #[serde(default)]
+    pub(crate) allocation: Option<PortAllocation>,
+
+    /// Port allocation of the NAT of the other peer, if it probed
+    #[serde(default)]
+    pub(crate) peer_allocation: Option<PortAllocation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PunchAttempt {
+    /// Start of the attempt, in milliseconds after the punching started
+    pub(crate) offset_ms: u64,
+
+    /// Number of packets sent to the peer
+    pub(crate) packets: u32,
+
+    /// Milliseconds between two packets
+    pub(crate) interval_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct P2XRequestRelayMessage {
+    /// Passphrase of the transfer, hole punching to the other peer failed
+    pub(crate) passphrase: Passphrase<'static>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct X2PRelayAcceptedMessage {
+    /// Bytes the relay-server forwards for the whole transfer, in both directions
+    pub(crate) quota: u64,
+
+    /// Bandwidth the relay-server forwards for the whole transfer, in bytes per second (optional)
+    #[serde(default)]
+    pub(crate) limit: Option<u64>,
+}
+
+// Messages between the relay-server and `nudge doctor` (D), which diagnoses the connectivity of the machine
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct D2XProbeMessage {
+    /// Random number echoed in the answer, so the answer of a late probe is not taken for another one
+    pub(crate) nonce: u64,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct X2DObservationMessage {
+    /// Nonce of the probe
+    pub(crate) nonce: u64,
+
+    /// Address the probe came from, as seen by the relay-server
+    pub(crate) observed: SocketAddr,
+
+    /// Port on which the relay-server answers probes as well, the second destination of the mapping test (optional)
+    #[serde(default)]
+    pub(crate) probe_port: Option<u16>,
+
+    /// Whether the relay-server forwards the data of peers which cannot punch a hole
+    #[serde(default)]
+    pub(crate) relaying: bool,
+}
+
+// Messages between the peers on the local network or connecting directly, which find each other without a relay-server
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2RAnnounceMessage {
+    /// Random salt of the session, hashed together with the passphrase
+    pub(crate) salt: String,
+
+    /// Hash of the salt and the passphrase, the passphrase itself is never announced
+    pub(crate) session: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct R2SRequestFileInfoMessage {
+    /// Another hash of the salt and the passphrase, proves that the receiver knows the passphrase
+    pub(crate) proof: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct R2SHelloMessage {
+    /// Random salt of the receiver, the sender proves with it that it knows the passphrase
+    pub(crate) salt: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2RChallengeMessage {
+    /// Random salt for this receiver, it proves with it that it knows the passphrase
+    pub(crate) salt: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2RChunkSizeQueryMessage {
+    /// Chunk size the sender was told to use (optional)
+    pub(crate) chunk_size: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct R2SChunkSizeReplyMessage {
+    /// Size of the largest path MTU probe which arrived at the receiver
+    pub(crate) largest_probe: u32,
+
+    /// Chunk size the receiver was told to use (optional)
+    pub(crate) chunk_size: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S2RChunkSizeConfirmMessage {
+    /// Chunk size the sender decided on, none if the peers disagree
+    pub(crate) chunk_size: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct R2SChunkSizeAckMessage {}
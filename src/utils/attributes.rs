@@ -0,0 +1,194 @@
+use std::fs::{File, FileTimes};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NudgeError, Result};
+
+/// Permission bits which are applied, setuid and setgid are never set on a received file
+#[cfg(unix)]
+const APPLIED_MODE_BITS: u32 = 0o1777;
+
+/// Namespace of the extended attributes which are sent, the others belong to the system (ACLs, security labels)
+#[cfg(unix)]
+const XATTR_NAMESPACE: &str = "user.";
+
+/// Metadata of the sent file, which the receiver applies to its copy.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileAttributes {
+    /// Unix permission bits (optional)
+    pub(crate) mode: Option<u32>,
+
+    /// Time of the last modification since the Unix epoch (optional)
+    pub(crate) modified: Option<Duration>,
+
+    /// Time of the last access since the Unix epoch (optional)
+    pub(crate) accessed: Option<Duration>,
+
+    /// Extended attributes in the user namespace, by name
+    #[serde(default)]
+    pub(crate) xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl FileAttributes {
+    /// Collects the attributes of `file`, attributes the platform does not know are left out.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the metadata of the file cannot be read
+    pub fn collect(file: &File) -> Result<FileAttributes> {
+        let metadata = file.metadata()?;
+        let since_epoch = |time: std::io::Result<SystemTime>| time.ok()
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok());
+
+        Ok(FileAttributes {
+            mode: mode_of(&metadata),
+            modified: since_epoch(metadata.modified()),
+            accessed: since_epoch(metadata.accessed()),
+            xattrs: xattrs_of(file),
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<FileAttributes> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    /// Applies the attributes to `file`, the times last so nothing else changes them afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::AttributesNotApplied` if the file system rejects any of them,
+    /// the others are still applied.
+    pub fn apply(&self, file: &File) -> Result<()> {
+        let mut failed = Vec::new();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            use xattr::FileExt;
+
+            for (name, value) in &self.xattrs {
+                if !name.starts_with(XATTR_NAMESPACE) {
+                    continue;
+                }
+                if let Err(err) = file.set_xattr(name, value) {
+                    failed.push(format!("extended attribute {} ({})", name, err));
+                }
+            }
+            if let Some(mode) = self.mode {
+                let permissions = std::fs::Permissions::from_mode(mode & APPLIED_MODE_BITS);
+                if let Err(err) = file.set_permissions(permissions) {
+                    failed.push(format!("permissions ({})", err));
+                }
+            }
+        }
+
+        let mut times = FileTimes::new();
+        if let Some(modified) = self.modified {
+            times = times.set_modified(SystemTime::UNIX_EPOCH + modified);
+        }
+        if let Some(accessed) = self.accessed {
+            times = times.set_accessed(SystemTime::UNIX_EPOCH + accessed);
+        }
+        if let Err(err) = file.set_times(times) {
+            failed.push(format!("times ({})", err));
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(NudgeError::AttributesNotApplied(failed.join(", ")))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// The extended attributes of the user namespace, a file system without them has none
+#[cfg(unix)]
+fn xattrs_of(file: &File) -> Vec<(String, Vec<u8>)> {
+    use xattr::FileExt;
+
+    let names = match file.list_xattr() {
+        Ok(names) => names,
+        Err(err) => {
+            debug!("Cannot list extended attributes: {}", err);
+            return Vec::new();
+        }
+    };
+    names
+        .filter_map(|name| name.into_string().ok())
+        .filter(|name| name.starts_with(XATTR_NAMESPACE))
+        .filter_map(|name| match file.get_xattr(&name) {
+            Ok(Some(value)) => Some((name, value)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn xattrs_of(_file: &File) -> Vec<(String, Vec<u8>)> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> (std::path::PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("nudge-attributes-{}-{}", name, std::process::id()));
+        let file = File::options().create(true).truncate(true).read(true).write(true).open(&path).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let attributes = FileAttributes {
+            mode: Some(0o755),
+            modified: Some(Duration::new(1_700_000_000, 123)),
+            accessed: None,
+            xattrs: vec![("user.origin".to_string(), vec![0, 1, 255])],
+        };
+        assert_eq!(FileAttributes::decode(&attributes.encode().unwrap()).unwrap(), attributes);
+    }
+
+    #[test]
+    fn test_apply_times() {
+        let (path, file) = temp_file("times");
+        let attributes = FileAttributes {
+            modified: Some(Duration::from_secs(1_500_000_000)),
+            accessed: Some(Duration::from_secs(1_600_000_000)),
+            ..FileAttributes::default()
+        };
+        attributes.apply(&file).unwrap();
+
+        let applied = FileAttributes::collect(&file).unwrap();
+        assert_eq!(applied.modified, attributes.modified);
+        assert_eq!(applied.accessed, attributes.accessed);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_never_sets_setuid_or_setgid() {
+        let (path, file) = temp_file("mode");
+        let attributes = FileAttributes { mode: Some(0o6755), ..FileAttributes::default() };
+        attributes.apply(&file).unwrap();
+
+        assert_eq!(FileAttributes::collect(&file).unwrap().mode, Some(0o755));
+        std::fs::remove_file(path).unwrap();
+    }
+}
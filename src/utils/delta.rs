@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::streams::read_exact_at;
+
+/// Bytes of the BLAKE3 hash kept per block
+const STRONG_SIZE: usize = 16;
+
+/// Size of a single entry of an encoded signature: weak checksum and strong hash
+const ENTRY_SIZE: usize = 4 + STRONG_SIZE;
+
+/// Block sizes are powers of two between these bounds
+const MIN_BLOCK_SIZE: u32 = 1024;
+const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+
+/// How much of the file is read at once while searching for matching blocks
+const READ_SIZE: usize = 1024 * 1024;
+
+/// Delta packets need room for a copy instruction
+pub const MIN_DELTA_CHUNK_SIZE: u32 = 16;
+
+const LITERAL_TAG: u8 = 0;
+const COPY_TAG: u8 = 1;
+
+/// Picks the block size for a basis file of `len` bytes: about the square root, like rsync does.
+pub fn block_size_for(len: u64) -> u32 {
+    ((len as f64).sqrt() as u32).next_power_of_two().clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// Weak checksum which can be moved along the data one byte at a time (the one rsync uses)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RollingChecksum {
+    a: u16,
+    b: u16,
+    len: u16,
+}
+
+impl RollingChecksum {
+    pub fn new(data: &[u8]) -> Self {
+        let len = data.len();
+        let mut a = 0u16;
+        let mut b = 0u16;
+        for (index, byte) in data.iter().enumerate() {
+            a = a.wrapping_add(*byte as u16);
+            b = b.wrapping_add(((len - index) as u16).wrapping_mul(*byte as u16));
+        }
+        RollingChecksum { a, b, len: len as u16 }
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.b as u32) << 16 | self.a as u32
+    }
+
+    /// Moves the window one byte ahead: `old` leaves it, `new` enters it.
+    pub fn roll(&mut self, old: u8, new: u8) {
+        self.a = self.a.wrapping_sub(old as u16).wrapping_add(new as u16);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(old as u16)).wrapping_add(self.a);
+    }
+}
+
+fn strong_hash(data: &[u8]) -> [u8; STRONG_SIZE] {
+    let mut strong = [0u8; STRONG_SIZE];
+    strong.copy_from_slice(&blake3::hash(data).as_bytes()[..STRONG_SIZE]);
+    strong
+}
+
+/// Checksums of every full block of a basis file, sent by the receiver to the sender
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub(crate) block_size: u32,
+    pub(crate) blocks: Vec<(u32, [u8; STRONG_SIZE])>,
+}
+
+impl Signature {
+    /// Computes the signature of `file`, a trailing partial block is left out.
+    pub fn compute(file: &File) -> Result<Signature> {
+        let len = file.metadata()?.len();
+        let block_size = block_size_for(len);
+        let mut buffer = vec![0u8; block_size as usize];
+
+        let blocks = (0..len / block_size as u64)
+            .map(|index| {
+                read_exact_at(file, &mut buffer, index * block_size as u64)?;
+                Ok((RollingChecksum::new(&buffer).value(), strong_hash(&buffer)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Signature { block_size, blocks })
+    }
+
+    /// Encodes the signature as `[block size u32][entries]`, every entry being `[weak u32][strong hash]`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.blocks.len() * ENTRY_SIZE);
+        bytes.extend_from_slice(&self.block_size.to_be_bytes());
+        for (weak, strong) in &self.blocks {
+            bytes.extend_from_slice(&weak.to_be_bytes());
+            bytes.extend_from_slice(strong);
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Signature> {
+        if bytes.len() < 4 || !(bytes.len() - 4).is_multiple_of(ENTRY_SIZE) {
+            return Err(NudgeError::InvalidSignature);
+        }
+        let block_size = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(NudgeError::InvalidSignature);
+        }
+        let blocks = bytes[4..].chunks_exact(ENTRY_SIZE)
+            .map(|entry| {
+                let weak = u32::from_be_bytes(entry[..4].try_into().unwrap());
+                (weak, entry[4..].try_into().unwrap())
+            })
+            .collect();
+        Ok(Signature { block_size, blocks })
+    }
+}
+
+/// Local copy of an older version of the file, which the receiver rebuilds the new version from
+pub struct Basis {
+    file: File,
+    signature: Signature,
+}
+
+impl Basis {
+    /// Opens the basis file and computes its signature.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the file cannot be read
+    pub fn open(path: &Path) -> Result<Basis> {
+        let file = File::open(path)?;
+        let signature = Signature::compute(&file)?;
+        Ok(Basis { file, signature })
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    /// Reads block `index` of the basis into `buffer`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidBasisBlock` if the basis has no such block
+    pub fn read_block(&self, index: u64, buffer: &mut Vec<u8>) -> Result<()> {
+        if index >= self.signature.blocks.len() as u64 {
+            return Err(NudgeError::InvalidBasisBlock(index));
+        }
+        let block_size = self.signature.block_size as usize;
+        buffer.resize(block_size, 0);
+        read_exact_at(&self.file, buffer, index * block_size as u64)?;
+        Ok(())
+    }
+}
+
+/// Looks up blocks of a signature by their weak checksum first, then by their strong hash
+pub struct SignatureIndex {
+    block_size: usize,
+    by_weak: HashMap<u32, Vec<(u64, [u8; STRONG_SIZE])>>,
+}
+
+impl SignatureIndex {
+    pub fn new(signature: &Signature) -> Self {
+        let mut by_weak: HashMap<u32, Vec<_>> = HashMap::with_capacity(signature.blocks.len());
+        for (index, (weak, strong)) in signature.blocks.iter().enumerate() {
+            by_weak.entry(*weak).or_default().push((index as u64, *strong));
+        }
+        SignatureIndex { block_size: signature.block_size as usize, by_weak }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the index of a basis block with the same content as `window`, if there is one.
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        let candidates = self.by_weak.get(&weak)?;
+        let strong = strong_hash(window);
+        candidates.iter()
+            .find(|(_, candidate)| *candidate == strong)
+            .map(|(index, _)| *index)
+    }
+}
+
+/// One instruction to rebuild the file on the receiver
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaOp<'a> {
+    /// Bytes the basis does not contain
+    Literal(&'a [u8]),
+    /// `count` consecutive blocks of the basis, starting at block `block`
+    Copy { block: u64, count: u32 },
+}
+
+impl DeltaOp<'_> {
+    /// Encodes the instruction into `packet`; the tag is the last byte,
+    /// so the receiver can strip it from literal data without moving the data.
+    pub fn encode(&self, packet: &mut Vec<u8>) {
+        packet.clear();
+        match self {
+            DeltaOp::Literal(data) => {
+                packet.extend_from_slice(data);
+                packet.push(LITERAL_TAG);
+            }
+            DeltaOp::Copy { block, count } => {
+                packet.extend_from_slice(&block.to_be_bytes());
+                packet.extend_from_slice(&count.to_be_bytes());
+                packet.push(COPY_TAG);
+            }
+        }
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<DeltaOp<'_>> {
+        match packet.split_last() {
+            Some((&LITERAL_TAG, data)) => Ok(DeltaOp::Literal(data)),
+            Some((&COPY_TAG, data)) if data.len() == 12 => Ok(DeltaOp::Copy {
+                block: u64::from_be_bytes(data[..8].try_into().unwrap()),
+                count: u32::from_be_bytes(data[8..].try_into().unwrap()),
+            }),
+            _ => Err(NudgeError::InvalidDeltaPacket),
+        }
+    }
+}
+
+/// Collects consecutive copies into one instruction before passing them on
+struct OpSink<F> {
+    emit: F,
+    pending_copy: Option<(u64, u32)>,
+}
+
+impl<F: FnMut(DeltaOp) -> Result<()>> OpSink<F> {
+    fn copy(&mut self, block: u64) -> Result<()> {
+        match &mut self.pending_copy {
+            Some((start, count)) if *start + *count as u64 == block => *count += 1,
+            _ => {
+                self.flush_copy()?;
+                self.pending_copy = Some((block, 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn literal(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy()?;
+        (self.emit)(DeltaOp::Literal(data))
+    }
+
+    fn flush_copy(&mut self) -> Result<()> {
+        match self.pending_copy.take() {
+            Some((block, count)) => (self.emit)(DeltaOp::Copy { block, count }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Part of the file kept in memory while searching for matching blocks
+struct Window<'a> {
+    file: &'a File,
+    end: u64,
+    buffer: Vec<u8>,
+    /// File offset of `buffer[0]`
+    start: u64,
+}
+
+impl Window<'_> {
+    /// Makes sure the bytes up to `until` are loaded, dropping the ones before `keep_from`.
+    fn load(&mut self, keep_from: u64, until: u64) -> Result<()> {
+        let loaded_until = self.start + self.buffer.len() as u64;
+        if until <= loaded_until {
+            return Ok(());
+        }
+        self.buffer.drain(..(keep_from - self.start) as usize);
+        self.start = keep_from;
+
+        let offset = self.start + self.buffer.len() as u64;
+        let len = ((until - offset) as usize).max(READ_SIZE).min((self.end - offset) as usize);
+        let previous_len = self.buffer.len();
+        self.buffer.resize(previous_len + len, 0);
+        read_exact_at(self.file, &mut self.buffer[previous_len..], offset)?;
+        Ok(())
+    }
+
+    fn get(&self, range: Range<u64>) -> &[u8] {
+        &self.buffer[(range.start - self.start) as usize..(range.end - self.start) as usize]
+    }
+}
+
+/// Finds the blocks of `range` of `file` which the basis already has and emits the instructions
+/// to rebuild that range from the basis.
+///
+/// # Arguments
+///
+/// * `file` - The new version of the file.
+/// * `range` - The part of the file to encode.
+/// * `index` - The signature of the basis.
+/// * `max_literal` - Largest literal instruction to emit.
+/// * `emit` - Called for every instruction, in order.
+pub fn encode_range<F>(file: &File, range: Range<u64>, index: &SignatureIndex, max_literal: usize, emit: F) -> Result<()>
+where
+    F: FnMut(DeltaOp) -> Result<()>,
+{
+    let block_size = index.block_size() as u64;
+    let mut sink = OpSink { emit, pending_copy: None };
+    let mut window = Window { file, end: range.end, buffer: Vec::new(), start: range.start };
+
+    let mut position = range.start;
+    let mut literal_start = position;
+    let mut checksum: Option<RollingChecksum> = None;
+
+    while position + block_size <= range.end {
+        window.load(literal_start, position + block_size)?;
+        let block = window.get(position..position + block_size);
+        let weak = *checksum.get_or_insert_with(|| RollingChecksum::new(block));
+
+        if let Some(matching_block) = index.find(weak.value(), block) {
+            sink.literal(window.get(literal_start..position))?;
+            sink.copy(matching_block)?;
+            position += block_size;
+            literal_start = position;
+            checksum = None;
+            continue;
+        }
+
+        if (position + 1 - literal_start) as usize >= max_literal {
+            sink.literal(window.get(literal_start..position + 1))?;
+            literal_start = position + 1;
+        }
+        if position + block_size < range.end {
+            window.load(literal_start.min(position), position + block_size + 1)?;
+            let old = window.get(position..position + 1)[0];
+            let new = window.get(position + block_size..position + block_size + 1)[0];
+            checksum = checksum.map(|mut checksum| {
+                checksum.roll(old, new);
+                checksum
+            });
+        }
+        position += 1;
+    }
+
+    // the tail is shorter than a block
+    window.load(literal_start, range.end)?;
+    for start in (literal_start..range.end).step_by(max_literal) {
+        sink.literal(window.get(start..(start + max_literal as u64).min(range.end)))?;
+    }
+    sink.flush_copy()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn temp_file(name: &str, content: &[u8]) -> (std::path::PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("nudge-delta-{}-{}", name, std::process::id()));
+        File::create(&path).unwrap().write_all(content).unwrap();
+        let file = File::open(&path).unwrap();
+        (path, file)
+    }
+
+    /// Pseudo-random but reproducible content
+    fn content(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Rebuilds `range` of the new file from the basis and the instructions, like the receiver does.
+    fn rebuild(basis: &[u8], new: &File, range: Range<u64>, index: &SignatureIndex, max_literal: usize) -> (Vec<u8>, usize) {
+        let block_size = index.block_size();
+        let mut rebuilt = Vec::new();
+        let mut literal_bytes = 0;
+        let mut packet = Vec::new();
+        encode_range(new, range, index, max_literal, |op| {
+            op.encode(&mut packet);
+            assert!(packet.len() <= max_literal + 1);
+            match DeltaOp::decode(&packet)? {
+                DeltaOp::Literal(data) => {
+                    literal_bytes += data.len();
+                    rebuilt.extend_from_slice(data);
+                }
+                DeltaOp::Copy { block, count } => {
+                    let start = block as usize * block_size;
+                    rebuilt.extend_from_slice(&basis[start..start + count as usize * block_size]);
+                }
+            }
+            Ok(())
+        }).unwrap();
+        (rebuilt, literal_bytes)
+    }
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data = content(5000, 1);
+        let mut rolling = RollingChecksum::new(&data[..1024]);
+        for start in 1..100 {
+            rolling.roll(data[start - 1], data[start + 1023]);
+            assert_eq!(rolling, RollingChecksum::new(&data[start..start + 1024]));
+        }
+    }
+
+    #[test]
+    fn test_signature_roundtrip() {
+        let (path, file) = temp_file("signature", &content(10_000, 2));
+        let signature = Signature::compute(&file).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(signature.block_size, 1024);
+        // the partial block at the end is left out
+        assert_eq!(signature.blocks.len(), 9);
+        assert_eq!(Signature::decode(&signature.encode()).unwrap(), signature);
+        assert!(matches!(Signature::decode(&[0, 0, 4]), Err(NudgeError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_delta_with_insertions_and_changes() {
+        let basis = content(100_000, 3);
+        let mut new = basis.clone();
+        new.splice(5_000..5_000, content(777, 4)); // insertion shifts everything after it
+        new[60_000..60_100].copy_from_slice(&content(100, 5)); // change in place
+        new.truncate(90_000);
+        new.extend_from_slice(&content(3_333, 6));
+
+        let (basis_path, basis_file) = temp_file("basis", &basis);
+        let (new_path, new_file) = temp_file("new", &new);
+        let index = SignatureIndex::new(&Signature::compute(&basis_file).unwrap());
+
+        let (rebuilt, literal_bytes) = rebuild(&basis, &new_file, 0..new.len() as u64, &index, 1000);
+        std::fs::remove_file(basis_path).unwrap();
+        std::fs::remove_file(new_path).unwrap();
+
+        assert_eq!(rebuilt, new);
+        assert!(literal_bytes < 10_000, "{} literal bytes", literal_bytes);
+    }
+
+    #[test]
+    fn test_delta_of_unrelated_range() {
+        let basis = content(20_000, 7);
+        let new = content(30_000, 8);
+        let (basis_path, basis_file) = temp_file("unrelated-basis", &basis);
+        let (new_path, new_file) = temp_file("unrelated-new", &new);
+        let index = SignatureIndex::new(&Signature::compute(&basis_file).unwrap());
+
+        let (rebuilt, literal_bytes) = rebuild(&basis, &new_file, 1_000..25_000, &index, 500);
+        std::fs::remove_file(basis_path).unwrap();
+        std::fs::remove_file(new_path).unwrap();
+
+        assert_eq!(rebuilt, new[1_000..25_000]);
+        assert_eq!(literal_bytes, 24_000);
+    }
+
+    #[test]
+    fn test_decode_invalid_packet() {
+        assert!(matches!(DeltaOp::decode(&[]), Err(NudgeError::InvalidDeltaPacket)));
+        assert!(matches!(DeltaOp::decode(&[1, 2, 1]), Err(NudgeError::InvalidDeltaPacket)));
+        assert!(matches!(DeltaOp::decode(&[7]), Err(NudgeError::InvalidDeltaPacket)));
+    }
+}
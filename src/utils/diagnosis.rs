@@ -0,0 +1,441 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::random;
+
+use crate::error::{NudgeError, Result};
+use crate::models::{D2XProbeMessage, PortAllocation, X2DObservationMessage};
+use crate::utils::interfaces::{global_ipv6_ips, is_interface_ip};
+use crate::utils::prediction::{classify, PROBE_SOCKETS};
+use crate::utils::serialize::{parse_and_expect, serialize_and_send_to};
+use crate::utils::socket::{bind_to, canonical};
+
+/// Probes sent to the relay-server to measure the round trip time
+pub const RTT_PROBES: usize = 5;
+
+/// How long to wait for the answer to a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often a probe which only has to be answered once is sent
+const PROBE_ATTEMPTS: usize = 3;
+
+/// Packets every socket sends to the public address of the other in the hairpinning test
+const HAIRPIN_PACKETS: usize = 5;
+
+/// Time between two packets of the hairpinning test
+const HAIRPIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Content of the packets of the hairpinning test
+const HAIRPIN_TAG: &[u8] = b"NUDGE_HAIRPIN";
+
+/// How the NAT maps a socket to public addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapping {
+    /// The socket has a public address of its own
+    NoNat,
+    /// The same public address for every destination, the peer can punch a hole to it
+    EndpointIndependent,
+    /// Another public address for every destination (symmetric NAT), the peer has to predict it
+    EndpointDependent,
+    /// The second port of the relay-server did not answer
+    Unknown,
+}
+
+/// Compares the public addresses of one socket towards both ports of the relay-server.
+///
+/// # Arguments
+///
+/// * `nat` - Whether a NAT is in the way, i.e. the first public address is not the address of the socket.
+/// * `first` - The public address towards the port of the relay-server.
+/// * `second` - The public address towards its second port, `None` if it did not answer.
+pub fn mapping(nat: bool, first: SocketAddr, second: Option<SocketAddr>) -> Mapping {
+    match second {
+        _ if !nat => Mapping::NoNat,
+        None => Mapping::Unknown,
+        Some(second) if second == first => Mapping::EndpointIndependent,
+        Some(_) => Mapping::EndpointDependent,
+    }
+}
+
+/// What the probes over one address family showed
+#[derive(Debug, Clone)]
+pub struct FamilyReport {
+    /// The address of the relay-server the probes went to
+    pub relay_addr: SocketAddr,
+    /// Round trip times of the answered probes, out of `RTT_PROBES`
+    pub rtts: Vec<Duration>,
+    /// Local port of the probing socket
+    pub local_port: u16,
+    /// Public address of the probing socket, `None` if the relay-server did not answer
+    pub public: Option<SocketAddr>,
+    pub mapping: Mapping,
+    /// How the NAT picks the port of a new mapping, `None` without a NAT
+    pub allocation: Option<PortAllocation>,
+    /// Whether packets to our own public address come back, `None` without a NAT
+    pub hairpin: Option<bool>,
+    /// Whether the relay-server forwards the data of peers which cannot punch a hole
+    pub relaying: bool,
+}
+
+impl FamilyReport {
+    pub fn reachable(&self) -> bool {
+        self.public.is_some()
+    }
+}
+
+/// What `nudge doctor` found out about the connectivity of this machine
+#[derive(Debug, Clone)]
+pub struct Diagnosis {
+    /// Over IPv4, `None` if the relay-server has no IPv4 address
+    pub ipv4: Option<FamilyReport>,
+    /// Over IPv6, `None` if the relay-server has no IPv6 address
+    pub ipv6: Option<FamilyReport>,
+    /// The global IPv6 addresses of this machine
+    pub global_ipv6: Vec<IpAddr>,
+}
+
+impl Diagnosis {
+    /// The report of the family the peers reach the relay-server over, IPv4 if both are reachable.
+    fn coordination(&self) -> Option<&FamilyReport> {
+        self.ipv4.iter().chain(&self.ipv6).find(|report| report.reachable())
+    }
+}
+
+/// Whether a way to connect to a peer works from this machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outlook {
+    Works,
+    /// Works with some peers, depending on their network
+    Depends,
+    Fails,
+    /// Could not be tested
+    Unknown,
+}
+
+/// A way to connect to a peer, and whether it works from this machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Strategy {
+    pub name: &'static str,
+    pub outlook: Outlook,
+    pub reason: String,
+}
+
+impl Strategy {
+    fn new(name: &'static str, outlook: Outlook, reason: &str) -> Self {
+        Strategy { name, outlook, reason: reason.to_string() }
+    }
+}
+
+/// Probes the relay-server over every family it has an address of.
+///
+/// # Arguments
+///
+/// * `relay_addrs` - The addresses of the relay-server, the first one of each family is probed.
+///
+/// # Errors
+///
+/// Returns `NudgeError::DiagnosisNotSupported` if the relay-server does not know the probes,
+/// or `NudgeError::Io` if the sockets fail
+pub fn diagnose(relay_addrs: &[SocketAddr]) -> Result<Diagnosis> {
+    let probe_family = |ipv6: bool| relay_addrs.iter()
+        .copied()
+        .find(|addr| addr.is_ipv6() == ipv6)
+        .map(diagnose_family)
+        .transpose();
+    Ok(Diagnosis {
+        ipv4: probe_family(false)?,
+        ipv6: probe_family(true)?,
+        global_ipv6: global_ipv6_ips(),
+    })
+}
+
+/// Probes the relay-server at `relay_addr`: its round trip time, our public address, and how our NAT maps.
+///
+/// Another few sockets show how the NAT allocates ports, and the first two of them whether it hairpins.
+///
+/// # Errors
+///
+/// Returns `NudgeError::DiagnosisNotSupported` if the relay-server does not know the probes,
+/// or `NudgeError::Io` if the sockets fail
+pub fn diagnose_family(relay_addr: SocketAddr) -> Result<FamilyReport> {
+    let socket = bind_unspecified(relay_addr)?;
+    let local_port = socket.local_addr()?.port();
+
+    let mut rtts = Vec::new();
+    let mut first = None;
+    for _ in 0..RTT_PROBES {
+        if let Some((rtt, observation)) = probe(&socket, relay_addr, 1)? {
+            rtts.push(rtt);
+            first.get_or_insert(observation);
+        }
+    }
+    let Some(first) = first else {
+        debug!("({}) Relay-server does not answer", relay_addr);
+        return Ok(FamilyReport {
+            relay_addr,
+            rtts,
+            local_port,
+            public: None,
+            mapping: Mapping::Unknown,
+            allocation: None,
+            hairpin: None,
+            relaying: false,
+        });
+    };
+    let public = first.observed;
+    let nat = public.port() != local_port || !is_interface_ip(&public.ip());
+    debug!("Public address: {} (local port {}, NAT: {})", public, local_port, nat);
+
+    let second = match first.probe_port {
+        Some(port) => probe(&socket, SocketAddr::new(relay_addr.ip(), port), PROBE_ATTEMPTS)?
+            .map(|(_, observation)| observation.observed),
+        None => None,
+    };
+    debug!("Public address towards the second port: {:?}", second);
+
+    // every new socket gets a new mapping
+    let mut probed = Vec::new();
+    for _ in 0..PROBE_SOCKETS {
+        let probe_socket = bind_unspecified(relay_addr)?;
+        if let Some((_, observation)) = probe(&probe_socket, relay_addr, PROBE_ATTEMPTS)? {
+            probed.push((probe_socket, observation.observed));
+        }
+    }
+    let ports = probed.iter()
+        .map(|(probe_socket, public)| Ok((probe_socket.local_addr()?.port(), public.port())))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    debug!("Ports of the probe sockets: {:?}", ports);
+
+    let hairpin = match &probed[..] {
+        [(a, a_public), (b, b_public), ..] if nat => Some(hairpins(a, *a_public, b, *b_public)?),
+        _ => None,
+    };
+    Ok(FamilyReport {
+        relay_addr,
+        rtts,
+        local_port,
+        public: Some(public),
+        mapping: mapping(nat, public, second),
+        allocation: if nat { classify(&ports) } else { None },
+        hairpin,
+        relaying: first.relaying,
+    })
+}
+
+/// A socket of the family of `addr` on any free port
+fn bind_unspecified(addr: SocketAddr) -> Result<UdpSocket> {
+    let ip = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    Ok(bind_to(SocketAddr::new(ip, 0))?)
+}
+
+/// Sends a probe to `addr` until it is answered, at most `attempts` times.
+///
+/// # Returns
+///
+/// `Result<Option<(Duration, X2DObservationMessage)>>` - The round trip time and the answer, `None` without an answer.
+///
+/// # Errors
+///
+/// Returns `NudgeError::DiagnosisNotSupported` if the relay-server does not know the probes,
+/// or `NudgeError::Io` if the socket fails
+fn probe(socket: &UdpSocket, addr: SocketAddr, attempts: usize) -> Result<Option<(Duration, X2DObservationMessage)>> {
+    let mut buffer = [0u8; 1024];
+    for _ in 0..attempts {
+        let nonce: u64 = random();
+        let sent_at = Instant::now();
+        serialize_and_send_to(socket, addr, "D2X_PROBE", &D2XProbeMessage { nonce })?;
+        while let Some(remaining) = PROBE_TIMEOUT.checked_sub(sent_at.elapsed()).filter(|remaining| !remaining.is_zero()) {
+            socket.set_read_timeout(Some(remaining))?;
+            let Ok((len, from)) = socket.recv_from(&mut buffer) else {
+                break;
+            };
+            if canonical(from) != canonical(addr) {
+                continue;
+            }
+            match parse_and_expect::<X2DObservationMessage>(&buffer[..len], "X2D_OBS") {
+                Ok(observation) if observation.nonce == nonce => {
+                    let mut observation = observation;
+                    observation.observed = canonical(observation.observed);
+                    return Ok(Some((sent_at.elapsed(), observation)));
+                }
+                Err(NudgeError::ServerError(_)) => return Err(NudgeError::DiagnosisNotSupported),
+                _ => {}
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the NAT forwards packets from one of its mappings to another one (hairpinning).
+///
+/// Both sockets send to the public address of the other, so the NAT lets the packets in even if it filters.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the sockets fail
+fn hairpins(a: &UdpSocket, a_public: SocketAddr, b: &UdpSocket, b_public: SocketAddr) -> Result<bool> {
+    a.set_nonblocking(true)?;
+    b.set_nonblocking(true)?;
+    let mut buffer = [0u8; 64];
+    for _ in 0..HAIRPIN_PACKETS {
+        let _ = a.send_to(HAIRPIN_TAG, b_public);
+        let _ = b.send_to(HAIRPIN_TAG, a_public);
+        thread::sleep(HAIRPIN_INTERVAL);
+        for socket in [a, b] {
+            while let Ok((len, _)) = socket.recv_from(&mut buffer) {
+                if &buffer[..len] == HAIRPIN_TAG {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Which ways to connect to a peer work from this machine, judged from the diagnosis.
+pub fn verdict(diagnosis: &Diagnosis) -> Vec<Strategy> {
+    let coordination = diagnosis.coordination();
+    let has_ipv6 = !diagnosis.global_ipv6.is_empty();
+
+    let punching = match coordination.map(|report| (report.mapping, report.allocation)) {
+        None => Strategy::new("Hole punching", Outlook::Fails, "the relay-server, which coordinates the punching, cannot be reached"),
+        Some((Mapping::NoNat, _)) => Strategy::new("Hole punching", Outlook::Works, "the relay-server sees the address of this machine, no NAT is in the way"),
+        Some((Mapping::EndpointIndependent, _)) => Strategy::new("Hole punching", Outlook::Works, "the NAT keeps the same public port for every destination"),
+        Some((Mapping::EndpointDependent, Some(PortAllocation::Random))) => Strategy::new(
+            "Hole punching",
+            Outlook::Depends,
+            "the NAT picks a random public port for every destination, only peers behind a predictable NAT can be reached",
+        ),
+        Some((Mapping::EndpointDependent, _)) => Strategy::new(
+            "Hole punching",
+            Outlook::Depends,
+            "the NAT picks another public port for every destination, the peer has to predict it from their sequence",
+        ),
+        Some((Mapping::Unknown, _)) => Strategy::new("Hole punching", Outlook::Unknown, "the second port of the relay-server does not answer, the mapping of the NAT is unknown"),
+    };
+
+    let ipv6 = if has_ipv6 {
+        Strategy::new("IPv6", Outlook::Depends, "peers with IPv6 reach this machine without NAT traversal, unless a firewall is in the way")
+    } else {
+        Strategy::new("IPv6", Outlook::Fails, "this machine has no global IPv6 address")
+    };
+
+    let local = match coordination.and_then(|report| report.hairpin) {
+        Some(true) => "peers in the same network connect through their local addresses, the router also hairpins",
+        Some(false) => "peers in the same network connect through their local addresses, the router does not hairpin",
+        None => "peers in the same network connect through their local addresses",
+    };
+    let local = Strategy::new("Local network", Outlook::Works, local);
+
+    let relay = match coordination {
+        None => Strategy::new("Relaying", Outlook::Fails, "the relay-server cannot be reached"),
+        Some(report) if report.relaying => Strategy::new("Relaying", Outlook::Works, "the relay-server forwards the data if hole punching fails"),
+        Some(_) => Strategy::new("Relaying", Outlook::Fails, "the relay-server does not forward data, it runs without --allow-relaying"),
+    };
+
+    let listen = match coordination.map(|report| report.mapping) {
+        None => Strategy::new("Direct (--listen)", Outlook::Unknown, "without the relay-server, it is unknown whether a NAT is in the way"),
+        Some(Mapping::NoNat) => Strategy::new("Direct (--listen)", Outlook::Works, "receivers can connect to this machine, unless a firewall blocks the port"),
+        Some(_) if has_ipv6 => Strategy::new("Direct (--listen)", Outlook::Depends, "behind the NAT, only receivers with IPv6 or in the same network can connect"),
+        Some(_) => Strategy::new("Direct (--listen)", Outlook::Depends, "behind the NAT, only receivers in the same network can connect"),
+    };
+
+    vec![punching, ipv6, local, relay, listen]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(mapping: Mapping, allocation: Option<PortAllocation>, relaying: bool) -> FamilyReport {
+        FamilyReport {
+            relay_addr: "192.0.2.1:4000".parse().unwrap(),
+            rtts: vec![Duration::from_millis(10)],
+            local_port: 50000,
+            public: Some("198.51.100.7:40000".parse().unwrap()),
+            mapping,
+            allocation,
+            hairpin: Some(true),
+            relaying,
+        }
+    }
+
+    fn outlook(diagnosis: &Diagnosis, name: &str) -> Outlook {
+        verdict(diagnosis).into_iter().find(|strategy| strategy.name == name).unwrap().outlook
+    }
+
+    #[test]
+    fn test_mapping() {
+        let first = "198.51.100.7:40000".parse().unwrap();
+        assert_eq!(mapping(false, first, None), Mapping::NoNat);
+        assert_eq!(mapping(true, first, None), Mapping::Unknown);
+        assert_eq!(mapping(true, first, Some(first)), Mapping::EndpointIndependent);
+        assert_eq!(mapping(true, first, Some("198.51.100.7:40001".parse().unwrap())), Mapping::EndpointDependent);
+    }
+
+    #[test]
+    fn test_verdict() {
+        let mut diagnosis = Diagnosis {
+            ipv4: Some(report(Mapping::EndpointIndependent, Some(PortAllocation::Random), false)),
+            ipv6: None,
+            global_ipv6: Vec::new(),
+        };
+        assert_eq!(outlook(&diagnosis, "Hole punching"), Outlook::Works);
+        assert_eq!(outlook(&diagnosis, "IPv6"), Outlook::Fails);
+        assert_eq!(outlook(&diagnosis, "Relaying"), Outlook::Fails);
+        assert_eq!(outlook(&diagnosis, "Direct (--listen)"), Outlook::Depends);
+
+        diagnosis.ipv4 = Some(report(Mapping::EndpointDependent, Some(PortAllocation::Random), true));
+        diagnosis.global_ipv6 = vec!["2a01:4f8::1".parse().unwrap()];
+        assert_eq!(outlook(&diagnosis, "Hole punching"), Outlook::Depends);
+        assert_eq!(outlook(&diagnosis, "IPv6"), Outlook::Depends);
+        assert_eq!(outlook(&diagnosis, "Relaying"), Outlook::Works);
+
+        diagnosis.ipv4 = Some(report(Mapping::NoNat, None, false));
+        assert_eq!(outlook(&diagnosis, "Hole punching"), Outlook::Works);
+        assert_eq!(outlook(&diagnosis, "Direct (--listen)"), Outlook::Works);
+    }
+
+    #[test]
+    fn test_verdict_without_relay_server() {
+        let mut unreachable = report(Mapping::Unknown, None, false);
+        unreachable.public = None;
+        let diagnosis = Diagnosis { ipv4: Some(unreachable), ipv6: None, global_ipv6: Vec::new() };
+        assert_eq!(outlook(&diagnosis, "Hole punching"), Outlook::Fails);
+        assert_eq!(outlook(&diagnosis, "Relaying"), Outlook::Fails);
+        assert_eq!(outlook(&diagnosis, "Direct (--listen)"), Outlook::Unknown);
+        assert_eq!(outlook(&diagnosis, "Local network"), Outlook::Works);
+    }
+
+    #[test]
+    fn test_probe_without_answer() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(probe(&socket, silent.local_addr().unwrap(), 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_probe_of_an_outdated_relay_server() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let answering = thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            let (_, addr) = relay.recv_from(&mut buffer).unwrap();
+            relay.send_to(b"ERROR Unknown command\n", addr).unwrap();
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(matches!(probe(&socket, relay_addr, 1), Err(NudgeError::DiagnosisNotSupported)));
+        answering.join().unwrap();
+    }
+
+    #[test]
+    fn test_hairpins_between_reachable_sockets() {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(hairpins(&a, a.local_addr().unwrap(), &b, b.local_addr().unwrap()).unwrap());
+    }
+}
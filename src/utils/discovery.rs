@@ -0,0 +1,512 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::error::{NudgeError, Result};
+use crate::models::{
+    FileInfo, R2SHelloMessage, R2SRequestFileInfoMessage, S2RAnnounceMessage, S2RChallengeMessage,
+    X2SSenderConnectToReceiverMessage,
+};
+use crate::utils::passphrase::Passphrase;
+use crate::utils::serialize::{parse_and_expect, serialize_and_send_to};
+use crate::utils::socket::canonical;
+
+/// Multicast group the senders announce their sessions on, within the organization-local scope
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 77, 11);
+
+/// Port the receivers listen on for announcements
+pub const DISCOVERY_PORT: u16 = 4712;
+
+/// Announcements never leave the local network
+const MULTICAST_TTL: u32 = 1;
+
+/// Interval of the announcements, and of the file info requests until the sender answers
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the receiver listens for an announcement of the passphrase
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the receiver asks the sender for the file info
+const REQUEST_ATTEMPTS: usize = 5;
+
+/// Key derivation context of the announced session id
+const SESSION_CONTEXT: &str = "nudge 2024-11 local discovery session";
+
+/// Key derivation context of the receiver's proof, so an announcement cannot be replayed as a proof
+const PROOF_CONTEXT: &str = "nudge 2024-11 local discovery proof";
+
+/// Key derivation context of the sender's proof to a receiver which connects directly
+const SENDER_PROOF_CONTEXT: &str = "nudge 2024-11 direct sender proof";
+
+/// Receivers which got a challenge but did not answer it yet, the oldest ones are forgotten beyond
+const MAX_CHALLENGES: usize = 64;
+
+/// The hash of the passphrase a session is announced as.
+pub fn session_id(passphrase: &Passphrase, salt: &str) -> String {
+    derive(SESSION_CONTEXT, passphrase, salt)
+}
+
+/// The hash of the passphrase a receiver proves it knows the passphrase with.
+pub fn request_proof(passphrase: &Passphrase, salt: &str) -> String {
+    derive(PROOF_CONTEXT, passphrase, salt)
+}
+
+/// The hash of the passphrase a sender proves it knows the passphrase with, salted by the receiver.
+pub fn sender_proof(passphrase: &Passphrase, salt: &str) -> String {
+    derive(SENDER_PROOF_CONTEXT, passphrase, salt)
+}
+
+fn derive(context: &str, passphrase: &Passphrase, salt: &str) -> String {
+    let mut hasher = blake3::Hasher::new_derive_key(context);
+    hasher.update(salt.as_bytes());
+    hasher.update(passphrase.0.as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// A fresh salt for every session, so the same passphrase is never announced as the same hash twice
+fn random_salt() -> String {
+    let salt: [u8; 16] = thread_rng().gen();
+    salt.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The address with the IP the packets actually came from, the peers only know the ports of their sockets.
+fn with_ip(addr: SocketAddr, ip: IpAddr) -> SocketAddr {
+    SocketAddr::new(ip, addr.port())
+}
+
+/// Announces the session on the local network until a receiver who knows the passphrase asks to connect.
+///
+/// Only a salted hash of the passphrase is announced. A receiver gets the file info once it proves
+/// it knows the passphrase, and only such a receiver can ask the sender to connect.
+///
+/// # Arguments
+///
+/// * `socket` - The (unconnected) socket of the first stream, the receiver connects to it.
+/// * `passphrase` - The passphrase of the transfer.
+/// * `file_info` - What the receiver learns about the file, the ports of its addresses are those of our sockets.
+///
+/// # Returns
+///
+/// `Result<X2SSenderConnectToReceiverMessage>` - The addresses of the receiver, and what it asked for.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the socket fails
+pub fn announce(
+    socket: &UdpSocket,
+    passphrase: &Passphrase<'static>,
+    file_info: &FileInfo,
+) -> Result<X2SSenderConnectToReceiverMessage> {
+    let salt = random_salt();
+    let announcement = S2RAnnounceMessage { session: session_id(passphrase, &salt), salt: salt.clone() };
+    let proof = request_proof(passphrase, &salt);
+    let group = SocketAddr::new(IpAddr::V4(DISCOVERY_GROUP), DISCOVERY_PORT);
+
+    set_multicast_ttl(socket)?;
+    socket.set_read_timeout(Some(ANNOUNCE_INTERVAL))?;
+    let mut announced_at: Option<Instant> = None;
+    let mut receivers: Vec<SocketAddr> = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        if announced_at.is_none_or(|announced_at| announced_at.elapsed() >= ANNOUNCE_INTERVAL) {
+            // an interface may come up later, so a failed announcement is retried
+            if let Err(err) = serialize_and_send_to(socket, group, "S2R_ANN", &announcement) {
+                warn!("Cannot announce the session: {}", err);
+            }
+            announced_at = Some(Instant::now());
+        }
+        let Ok((len, addr)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        let addr = canonical(addr);
+        let message = &buffer[..len];
+        match message.split(|byte| *byte == b' ').next().unwrap_or_default() {
+            b"R2S_LFI" => {
+                let Ok(request) = parse_and_expect::<R2SRequestFileInfoMessage>(message, "R2S_LFI") else {
+                    continue;
+                };
+                if request.proof != proof {
+                    debug!("({}) Ignoring a request with the wrong passphrase", addr);
+                    continue;
+                }
+                debug!("({}) Receiver knows the passphrase, sending the file info", addr);
+                if !receivers.contains(&addr) {
+                    receivers.push(addr);
+                }
+                serialize_and_send_to(socket, addr, "S2R_AFI", file_info)?;
+            }
+            b"R2S_SCON" if receivers.contains(&addr) => {
+                let Ok(conn_req) = parse_and_expect(message, "R2S_SCON") else {
+                    continue;
+                };
+                return Ok(from_receiver(conn_req, addr));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Keeps the announcements within the local network, with the option of the socket's family.
+///
+/// The announcements of a dual-stack socket are IPv4 packets, which follow the IPv4 option where
+/// the platform accepts it on an IPv6 socket (Linux); the IPv6 hop limit is set in any case.
+fn set_multicast_ttl(socket: &UdpSocket) -> Result<()> {
+    let socket_ref = SockRef::from(socket);
+    if socket.local_addr()?.is_ipv4() {
+        socket_ref.set_multicast_ttl_v4(MULTICAST_TTL)?;
+        return Ok(());
+    }
+    socket_ref.set_multicast_hops_v6(MULTICAST_TTL)?;
+    if let Err(err) = socket_ref.set_multicast_ttl_v4(MULTICAST_TTL) {
+        debug!("Cannot limit the TTL of IPv4 announcements: {}", err);
+    }
+    Ok(())
+}
+
+/// Waits for the announcement of the passphrase on the local network and gets the file info from its sender.
+///
+/// # Arguments
+///
+/// * `socket` - The (unconnected) socket of the first stream, the sender answers to it.
+/// * `passphrase` - The passphrase of the transfer.
+///
+/// # Returns
+///
+/// `Result<FileInfo>` - The file info, with the addresses the sender was heard from.
+///
+/// # Errors
+///
+/// Returns `NudgeError::LocalSessionNotFound` if no sender announces the passphrase or answers,
+/// or `NudgeError::Io` if joining the multicast group fails
+pub fn find_session(socket: &UdpSocket, passphrase: &Passphrase<'static>) -> Result<FileInfo> {
+    let (sender_addr, salt) = wait_for_announcement(passphrase)?;
+    debug!("({}) Sender announces the passphrase", sender_addr);
+
+    let request = R2SRequestFileInfoMessage { proof: request_proof(passphrase, &salt) };
+    let file_info = ask(socket, sender_addr, "R2S_LFI", &request, "S2R_AFI")?
+        .ok_or(NudgeError::LocalSessionNotFound)?;
+    Ok(from_sender(file_info, sender_addr))
+}
+
+/// Listens on the multicast group until a sender announces the passphrase.
+///
+/// # Returns
+///
+/// `Result<(SocketAddr, String)>` - The address of the sender and the salt of its session.
+fn wait_for_announcement(passphrase: &Passphrase<'static>) -> Result<(SocketAddr, String)> {
+    let listener = bind_discovery_port()?;
+    listener.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    listener.set_read_timeout(Some(ANNOUNCE_INTERVAL))?;
+
+    let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+    let mut buffer = [0u8; 1024];
+    while Instant::now() < deadline {
+        let Ok((len, addr)) = listener.recv_from(&mut buffer) else {
+            continue;
+        };
+        let Ok(announcement) = parse_and_expect::<S2RAnnounceMessage>(&buffer[..len], "S2R_ANN") else {
+            continue;
+        };
+        if announcement.session == session_id(passphrase, &announcement.salt) {
+            return Ok((canonical(addr), announcement.salt));
+        }
+    }
+    Err(NudgeError::LocalSessionNotFound)
+}
+
+/// Binds the port the announcements are sent to, which other receivers on this machine may use at the same time.
+fn bind_discovery_port() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DISCOVERY_PORT).into())?;
+    Ok(socket.into())
+}
+
+/// Waits on `socket` for receivers which connect directly, until one who knows the passphrase asks to connect.
+///
+/// Every receiver gets its own challenge. Only a receiver which answers it with a proof of the passphrase
+/// gets the file info, together with the sender's proof. Nothing derived from the passphrase is sent before.
+///
+/// # Arguments
+///
+/// * `socket` - The (unconnected) socket of the first stream, bound to the address to listen on.
+/// * `passphrase` - The passphrase of the transfer.
+/// * `file_info` - What the receiver learns about the file, the ports of its addresses are those of our sockets.
+///
+/// # Returns
+///
+/// `Result<X2SSenderConnectToReceiverMessage>` - The addresses of the receiver, and what it asked for.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the socket fails
+pub fn listen(
+    socket: &UdpSocket,
+    passphrase: &Passphrase<'static>,
+    file_info: &FileInfo,
+) -> Result<X2SSenderConnectToReceiverMessage> {
+    socket.set_read_timeout(None)?;
+    // the receiver, the salt it has to prove with, and its own salt
+    let mut challenges: Vec<(SocketAddr, String, String)> = Vec::new();
+    // the receivers which proved, and the file info they got
+    let mut receivers: Vec<(SocketAddr, FileInfo)> = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        let Ok((len, addr)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        let addr = canonical(addr);
+        let message = &buffer[..len];
+        match message.split(|byte| *byte == b' ').next().unwrap_or_default() {
+            b"R2S_HELLO" => {
+                let Ok(hello) = parse_and_expect::<R2SHelloMessage>(message, "R2S_HELLO") else {
+                    continue;
+                };
+                // a repeated hello gets the same challenge, the receiver may have answered it already
+                let salt = match challenges.iter().find(|(challenged, _, receiver_salt)| *challenged == addr && *receiver_salt == hello.salt) {
+                    Some((_, salt, _)) => salt.clone(),
+                    None => {
+                        challenges.retain(|(challenged, _, _)| *challenged != addr);
+                        if challenges.len() >= MAX_CHALLENGES {
+                            challenges.remove(0);
+                        }
+                        let salt = random_salt();
+                        challenges.push((addr, salt.clone(), hello.salt));
+                        salt
+                    }
+                };
+                debug!("({}) Receiver connects directly, sending a challenge", addr);
+                serialize_and_send_to(socket, addr, "S2R_CHAL", &S2RChallengeMessage { salt })?;
+            }
+            b"R2S_LFI" => {
+                let Ok(request) = parse_and_expect::<R2SRequestFileInfoMessage>(message, "R2S_LFI") else {
+                    continue;
+                };
+                // the file info got lost
+                if let Some((_, file_info)) = receivers.iter().find(|(receiver, _)| *receiver == addr) {
+                    serialize_and_send_to(socket, addr, "S2R_AFI", file_info)?;
+                    continue;
+                }
+                // every challenge can only be answered once
+                let Some(position) = challenges.iter().position(|(challenged, _, _)| *challenged == addr) else {
+                    continue;
+                };
+                let (_, salt, receiver_salt) = challenges.remove(position);
+                if request.proof != request_proof(passphrase, &salt) {
+                    debug!("({}) Ignoring a request with the wrong passphrase", addr);
+                    continue;
+                }
+                debug!("({}) Receiver knows the passphrase, sending the file info", addr);
+                let file_info = FileInfo { sender_proof: Some(sender_proof(passphrase, &receiver_salt)), ..file_info.clone() };
+                serialize_and_send_to(socket, addr, "S2R_AFI", &file_info)?;
+                receivers.push((addr, file_info));
+            }
+            b"R2S_SCON" if receivers.iter().any(|(receiver, _)| *receiver == addr) => {
+                let Ok(conn_req) = parse_and_expect(message, "R2S_SCON") else {
+                    continue;
+                };
+                return Ok(from_receiver(conn_req, addr));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Gets the file info from a sender which listens on `sender_addr`, proving that we know the passphrase.
+///
+/// # Arguments
+///
+/// * `socket` - The (unconnected) socket of the first stream, the sender answers to it.
+/// * `passphrase` - The passphrase of the transfer.
+/// * `sender_addr` - The address the sender listens on.
+///
+/// # Returns
+///
+/// `Result<FileInfo>` - The file info, with the address of the sender.
+///
+/// # Errors
+///
+/// Returns `NudgeError::DirectPeerUnreachable` if the sender does not answer or does not accept our proof,
+/// or `NudgeError::PeerNotAuthenticated` if the sender cannot prove that it knows the passphrase
+pub fn connect_to_sender(socket: &UdpSocket, passphrase: &Passphrase<'static>, sender_addr: SocketAddr) -> Result<FileInfo> {
+    let salt = random_salt();
+    let challenge: S2RChallengeMessage = ask(socket, sender_addr, "R2S_HELLO", &R2SHelloMessage { salt: salt.clone() }, "S2R_CHAL")?
+        .ok_or(NudgeError::DirectPeerUnreachable(sender_addr))?;
+
+    let request = R2SRequestFileInfoMessage { proof: request_proof(passphrase, &challenge.salt) };
+    let file_info: FileInfo = ask(socket, sender_addr, "R2S_LFI", &request, "S2R_AFI")?
+        .ok_or(NudgeError::DirectPeerUnreachable(sender_addr))?;
+    if file_info.sender_proof.as_deref() != Some(sender_proof(passphrase, &salt).as_str()) {
+        return Err(NudgeError::PeerNotAuthenticated(sender_addr));
+    }
+    Ok(from_sender(file_info, sender_addr))
+}
+
+/// Sends `message` to `addr` until it answers with a message of the `expected` prefix.
+///
+/// # Returns
+///
+/// `Result<Option<R>>` - The answer, `None` if there was none after `REQUEST_ATTEMPTS` tries.
+fn ask<T: Serialize, R: DeserializeOwned>(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    prefix: &str,
+    message: &T,
+    expected: &str,
+) -> Result<Option<R>> {
+    socket.set_read_timeout(Some(ANNOUNCE_INTERVAL))?;
+    let mut buffer = [0u8; 4096];
+    for _ in 0..REQUEST_ATTEMPTS {
+        serialize_and_send_to(socket, addr, prefix, message)?;
+        let deadline = Instant::now() + ANNOUNCE_INTERVAL;
+        while Instant::now() < deadline {
+            let Ok((len, from)) = socket.recv_from(&mut buffer) else {
+                break;
+            };
+            let answer = &buffer[..len];
+            if canonical(from) == addr && answer.starts_with(expected.as_bytes()) && answer.get(expected.len()) == Some(&b' ') {
+                return Ok(Some(parse_and_expect(answer, expected)?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The request of a receiver, with the IP its packets came from.
+fn from_receiver(mut conn_req: X2SSenderConnectToReceiverMessage, addr: SocketAddr) -> X2SSenderConnectToReceiverMessage {
+    conn_req.receiver_addr = addr;
+    for stream_addr in &mut conn_req.receiver_stream_addrs {
+        *stream_addr = with_ip(*stream_addr, addr.ip());
+    }
+    conn_req
+}
+
+/// The file info of a sender, with the IP its packets came from.
+fn from_sender(mut file_info: FileInfo, addr: SocketAddr) -> FileInfo {
+    file_info.sender_addr = addr;
+    for stream_addr in &mut file_info.sender_stream_addrs {
+        *stream_addr = with_ip(*stream_addr, addr.ip());
+    }
+    file_info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::LocalCandidates;
+    use crate::utils::AnonymousString;
+    use crate::utils::socket::bind_dual_stack;
+
+    #[test]
+    fn test_session_id_hides_the_passphrase() {
+        let passphrase = Passphrase::from("apple-banana-cherry");
+        let salt = random_salt();
+        assert_eq!(salt.len(), 32);
+        assert_ne!(salt, random_salt());
+
+        let session = session_id(&passphrase, &salt);
+        assert_eq!(session, session_id(&Passphrase::from("apple-banana-cherry"), &salt));
+        assert!(!session.contains("apple"));
+        // another salt, passphrase or purpose gives another hash
+        assert_ne!(session, session_id(&passphrase, &random_salt()));
+        assert_ne!(session, session_id(&Passphrase::from("apple-banana-plum"), &salt));
+        assert_ne!(session, request_proof(&passphrase, &salt));
+        assert_ne!(request_proof(&passphrase, &salt), sender_proof(&passphrase, &salt));
+    }
+
+    #[test]
+    fn test_discovery_port_can_be_shared() {
+        let first = bind_discovery_port().unwrap();
+        let second = bind_discovery_port().unwrap();
+        assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+    }
+
+    fn file_info() -> FileInfo {
+        FileInfo {
+            file_size: 42,
+            file_name: "file.txt".to_string(),
+            hashed: true,
+            fec: false,
+            sender_host: AnonymousString(None),
+            created_at: 0,
+            sender_addr: "0.0.0.0:0".parse().unwrap(),
+            streams: 2,
+            sender_stream_addrs: vec!["0.0.0.0:4000".parse().unwrap()],
+            receiver_stream_addrs: Vec::new(),
+            sender_local: LocalCandidates::default(),
+            receiver_local: LocalCandidates::default(),
+            same_network: true,
+            sender_proof: None,
+        }
+    }
+
+    fn conn_req() -> X2SSenderConnectToReceiverMessage {
+        X2SSenderConnectToReceiverMessage {
+            receiver_addr: "0.0.0.0:0".parse().unwrap(),
+            receiver_host: AnonymousString(None),
+            receiver_stream_addrs: vec!["0.0.0.0:5000".parse().unwrap()],
+            limit: None,
+            basis: false,
+            receiver_local: LocalCandidates::default(),
+            same_network: true,
+        }
+    }
+
+    #[test]
+    fn test_connect_to_sender_proves_the_passphrase_both_ways() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender_addr = sender.local_addr().unwrap();
+        let listening = std::thread::spawn(move || {
+            listen(&sender, &Passphrase::from("apple-banana-cherry"), &file_info()).unwrap()
+        });
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let received = connect_to_sender(&receiver, &Passphrase::from("apple-banana-cherry"), sender_addr).unwrap();
+        assert_eq!(received.file_size, 42);
+        assert_eq!(received.sender_addr, sender_addr);
+        assert_eq!(received.sender_stream_addrs, vec!["127.0.0.1:4000".parse().unwrap()]);
+
+        serialize_and_send_to(&receiver, sender_addr, "R2S_SCON", &conn_req()).unwrap();
+        let conn_req = listening.join().unwrap();
+        assert_eq!(conn_req.receiver_addr, receiver.local_addr().unwrap());
+        assert_eq!(conn_req.receiver_stream_addrs, vec!["127.0.0.1:5000".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_announce_and_find_session_over_multicast() {
+        let sender = bind_dual_stack().unwrap();
+        let sender_port = sender.local_addr().unwrap().port();
+        let announcing = std::thread::spawn(move || {
+            announce(&sender, &Passphrase::from("apple-banana-cherry"), &file_info()).unwrap()
+        });
+
+        // a receiver with another passphrase hears the same announcements, but never matches them
+        let guessing = std::thread::spawn(|| {
+            let receiver = bind_dual_stack().unwrap();
+            find_session(&receiver, &Passphrase::from("apple-banana-plum"))
+        });
+
+        let receiver = bind_dual_stack().unwrap();
+        let received = find_session(&receiver, &Passphrase::from("apple-banana-cherry")).unwrap();
+        assert_eq!(received.file_size, 42);
+        assert_eq!(received.sender_addr.port(), sender_port);
+        assert_eq!(received.sender_stream_addrs, vec![with_ip("0.0.0.0:4000".parse().unwrap(), received.sender_addr.ip())]);
+        assert!(matches!(guessing.join().unwrap(), Err(NudgeError::LocalSessionNotFound)));
+
+        serialize_and_send_to(&receiver, received.sender_addr, "R2S_SCON", &conn_req()).unwrap();
+        let conn_req = announcing.join().unwrap();
+        assert_eq!(conn_req.receiver_addr.port(), receiver.local_addr().unwrap().port());
+    }
+
+    #[test]
+    fn test_with_ip() {
+        let addr = "0.0.0.0:4000".parse().unwrap();
+        assert_eq!(with_ip(addr, "192.168.1.2".parse().unwrap()), "192.168.1.2:4000".parse().unwrap());
+    }
+}
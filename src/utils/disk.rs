@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::error::Result;
+
+/// Space available to the current user on the file system `path` will be created on.
+///
+/// Directories which do not exist yet are on the file system of their nearest existing ancestor.
+///
+/// # Returns
+///
+/// `Result<Option<u64>>` - The available bytes, or `None` if the platform cannot tell.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the file system cannot be queried
+pub fn available_space(path: &Path) -> Result<Option<u64>> {
+    let absolute = std::path::absolute(path)?;
+    let existing = absolute.ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("/"));
+    statvfs_available(existing)
+}
+
+#[cfg(unix)]
+fn statvfs_available(path: &Path) -> Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::from)?;
+    // SAFETY: `statvfs` is a plain C struct for which all zeroes is a valid value
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid NUL-terminated string and `stat` an out-parameter owned by us, both outlive the call
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn statvfs_available(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Reserves `len` bytes on the disk for `file` and sets its length.
+///
+/// Where the file system cannot reserve space, only the length is set and the space is taken while writing.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the space cannot be reserved, e.g. because the disk is full
+pub fn preallocate(file: &File, len: u64) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: the file descriptor stays open for the duration of the call, since `file` is borrowed
+        if len > 0 && unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err.into());
+            }
+            debug!("The file system cannot preallocate, the space is taken while writing");
+        }
+    }
+    // also shrinks a longer file left by an earlier transfer
+    file.set_len(len)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_available_space() {
+        let dir = std::env::temp_dir();
+        #[cfg(unix)]
+        assert!(available_space(&dir).unwrap().unwrap() > 0);
+        // a path which does not exist yet is on the file system of its parent
+        assert_eq!(
+            available_space(&dir.join("nudge-missing/dir")).unwrap().is_some(),
+            available_space(&dir).unwrap().is_some()
+        );
+    }
+
+    #[test]
+    fn test_preallocate_sets_length() {
+        let path = std::env::temp_dir().join(format!("nudge-disk-{}", std::process::id()));
+        let file = File::options().create(true).truncate(true).read(true).write(true).open(&path).unwrap();
+        preallocate(&file, 100_000).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 100_000);
+        preallocate(&file, 10).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 10);
+        std::fs::remove_file(path).unwrap();
+    }
+}
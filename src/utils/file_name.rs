@@ -0,0 +1,161 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{NudgeError, Result};
+use crate::utils::part_file::PART_EXTENSION;
+
+/// Longest file name most file systems accept (255 bytes), less the extension of the temporary file
+const MAX_NAME_LEN: usize = 255 - ".".len() - PART_EXTENSION.len();
+
+/// Characters which are not allowed in file names on Windows, they are replaced on every platform
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows reserves, with any extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns the file name supplied by the sender into a single, harmless path component.
+///
+/// Only the last component of the name is kept (split at `/` and `\`), control characters are dropped,
+/// characters reserved on Windows are replaced with `_`, trailing dots and spaces are trimmed,
+/// reserved device names get a `_` prefix, and the name is cut so that `.nudge-part` still fits into 255 bytes.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidFileName` if nothing usable is left, e.g. for `..` or an empty name
+pub fn sanitize_file_name(name: &str) -> Result<String> {
+    let last = name.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let mut sanitized: String = last.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if RESERVED_CHARS.contains(&c) { '_' } else { c })
+        .collect();
+    sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+    let sanitized = sanitized.trim_start();
+
+    if sanitized.is_empty() {
+        return Err(NudgeError::InvalidFileName(name.to_string()));
+    }
+
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    let mut sanitized = if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end())) {
+        format!("_{}", sanitized)
+    } else {
+        sanitized.to_string()
+    };
+
+    if sanitized.len() > MAX_NAME_LEN {
+        let mut end = MAX_NAME_LEN;
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+        // the cut may end the name with a dot or space again
+        sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+        if sanitized.is_empty() {
+            return Err(NudgeError::InvalidFileName(name.to_string()));
+        }
+    }
+    Ok(sanitized)
+}
+
+/// Resolves the output path inside `out_dir`, which must not be left.
+///
+/// # Arguments
+///
+/// * `out_dir` - The directory the file has to end up in.
+/// * `relative` - The path within the directory, either a sanitized name or the `-o` option.
+///
+/// # Errors
+///
+/// Returns `NudgeError::OutputOutsideDir` if `relative` is absolute or contains `..`
+pub fn path_in_dir(out_dir: &Path, relative: &Path) -> Result<PathBuf> {
+    let stays_inside = relative.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        && relative.components().any(|component| matches!(component, Component::Normal(_)));
+    if !stays_inside {
+        return Err(NudgeError::OutputOutsideDir(relative.to_path_buf(), out_dir.to_path_buf()));
+    }
+    Ok(out_dir.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_ordinary_names() {
+        assert_eq!(sanitize_file_name("report.pdf").unwrap(), "report.pdf");
+        assert_eq!(sanitize_file_name(".bashrc").unwrap(), ".bashrc");
+        assert_eq!(sanitize_file_name("Übersicht 2024.tar.gz").unwrap(), "Übersicht 2024.tar.gz");
+    }
+
+    #[test]
+    fn test_strips_directories() {
+        assert_eq!(sanitize_file_name("/etc/passwd").unwrap(), "passwd");
+        assert_eq!(sanitize_file_name("..\\..\\Windows\\evil.dll").unwrap(), "evil.dll");
+        assert_eq!(sanitize_file_name("a/b\\c").unwrap(), "c");
+    }
+
+    #[test]
+    fn test_rejects_names_without_anything_left() {
+        for name in ["", "dir/", "..", ".", "../..", " . ", "\n\t", "a\\"] {
+            assert!(matches!(sanitize_file_name(name), Err(NudgeError::InvalidFileName(_))), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_replaces_dangerous_characters() {
+        assert_eq!(sanitize_file_name("evil\x1b[31m.txt").unwrap(), "evil[31m.txt");
+        assert_eq!(sanitize_file_name("a\nb\r.txt").unwrap(), "ab.txt");
+        assert_eq!(sanitize_file_name("what?<*>.txt").unwrap(), "what____.txt");
+        assert_eq!(sanitize_file_name("C:file").unwrap(), "C_file");
+        assert_eq!(sanitize_file_name("trailing. . ").unwrap(), "trailing");
+    }
+
+    #[test]
+    fn test_prefixes_reserved_names() {
+        assert_eq!(sanitize_file_name("CON").unwrap(), "_CON");
+        assert_eq!(sanitize_file_name("nul.txt").unwrap(), "_nul.txt");
+        assert_eq!(sanitize_file_name("com1.tar.gz").unwrap(), "_com1.tar.gz");
+        assert_eq!(sanitize_file_name("console.txt").unwrap(), "console.txt");
+    }
+
+    #[test]
+    fn test_limits_length() {
+        let name = "ä".repeat(200);
+        let sanitized = sanitize_file_name(&name).unwrap();
+        assert!(sanitized.len() <= MAX_NAME_LEN);
+        assert_eq!(sanitized, "ä".repeat(MAX_NAME_LEN / 2));
+
+        let name = format!("{}. .txt", "a".repeat(MAX_NAME_LEN - 2));
+        assert_eq!(sanitize_file_name(&name).unwrap(), "a".repeat(MAX_NAME_LEN - 2));
+        assert!(sanitize_file_name(&format!("{}x", ".".repeat(300))).is_err());
+    }
+
+    #[test]
+    fn test_part_file_fits_for_longest_name() {
+        use crate::utils::part_file::PartFile;
+
+        let name = sanitize_file_name(&"a".repeat(300)).unwrap();
+        assert_eq!(name.len(), MAX_NAME_LEN);
+
+        let out_dir = std::env::temp_dir().join(format!("nudge-longest-name-{}", std::process::id()));
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let part_file = PartFile::create(&path_in_dir(&out_dir, Path::new(&name)).unwrap(), 10, false).unwrap();
+        assert!(part_file.path().exists());
+        drop(part_file);
+        std::fs::remove_dir_all(out_dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_stays_in_dir() {
+        let dir = Path::new("downloads");
+        assert_eq!(path_in_dir(dir, Path::new("file.txt")).unwrap(), PathBuf::from("downloads/file.txt"));
+        assert_eq!(path_in_dir(dir, Path::new("sub/file.txt")).unwrap(), PathBuf::from("downloads/sub/file.txt"));
+        for relative in ["../file.txt", "/tmp/file.txt", "sub/../../file.txt", "", "."] {
+            assert!(matches!(path_in_dir(dir, Path::new(relative)), Err(NudgeError::OutputOutsideDir(_, _))), "{:?}", relative);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::net::{IpAddr, Ipv6Addr, UdpSocket};
+
+/// At most this many private addresses are reported, every one of them costs a punch attempt
+pub const MAX_LOCAL_IPS: usize = 3;
+
+/// At most this many global IPv6 addresses are reported
+pub const MAX_GLOBAL_IPS: usize = 2;
+
+/// Addresses of the local network interfaces, which a peer can reach without NAT traversal.
+///
+/// Private addresses only help a peer in the same network, global IPv6 addresses help every peer with IPv6.
+/// The private ones come first, and the address `socket` sends from comes first among them.
+/// Loopback, link-local and public IPv4 addresses are left out, the relay-server sees the public address anyway.
+pub fn local_ips(socket: &UdpSocket) -> Vec<IpAddr> {
+    let candidates: Vec<IpAddr> = socket.local_addr().ok()
+        .map(|addr| addr.ip().to_canonical())
+        .into_iter()
+        .chain(interface_ips())
+        .collect();
+    let mut private = select(&candidates, is_private);
+    private.truncate(MAX_LOCAL_IPS);
+    let mut global = select(&candidates, is_global_ipv6);
+    global.truncate(MAX_GLOBAL_IPS);
+    private.extend(global);
+    private
+}
+
+/// The addresses the relay-server passes on to the peer, the private ones only if both peers are in the same network.
+///
+/// Both the relay-server and the peer itself use this, so they agree on the addresses the peers try.
+pub fn shared_ips(ips: &[IpAddr], same_network: bool) -> Vec<IpAddr> {
+    ips.iter().copied().filter(|ip| same_network || is_global_ipv6(ip)).collect()
+}
+
+/// The global IPv6 addresses of the local network interfaces, every peer with IPv6 can reach them without NAT traversal
+pub fn global_ipv6_ips() -> Vec<IpAddr> {
+    select(&interface_ips(), is_global_ipv6)
+}
+
+/// Whether `ip` is the address of a local network interface, so no NAT is in between if the relay-server sees it.
+pub fn is_interface_ip(ip: &IpAddr) -> bool {
+    interface_ips().iter().any(|own| own.to_canonical() == ip.to_canonical())
+}
+
+fn select(candidates: &[IpAddr], predicate: fn(&IpAddr) -> bool) -> Vec<IpAddr> {
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for ip in candidates {
+        if predicate(ip) && !ips.contains(ip) {
+            ips.push(*ip);
+        }
+    }
+    ips
+}
+
+/// Private IPv4 addresses and unique local IPv6 addresses (fc00::/7)
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xfe00 == 0xfc00,
+    }
+}
+
+/// IPv6 addresses which are reachable from anywhere, as long as no firewall is in the way (2000::/3)
+fn is_global_ipv6(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => false,
+        IpAddr::V6(ip) => ip.segments()[0] & 0xe000 == 0x2000 && !is_documentation(ip),
+    }
+}
+
+/// 2001:db8::/32, only used in examples
+fn is_documentation(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] == 0x2001 && ip.segments()[1] == 0x0db8
+}
+
+/// The list `getifaddrs` allocated, freed once it goes out of scope, however the walk over it ends
+#[cfg(unix)]
+struct InterfaceList(*mut libc::ifaddrs);
+
+#[cfg(unix)]
+impl Drop for InterfaceList {
+    fn drop(&mut self) {
+        // SAFETY: the list was allocated by a successful `getifaddrs` and is freed nowhere else
+        unsafe { libc::freeifaddrs(self.0) };
+    }
+}
+
+/// The addresses of all interfaces which are up
+#[cfg(unix)]
+fn interface_ips() -> Vec<IpAddr> {
+    let mut ips = Vec::new();
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: `addrs` is an out-parameter owned by us, it is only read if the call succeeded
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        debug!("Cannot list the network interfaces: {}", std::io::Error::last_os_error());
+        return ips;
+    }
+    let list = InterfaceList(addrs);
+
+    let mut current = list.0;
+    // SAFETY: every node of the list stays valid until `list` is dropped, after the loop
+    while let Some(interface) = unsafe { current.as_ref() } {
+        current = interface.ifa_next;
+        let up = interface.ifa_flags & libc::IFF_UP as libc::c_uint != 0;
+        // SAFETY: `ifa_addr` is null or points to an address which lives as long as the list
+        let Some(addr) = (unsafe { interface.ifa_addr.as_ref() }) else {
+            continue;
+        };
+        if !up {
+            continue;
+        }
+        match addr.sa_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the family was checked, so the address is a `sockaddr_in`
+                let addr = unsafe { &*(interface.ifa_addr as *const libc::sockaddr_in) };
+                ips.push(IpAddr::V4(std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))));
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the family was checked, so the address is a `sockaddr_in6`
+                let addr = unsafe { &*(interface.ifa_addr as *const libc::sockaddr_in6) };
+                ips.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    ips
+}
+
+/// Only the address of the socket is known on other platforms
+#[cfg(not(unix))]
+fn interface_ips() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_local_ips() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let ips = local_ips(&socket);
+        assert!(ips.len() <= MAX_LOCAL_IPS + MAX_GLOBAL_IPS);
+        assert!(ips.iter().all(|ip| is_private(ip) || is_global_ipv6(ip)));
+        // the private addresses come first
+        assert!(ips.iter().skip_while(|ip| is_private(ip)).all(is_global_ipv6));
+    }
+
+    #[test]
+    fn test_interface_ips() {
+        assert!(global_ipv6_ips().iter().all(is_global_ipv6));
+        #[cfg(unix)]
+        assert!(is_interface_ip(&"127.0.0.1".parse().unwrap()));
+        assert!(!is_interface_ip(&"192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_is_private() {
+        assert!(is_private(&"192.168.1.20".parse().unwrap()));
+        assert!(is_private(&"10.0.0.1".parse().unwrap()));
+        assert!(is_private(&"fd00::2".parse().unwrap()));
+        assert!(!is_private(&"127.0.0.1".parse().unwrap()));
+        assert!(!is_private(&"8.8.8.8".parse().unwrap()));
+        assert!(!is_private(&"fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_shared_ips() {
+        let ips: Vec<IpAddr> = ["192.168.1.20", "fd00::2", "2a01:4f8::1", "::1", "fe80::1", "2001:db8::1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        assert_eq!(shared_ips(&ips, true), ips);
+        assert_eq!(shared_ips(&ips, false), vec!["2a01:4f8::1".parse::<IpAddr>().unwrap()]);
+    }
+}
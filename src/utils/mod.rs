@@ -1,6 +1,4 @@
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::Read;
 use std::time::SystemTime;
 use console::style;
 use dialoguer::theme::ColorfulTheme;
@@ -10,10 +8,26 @@ use serde::{Deserialize, Serialize};
 
 use crate::error::{NudgeError, Result};
 
+pub mod attributes;
+pub mod delta;
+pub mod diagnosis;
+pub mod discovery;
+pub mod disk;
+pub mod file_name;
+pub mod interfaces;
+pub mod mtu;
+pub mod part_file;
 pub mod passphrase;
+pub mod prediction;
+pub mod rate;
 pub mod reliable_udp;
+pub mod report;
 pub mod socket;
 pub mod serialize;
+pub mod size;
+pub mod streams;
+pub mod verify;
+pub mod writer;
 
 #[cfg(debug_assertions)]
 pub const DEFAULT_RELAY_HOST: &str = "127.0.0.1";
@@ -25,8 +39,6 @@ pub const DEFAULT_RELAY_PORT: &str = "4000";
 #[cfg(not(debug_assertions))]
 pub const DEFAULT_RELAY_PORT: &str = "80";
 
-pub const DEFAULT_CHUNK_SIZE: &str = "4096";
-
 /// A wrapper around a string that can be displayed as "<anonymous>" if the string is None
 #[derive(Serialize, Deserialize, Debug, Ord, PartialEq, PartialOrd, Eq, Clone)]
 pub struct AnonymousString(pub Option<String>);
@@ -53,6 +65,18 @@ pub fn current_unix_millis() -> u64 {
         .as_millis() as u64
 }
 
+/// Returns the current time in microseconds since the Unix epoch.
+///
+/// # Returns
+///
+/// `u64` - The current time in microseconds.
+pub fn current_unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
 /// Retrieves the hostname of the system.
 ///
 /// # Returns
@@ -77,41 +101,18 @@ pub fn hide_or_get_hostname(hide: bool) -> Result<AnonymousString> {
     Ok(AnonymousString(if hide { None } else { Some(get_hostname()?) }))
 }
 
-/// Hashes the contents of a file using the BLAKE3 hashing algorithm and resets the file's cursor to the start.
-///
-/// # Arguments
-///
-/// * `file` - A mutable reference to the file to be hashed.
-///
-/// # Returns
-///
-/// `Result<String>` - The hexadecimal hash string of the file contents.
-pub fn hash_file_and_seek(file: &mut File) -> Result<String> {
-    let mut hasher = blake3::Hasher::new();
-    let mut buffer = [0; 8192];
-
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-        hasher.update(&buffer[..bytes_read]);
-    }
-
-    Ok(hasher.finalize().to_hex().to_string())
-}
-
 /// Creates a customized theme for prompts.
 ///
 /// # Returns
 ///
 /// `ColorfulTheme` - A theme with customized prompt, success, and error prefixes.
 pub fn question_theme() -> ColorfulTheme {
-    let mut theme = ColorfulTheme::default();
-    theme.prompt_prefix = style("[?]".to_string()).for_stderr().dim();
-    theme.success_prefix = style("[✔]".to_string()).for_stderr().bold().green();
-    theme.error_prefix = style("[✗]".to_string()).for_stderr().bold().red();
-    theme
+    ColorfulTheme {
+        prompt_prefix: style("[?]".to_string()).for_stderr().dim(),
+        success_prefix: style("[✔]".to_string()).for_stderr().bold().green(),
+        error_prefix: style("[✗]".to_string()).for_stderr().bold().red(),
+        ..ColorfulTheme::default()
+    }
 }
 
 /// Creates a new progress bar with a specified length and custom style.
@@ -132,6 +133,24 @@ pub fn new_downloader_progressbar(len: u64) -> ProgressBar {
     progress_bar
 }
 
+/// Creates a progress bar for hashing a file of the specified length.
+///
+/// # Arguments
+///
+/// * `length` - The size of the file in bytes.
+///
+/// # Returns
+///
+/// `ProgressBar` - A progress bar styled like the transfer progress bar, labelled as hashing.
+pub fn new_hashing_progressbar(len: u64) -> ProgressBar {
+    let progress_bar = ProgressBar::new(len)
+        .with_prefix("[#]");
+    progress_bar.set_style(ProgressStyle::with_template("{prefix:.orange} hashing :: |{wide_bar:.white/dim}| :: {bytes}/{total_bytes}")
+        .unwrap()
+        .progress_chars("█ :"));
+    progress_bar
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,346 @@
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use crate::error::{NudgeError, Result};
+use crate::models::{R2SChunkSizeAckMessage, R2SChunkSizeReplyMessage, S2RChunkSizeConfirmMessage, S2RChunkSizeQueryMessage};
+use crate::utils::reliable_udp::HEADER_SIZE;
+use crate::utils::serialize::{parse_and_expect, serialize_and_send};
+
+/// UDP payload sizes probed on the path, largest first:
+/// jumbo frames, Ethernet, PPPoE, common tunnels, IPv6 minimum MTU, IPv4 minimum reassembly size
+const PROBE_SIZES: [usize; 6] = [8972, 1472, 1452, 1392, 1232, 548];
+
+/// Largest payload which fits into a single UDP datagram together with the packet header
+pub const MAX_CHUNK_SIZE: u32 = 65507 - HEADER_SIZE as u32;
+
+/// Marks a datagram as path MTU probe, the rest of the probe is padding
+const PROBE_TAG: &[u8] = b"S2R_MTU";
+
+/// How long to wait for the reply before probing again
+const ATTEMPT_TIMEOUT: Duration = Duration::from_millis(300);
+const MAX_ATTEMPTS: usize = 10;
+
+/// Number of answered probe rounds before the sender accepts the reply.
+/// The peer may still be finishing the hole punching during the first round and swallow some of its probes.
+const PROBE_ROUNDS: usize = 2;
+
+/// How often the sender repeats its decision until the receiver acknowledges it
+const CONFIRM_ATTEMPTS: usize = 3;
+
+/// Decides which chunk size both peers use.
+///
+/// An explicitly configured chunk size always wins; if both peers configured one, they have to match.
+/// Otherwise the chunk size is derived from the largest probe which made it through the path.
+///
+/// # Arguments
+///
+/// * `sender` - Chunk size passed to the sender (optional).
+/// * `receiver` - Chunk size passed to the receiver (optional).
+/// * `largest_probe` - Largest UDP payload which arrived at the receiver (0 if none).
+///
+/// # Returns
+///
+/// `Result<u32>` - The chunk size, or `NudgeError::ChunkSizeMismatch` if both sides disagree.
+pub fn decide_chunk_size(sender: Option<u32>, receiver: Option<u32>, largest_probe: u32) -> Result<u32> {
+    match (sender, receiver) {
+        (Some(sender), Some(receiver)) if sender != receiver => Err(NudgeError::ChunkSizeMismatch(sender, receiver)),
+        (Some(chunk_size), _) | (_, Some(chunk_size)) => Ok(chunk_size),
+        (None, None) => {
+            let smallest_probe = PROBE_SIZES[PROBE_SIZES.len() - 1] as u32;
+            Ok(largest_probe.max(smallest_probe) - HEADER_SIZE as u32)
+        }
+    }
+}
+
+/// Probes the path MTU to the receiver and agrees on a chunk size.
+///
+/// Has to be called right after `init_socket`, while the receiver runs `negotiate_chunk_size_as_receiver`.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the receiver.
+/// * `requested` - Chunk size passed to the sender (optional).
+///
+/// # Errors
+///
+/// Returns `NudgeError::ChunkSizeMismatch` if the receiver requested a different chunk size,
+/// or `NudgeError::NegotiationTimeout` if the receiver does not answer.
+pub fn negotiate_chunk_size_as_sender(socket: &UdpSocket, requested: Option<u32>) -> Result<u32> {
+    set_dont_fragment(socket, true);
+    let result = query_receiver(socket, requested);
+    set_dont_fragment(socket, false);
+
+    let reply = result?;
+    let decision = decide_chunk_size(requested, reply.chunk_size, reply.largest_probe);
+    confirm_to_receiver(socket, decision.as_ref().ok().copied())?;
+    let chunk_size = decision?;
+    if reply.largest_probe > 0 && chunk_size as usize + HEADER_SIZE > reply.largest_probe as usize {
+        warn!(
+            "Chunk size of {} bytes exceeds the path MTU ({} bytes payload), packets will be fragmented",
+            chunk_size, reply.largest_probe
+        );
+    }
+    Ok(chunk_size)
+}
+
+/// Answers the path MTU probes of the sender and agrees on a chunk size.
+///
+/// Keeps answering repeated queries until the sender confirms its decision, so a lost reply is answered again.
+/// If the confirmation got lost, the first data packet ends the negotiation as well.
+/// Once the sender stopped asking for as long as it would keep asking, it is assumed to have given up.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket connected to the sender.
+/// * `requested` - Chunk size passed to the receiver (optional).
+///
+/// # Errors
+///
+/// Returns `NudgeError::ChunkSizeMismatch` if the sender requested a different chunk size,
+/// or `NudgeError::NegotiationTimeout` if the sender never asks.
+pub fn negotiate_chunk_size_as_receiver(socket: &UdpSocket, requested: Option<u32>) -> Result<u32> {
+    socket.set_read_timeout(Some(ATTEMPT_TIMEOUT))?;
+
+    let mut buffer = vec![0u8; PROBE_SIZES[0] + 1];
+    let mut largest_probe = 0;
+    let mut decision = None;
+    let mut last_query = Instant::now();
+
+    loop {
+        match socket.recv(&mut buffer) {
+            Ok(len) if buffer[..len].starts_with(PROBE_TAG) => largest_probe = largest_probe.max(len as u32),
+            Ok(len) if buffer[..len].starts_with(b"S2R_CHQ ") => {
+                let query: S2RChunkSizeQueryMessage = parse_and_expect(&buffer[..len], "S2R_CHQ")?;
+                serialize_and_send(socket, "R2S_CHR", &R2SChunkSizeReplyMessage {
+                    largest_probe,
+                    chunk_size: requested,
+                })?;
+                decision = Some(decide_chunk_size(query.chunk_size, requested, largest_probe));
+                last_query = Instant::now();
+            }
+            Ok(len) if buffer[..len].starts_with(b"S2R_CHC ") && decision.is_some() => {
+                let confirm: S2RChunkSizeConfirmMessage = parse_and_expect(&buffer[..len], "S2R_CHC")?;
+                serialize_and_send(socket, "R2S_CHA", &R2SChunkSizeAckMessage {})?;
+                // the sender decided on the last reply it got, later probes must not change the outcome
+                return match confirm.chunk_size {
+                    Some(chunk_size) => Ok(chunk_size),
+                    None => decision.unwrap_or(Err(NudgeError::NegotiationTimeout)),
+                };
+            }
+            // the confirmation got lost; the data packet is dropped here, but will be retransmitted
+            Ok(len) if len >= HEADER_SIZE && decision.is_some() => break,
+            // leftovers of the hole punching or a timeout
+            _ => {}
+        }
+        if last_query.elapsed() > ATTEMPT_TIMEOUT * MAX_ATTEMPTS as u32 {
+            break;
+        }
+    }
+
+    decision.unwrap_or(Err(NudgeError::NegotiationTimeout))
+}
+
+/// Sends the probes followed by a query until the receiver replied to `PROBE_ROUNDS` of them.
+///
+/// The receiver decides again on every query, with the largest probe of all rounds.
+fn query_receiver(socket: &UdpSocket, requested: Option<u32>) -> Result<R2SChunkSizeReplyMessage> {
+    socket.set_read_timeout(Some(ATTEMPT_TIMEOUT))?;
+    let mut buffer = [0u8; 1024];
+    let mut answered_rounds = 0;
+
+    for _ in 0..MAX_ATTEMPTS {
+        for size in PROBE_SIZES {
+            let mut probe = vec![0u8; size];
+            probe[..PROBE_TAG.len()].copy_from_slice(PROBE_TAG);
+            // sizes above the MTU of the local interface are rejected right away
+            let _ = socket.send(&probe);
+        }
+        serialize_and_send(socket, "S2R_CHQ", &S2RChunkSizeQueryMessage { chunk_size: requested })?;
+
+        let deadline = Instant::now() + ATTEMPT_TIMEOUT;
+        while Instant::now() < deadline {
+            match socket.recv(&mut buffer) {
+                Ok(len) if buffer[..len].starts_with(b"R2S_CHR ") => {
+                    let reply = parse_and_expect(&buffer[..len], "R2S_CHR")?;
+                    answered_rounds += 1;
+                    if answered_rounds == PROBE_ROUNDS {
+                        return Ok(reply);
+                    }
+                    break;
+                }
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+    }
+    Err(NudgeError::NegotiationTimeout)
+}
+
+/// Tells the receiver which chunk size was decided on, until it acknowledges it or `CONFIRM_ATTEMPTS` ran out.
+///
+/// Without an acknowledgment the sender goes on anyway, the receiver then stops at the first data packet.
+fn confirm_to_receiver(socket: &UdpSocket, chunk_size: Option<u32>) -> Result<()> {
+    socket.set_read_timeout(Some(ATTEMPT_TIMEOUT))?;
+    let mut buffer = [0u8; 1024];
+
+    for _ in 0..CONFIRM_ATTEMPTS {
+        serialize_and_send(socket, "S2R_CHC", &S2RChunkSizeConfirmMessage { chunk_size })?;
+
+        let deadline = Instant::now() + ATTEMPT_TIMEOUT;
+        while Instant::now() < deadline {
+            match socket.recv(&mut buffer) {
+                Ok(len) if buffer[..len].starts_with(b"R2S_CHA ") => return Ok(()),
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+    }
+    debug!("The receiver did not acknowledge the chunk size");
+    Ok(())
+}
+
+/// Sets the don't-fragment flag, so probes larger than the path MTU are dropped instead of fragmented.
+///
+/// A dual-stack socket needs the flag for both families, its IPv4 packets follow the IPv4 option.
+/// Only supported on Linux, other platforms may report a larger path MTU than the real one.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, enabled: bool) {
+    let value: libc::c_int = if enabled { libc::IP_PMTUDISC_PROBE } else { libc::IP_PMTUDISC_WANT };
+    set_option(socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, value);
+
+    if socket.local_addr().is_ok_and(|addr| addr.is_ipv6()) {
+        let value: libc::c_int = if enabled { libc::IPV6_PMTUDISC_PROBE } else { libc::IPV6_PMTUDISC_WANT };
+        set_option(socket, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, value);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_option(socket: &UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the socket is valid for the duration of the call and `value` outlives it
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        debug!("Cannot change the don't-fragment flag: {}", std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &UdpSocket, _enabled: bool) {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_decide_explicit_chunk_sizes() {
+        assert_eq!(decide_chunk_size(Some(4096), Some(4096), 1472).unwrap(), 4096);
+        assert_eq!(decide_chunk_size(Some(4096), None, 1472).unwrap(), 4096);
+        assert_eq!(decide_chunk_size(None, Some(2048), 1472).unwrap(), 2048);
+        assert!(matches!(
+            decide_chunk_size(Some(4096), Some(2048), 1472),
+            Err(NudgeError::ChunkSizeMismatch(4096, 2048))
+        ));
+    }
+
+    #[test]
+    fn test_decide_from_path_mtu() {
+        assert_eq!(decide_chunk_size(None, None, 1472).unwrap(), 1472 - HEADER_SIZE as u32);
+        // no probe arrived, fall back to the smallest one
+        assert_eq!(decide_chunk_size(None, None, 0).unwrap(), 548 - HEADER_SIZE as u32);
+    }
+
+    #[test]
+    fn test_negotiate_over_loopback() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+        let receiver = thread::spawn(move || negotiate_chunk_size_as_receiver(&receiver_socket, None));
+        let chunk_size = negotiate_chunk_size_as_sender(&sender_socket, None).unwrap();
+
+        assert_eq!(receiver.join().unwrap().unwrap(), chunk_size);
+        assert_eq!(chunk_size, PROBE_SIZES[0] as u32 - HEADER_SIZE as u32);
+    }
+
+    #[test]
+    fn test_receiver_answers_until_confirmed() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+        sender_socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let receiver = thread::spawn(move || negotiate_chunk_size_as_receiver(&receiver_socket, Some(1024)));
+        let mut buffer = [0u8; 1024];
+        for _ in 0..3 {
+            serialize_and_send(&sender_socket, "S2R_CHQ", &S2RChunkSizeQueryMessage { chunk_size: None }).unwrap();
+            let len = sender_socket.recv(&mut buffer).unwrap();
+            let reply: R2SChunkSizeReplyMessage = parse_and_expect(&buffer[..len], "R2S_CHR").unwrap();
+            assert_eq!(reply.chunk_size, Some(1024));
+            // as if the reply got lost and the sender asked again after its attempt timed out
+            thread::sleep(ATTEMPT_TIMEOUT * 2);
+        }
+        assert!(!receiver.is_finished());
+
+        serialize_and_send(&sender_socket, "S2R_CHC", &S2RChunkSizeConfirmMessage { chunk_size: Some(1024) }).unwrap();
+        let len = sender_socket.recv(&mut buffer).unwrap();
+        let _: R2SChunkSizeAckMessage = parse_and_expect(&buffer[..len], "R2S_CHA").unwrap();
+        assert_eq!(receiver.join().unwrap().unwrap(), 1024);
+    }
+
+    #[test]
+    fn test_negotiation_before_basis_signature_returns_promptly() {
+        use crate::utils::delta::{Basis, Signature};
+        use crate::utils::reliable_udp::{MetadataKind, ReliableUdpSocket};
+
+        let path = std::env::temp_dir().join(format!("nudge-mtu-basis-{}", std::process::id()));
+        std::fs::write(&path, vec![7u8; 100_000]).unwrap();
+        let basis = Basis::open(&path).unwrap();
+
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+        // with a basis, the sender sends no data until it got the signature of the receiver
+        let started = Instant::now();
+        let receiver = thread::spawn(move || {
+            let chunk_size = negotiate_chunk_size_as_receiver(&receiver_socket, None).unwrap();
+            ReliableUdpSocket::send_metadata(receiver_socket, MetadataKind::Signature, &basis.signature().encode(), chunk_size as usize).unwrap();
+            basis.signature().blocks.len()
+        });
+        let chunk_size = negotiate_chunk_size_as_sender(&sender_socket, None).unwrap();
+        let encoded = ReliableUdpSocket::receive_metadata(sender_socket, MetadataKind::Signature, chunk_size as usize).unwrap();
+        let blocks = receiver.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(Signature::decode(&encoded).unwrap().blocks.len(), blocks);
+        assert!(started.elapsed() < ATTEMPT_TIMEOUT * 3, "took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn test_negotiate_mismatch_fails_on_both_sides() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+        let receiver = thread::spawn(move || negotiate_chunk_size_as_receiver(&receiver_socket, Some(1024)));
+        let result = negotiate_chunk_size_as_sender(&sender_socket, Some(4096));
+
+        assert!(matches!(result, Err(NudgeError::ChunkSizeMismatch(4096, 1024))));
+        assert!(matches!(receiver.join().unwrap(), Err(NudgeError::ChunkSizeMismatch(4096, 1024))));
+    }
+}
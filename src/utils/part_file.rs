@@ -0,0 +1,152 @@
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::error::Result;
+use crate::utils::disk::preallocate;
+
+/// Extension appended to the output path while the file is received
+pub const PART_EXTENSION: &str = "nudge-part";
+
+/// Temporary file the received data is written to, next to the output path.
+///
+/// It only replaces the output file once `persist` is called, so a failed transfer
+/// never leaves partial data under the real name. Dropping it without `persist`
+/// removes it, unless it should be kept.
+#[derive(Debug)]
+pub struct PartFile {
+    file: File,
+    path: PathBuf,
+    out_path: PathBuf,
+    keep_on_failure: bool,
+    persisted: bool,
+}
+
+impl PartFile {
+    /// Creates (or reuses) the temporary file for `out_path` and reserves `len` bytes for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `out_path` - The path the file gets once it is complete.
+    /// * `len` - The size of the complete file.
+    /// * `keep_on_failure` - Whether the temporary file stays if the transfer fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the file cannot be created or the space cannot be reserved
+    pub fn create(out_path: &Path, len: u64, keep_on_failure: bool) -> Result<PartFile> {
+        let path = part_path(out_path);
+        let file = OpenOptions::new()
+            .truncate(false)
+            .write(true)
+            .create(true)
+            .read(true)
+            .open(&path)?;
+        let part_file = PartFile {
+            file,
+            path,
+            out_path: out_path.to_path_buf(),
+            keep_on_failure,
+            persisted: false,
+        };
+        preallocate(&part_file.file, len)?;
+        Ok(part_file)
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flushes the data to the disk and renames the temporary file to the output path.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if syncing or renaming fails, the temporary file is handled like a failed transfer then
+    pub fn persist(mut self) -> Result<()> {
+        self.file.sync_all()?;
+        std::fs::rename(&self.path, &self.out_path)?;
+        self.persisted = true;
+
+        // the rename itself is only durable once the directory is synced
+        #[cfg(unix)]
+        File::open(parent_dir(&self.out_path))?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.persisted && !self.keep_on_failure {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The temporary path for `out_path`, in the same directory so the rename does not cross file systems.
+pub fn part_path(out_path: &Path) -> PathBuf {
+    let mut path = OsString::from(out_path.as_os_str());
+    path.push(".");
+    path.push(PART_EXTENSION);
+    PathBuf::from(path)
+}
+
+#[cfg(unix)]
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn out_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nudge-part-{}-{}.bin", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_part_path_is_next_to_output() {
+        assert_eq!(part_path(Path::new("dir/file.txt")), PathBuf::from("dir/file.txt.nudge-part"));
+        assert_eq!(part_path(Path::new("file")), PathBuf::from("file.nudge-part"));
+    }
+
+    #[test]
+    fn test_persist_replaces_output() {
+        let out_path = out_path("persist");
+        std::fs::write(&out_path, b"old").unwrap();
+
+        let part_file = PartFile::create(&out_path, 3, false).unwrap();
+        part_file.file().write_all(b"new").unwrap();
+        assert_eq!(std::fs::read(&out_path).unwrap(), b"old");
+        let path = part_file.path().to_path_buf();
+        part_file.persist().unwrap();
+
+        assert_eq!(std::fs::read(&out_path).unwrap(), b"new");
+        assert!(!path.exists());
+        std::fs::remove_file(out_path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_transfer_removes_or_keeps_part() {
+        let out_path = out_path("failed");
+
+        let part_file = PartFile::create(&out_path, 1000, false).unwrap();
+        let path = part_file.path().to_path_buf();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 1000);
+        drop(part_file);
+        assert!(!path.exists());
+
+        drop(PartFile::create(&out_path, 1000, true).unwrap());
+        assert!(path.exists());
+        assert!(!out_path.exists());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashSet;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+
+use crate::error::Result;
+use crate::models::{PortAllocation, PunchAttempt};
+use crate::utils::socket::{bind_dual_stack, canonical, disconnect, mapped_for, punch, reconnect};
+
+/// Probe sockets every peer opens, the relay-server derives the port allocation of its NAT from their mappings
+pub const PROBE_SOCKETS: u8 = 4;
+
+/// Larger steps between the ports of consecutive mappings are not considered a pattern
+const MAX_PORT_DELTA: i32 = 32;
+
+/// Ports tried after the last observed one, when the NAT allocates them sequentially
+const PREDICTED_PORTS: i32 = 64;
+
+/// Sockets opened for all streams together by a peer whose NAT allocates random ports
+const BIRTHDAY_SOCKETS: usize = 256;
+
+/// Random ports tried on a peer whose NAT allocates random ports, each one hits one of its sockets by chance
+const BIRTHDAY_PORTS: usize = 1024;
+
+/// Packets a stream sends at most while spraying, however many sockets and ports it tries
+const MAX_SPRAY_PACKETS: usize = 2048;
+
+/// How often every pair of socket and port is tried at most
+const SPRAY_ROUNDS: usize = 8;
+
+/// The packets are spread over this time
+const SPRAY_DURATION: Duration = Duration::from_secs(4);
+
+/// How long to listen for the peer after the last packet
+const SPRAY_LINGER: Duration = Duration::from_secs(1);
+
+/// Interval of checking the sockets for packets of the peer while spraying
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Ports below are usually not allocated by NATs
+const LOWEST_PORT: u16 = 1024;
+
+/// Start of the spray packets, the index of the stream follows, so the streams do not get each other's sockets
+const SPRAY_TAG: &[u8] = b"NUDGE_SPRAY ";
+
+/// Handshake once a sprayed packet got through, the peer may still be busy with its other ports
+const SPRAY_HANDSHAKE: PunchAttempt = PunchAttempt { offset_ms: 0, packets: 25, interval_ms: 20 };
+
+/// Time between the end of the punch attempts and the spraying, so both peers are done with the attempts
+const SPRAY_GAP: Duration = Duration::from_secs(2);
+
+/// The port allocation of the NATs of both peers, as observed by the relay-server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prediction {
+    pub own: PortAllocation,
+    pub peer: PortAllocation,
+}
+
+impl Prediction {
+    /// Only known if both peers probed.
+    pub fn new(own: Option<PortAllocation>, peer: Option<PortAllocation>) -> Option<Self> {
+        Some(Prediction { own: own?, peer: peer? })
+    }
+
+    /// Whether we open many sockets, so the peer hits one of their mappings with its random ports.
+    fn birthday(&self) -> bool {
+        self.own == PortAllocation::Random && self.peer != PortAllocation::Random
+    }
+}
+
+/// Derives the port allocation of a NAT from the mappings of the probe sockets.
+///
+/// Ports which grow (or shrink) in small steps are sequential, the smallest step is used for the prediction,
+/// as other hosts behind the NAT may have taken the ports in between.
+///
+/// # Arguments
+///
+/// * `probes` - The local and the public port of every probe socket, in the order the sockets were opened.
+///
+/// # Returns
+///
+/// `Option<PortAllocation>` - The allocation, `None` without any probe.
+pub fn classify(probes: &[(u16, u16)]) -> Option<PortAllocation> {
+    let (_, last_port) = *probes.last()?;
+    if probes.iter().all(|(local, public)| local == public) {
+        return Some(PortAllocation::Preserving);
+    }
+    let steps: Vec<i32> = probes.windows(2)
+        .map(|pair| pair[1].1 as i32 - pair[0].1 as i32)
+        .collect();
+    let ascending = steps.iter().all(|step| (1..=MAX_PORT_DELTA).contains(step));
+    let descending = steps.iter().all(|step| (-MAX_PORT_DELTA..=-1).contains(step));
+    if steps.is_empty() || !(ascending || descending) {
+        return Some(PortAllocation::Random);
+    }
+    let delta = steps.into_iter().min_by_key(|step| step.abs()).unwrap_or(1);
+    Some(PortAllocation::Sequential { last_port, delta })
+}
+
+/// The addresses of the peer a stream sprays: its public address, then the ports its NAT probably allocated.
+///
+/// The peer's NAT allocated the mapping towards us when the punch attempts started, after the probes.
+/// Random ports are only tried if we are the one who can be predicted, otherwise the chance of a hit is too low.
+pub fn spray_targets(public: SocketAddr, prediction: &Prediction) -> Vec<SocketAddr> {
+    let ports = match prediction.peer {
+        PortAllocation::Preserving => Vec::new(),
+        PortAllocation::Sequential { last_port, delta } => (1..=PREDICTED_PORTS)
+            .map(|step| last_port as i32 + delta * step)
+            .filter_map(|port| u16::try_from(port).ok())
+            .filter(|port| *port >= LOWEST_PORT)
+            .collect(),
+        PortAllocation::Random if prediction.own != PortAllocation::Random => random_ports(BIRTHDAY_PORTS),
+        PortAllocation::Random => Vec::new(),
+    };
+    std::iter::once(public)
+        .chain(ports.into_iter().filter(|port| *port != public.port()).map(|port| SocketAddr::new(public.ip(), port)))
+        .collect()
+}
+
+/// `count` different ports, in random order
+fn random_ports(count: usize) -> Vec<u16> {
+    let mut rng = thread_rng();
+    let mut ports = HashSet::new();
+    while ports.len() < count {
+        ports.insert(rng.gen_range(LOWEST_PORT..=u16::MAX));
+    }
+    ports.into_iter().collect()
+}
+
+/// When the spraying starts, after the last punch attempt of the plan.
+pub fn spray_offset(attempts: &[PunchAttempt]) -> Duration {
+    let end_ms = attempts.iter()
+        .map(|attempt| attempt.offset_ms + attempt.packets as u64 * attempt.interval_ms)
+        .max()
+        .unwrap_or_default();
+    Duration::from_millis(end_ms) + SPRAY_GAP
+}
+
+/// Connects the socket of a stream to the peer through a predicted mapping of the peer's NAT.
+///
+/// Both peers spray their targets at the same time. If the NAT of the peer allocates random ports,
+/// it does not know which of its ports to spray either: it opens many sockets which all send to our
+/// public address, while we try random ports, until one of them is the port of one of its sockets.
+///
+/// # Arguments
+///
+/// * `socket` - The socket of the stream, replaced by the opened socket which got through.
+/// * `public` - The public address of the peer's socket of the stream.
+/// * `stream_index` - The index of the stream.
+/// * `prediction` - The port allocation of both NATs.
+/// * `streams` - The number of streams, which share the opened sockets.
+///
+/// # Returns
+///
+/// `Result<Option<SocketAddr>>` - The address the peer was reached at, `None` if no packet got through.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the socket fails
+pub fn punch_predicted(
+    socket: &mut UdpSocket,
+    public: SocketAddr,
+    stream_index: u8,
+    prediction: &Prediction,
+    streams: usize,
+) -> Result<Option<SocketAddr>> {
+    if public.is_ipv6() {
+        return Ok(None);
+    }
+    if prediction.own == PortAllocation::Random && prediction.peer == PortAllocation::Random {
+        debug!("The NATs of both peers allocate random ports, there is nothing to predict");
+        return Ok(None);
+    }
+
+    let mut opened: Vec<UdpSocket> = Vec::new();
+    if prediction.birthday() {
+        for _ in 0..(BIRTHDAY_SOCKETS / streams.max(1)).max(1) {
+            match bind_dual_stack() {
+                Ok(opened_socket) => opened.push(opened_socket),
+                Err(err) => {
+                    debug!("Cannot open more sockets than {}: {}", opened.len(), err);
+                    break;
+                }
+            }
+        }
+    }
+    let targets = spray_targets(public, prediction);
+    if targets.len() == 1 && opened.is_empty() {
+        debug!("The public address of the peer is all there is to try, and it failed already");
+        return Ok(None);
+    }
+    debug!("Spraying {} addresses of the peer from {} sockets...", targets.len(), opened.len() + 1);
+
+    disconnect(socket);
+    let sockets: Vec<&UdpSocket> = std::iter::once(&*socket).chain(&opened).collect();
+    let hit = spray(&sockets, &targets, stream_index)?;
+    let Some((index, peer_addr)) = hit else {
+        return Ok(None);
+    };
+    if index > 0 {
+        *socket = opened.swap_remove(index - 1);
+    }
+
+    debug!("Reached the peer at {}, completing the handshake...", peer_addr);
+    reconnect(socket, peer_addr)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    Ok(punch(socket, &SPRAY_HANDSHAKE)?.then_some(peer_addr))
+}
+
+/// Sends the spray packets from every socket to every target, paced over `SPRAY_DURATION`, until the peer answers.
+///
+/// Only packets from the targets count, so both peers end up with the same pair of addresses.
+///
+/// # Returns
+///
+/// `Result<Option<(usize, SocketAddr)>>` - The index of the socket the peer reached, and the address of the peer.
+fn spray(sockets: &[&UdpSocket], targets: &[SocketAddr], stream_index: u8) -> Result<Option<(usize, SocketAddr)>> {
+    for socket in sockets {
+        socket.set_nonblocking(true)?;
+    }
+    let result = spray_nonblocking(sockets, targets, stream_index);
+    for socket in sockets {
+        socket.set_nonblocking(false)?;
+    }
+    result
+}
+
+fn spray_nonblocking(
+    sockets: &[&UdpSocket],
+    targets: &[SocketAddr],
+    stream_index: u8,
+) -> Result<Option<(usize, SocketAddr)>> {
+    let packet: Vec<u8> = SPRAY_TAG.iter().copied().chain([stream_index]).collect();
+    let pairs = sockets.len() * targets.len();
+    let budget = MAX_SPRAY_PACKETS.min(pairs * SPRAY_ROUNDS);
+    let start = Instant::now();
+    let mut sent = 0;
+    let mut buffer = [0u8; 64];
+
+    while start.elapsed() < SPRAY_DURATION + SPRAY_LINGER {
+        let due = (start.elapsed().as_micros() * budget as u128 / SPRAY_DURATION.as_micros()) as usize + 1;
+        while sent < due.min(budget) {
+            // every socket sends to the first target before any of them sends to the next
+            let socket = sockets[sent % sockets.len()];
+            let target = targets[sent / sockets.len() % targets.len()];
+            let _ = socket.send_to(&packet, mapped_for(socket, target));
+            sent += 1;
+        }
+        for (index, socket) in sockets.iter().enumerate() {
+            while let Ok((len, addr)) = socket.recv_from(&mut buffer) {
+                let addr = canonical(addr);
+                if targets.contains(&addr) && is_punch_packet(&buffer[..len], &packet) {
+                    return Ok(Some((index, addr)));
+                }
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(None)
+}
+
+/// A spray packet of the same stream, or the handshake of a peer which got our spray packet first
+fn is_punch_packet(received: &[u8], packet: &[u8]) -> bool {
+    received == packet || received.len() <= 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loopback(socket: &UdpSocket) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), socket.local_addr().unwrap().port())
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&[]), None);
+        assert_eq!(classify(&[(5000, 5000), (5001, 5001)]), Some(PortAllocation::Preserving));
+        assert_eq!(
+            classify(&[(5000, 40000), (5001, 40001), (5002, 40004)]),
+            Some(PortAllocation::Sequential { last_port: 40004, delta: 1 })
+        );
+        assert_eq!(
+            classify(&[(5000, 40000), (5001, 39996), (5002, 39992)]),
+            Some(PortAllocation::Sequential { last_port: 39992, delta: -4 })
+        );
+        assert_eq!(classify(&[(5000, 40000), (5001, 12345), (5002, 61000)]), Some(PortAllocation::Random));
+        // a single mapping shows no pattern
+        assert_eq!(classify(&[(5000, 40000)]), Some(PortAllocation::Random));
+    }
+
+    #[test]
+    fn test_spray_targets() {
+        let public: SocketAddr = "1.2.3.4:40010".parse().unwrap();
+        let prediction = |own, peer| Prediction { own, peer };
+
+        let targets = spray_targets(public, &prediction(PortAllocation::Preserving, PortAllocation::Preserving));
+        assert_eq!(targets, vec![public]);
+
+        let sequential = PortAllocation::Sequential { last_port: 40008, delta: 2 };
+        let targets = spray_targets(public, &prediction(PortAllocation::Random, sequential));
+        assert_eq!(targets[0], public);
+        // the public port itself is among the predicted ones, but only tried once
+        assert_eq!(targets.len(), PREDICTED_PORTS as usize);
+        assert_eq!(targets[1], "1.2.3.4:40012".parse().unwrap());
+        assert!(targets.iter().all(|target| target.ip() == public.ip()));
+
+        let targets = spray_targets(public, &prediction(PortAllocation::Preserving, PortAllocation::Random));
+        let unique: HashSet<&SocketAddr> = targets.iter().collect();
+        assert!(targets.len() >= BIRTHDAY_PORTS && unique.len() == targets.len());
+        assert!(targets.iter().all(|target| target.port() >= LOWEST_PORT));
+
+        // two random NATs cannot guess each other
+        let targets = spray_targets(public, &prediction(PortAllocation::Random, PortAllocation::Random));
+        assert_eq!(targets, vec![public]);
+    }
+
+    #[test]
+    fn test_spray_offset() {
+        let attempts = [
+            PunchAttempt { offset_ms: 0, packets: 10, interval_ms: 100 },
+            PunchAttempt { offset_ms: 500, packets: 10, interval_ms: 10 },
+        ];
+        assert_eq!(spray_offset(&attempts), Duration::from_millis(1_000) + SPRAY_GAP);
+    }
+
+    #[test]
+    fn test_spray_finds_the_opened_socket() {
+        // one peer opened many sockets, the other one hits the last of them among other ports
+        let opened: Vec<UdpSocket> = (0..3).map(|_| UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+        let sprayer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let decoys: Vec<UdpSocket> = (0..10).map(|_| UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+        let mut targets: Vec<SocketAddr> = decoys.iter().map(loopback).collect();
+        targets.push(loopback(&opened[2]));
+        let sprayer_addr = loopback(&sprayer);
+
+        let (opened_hit, sprayer_hit) = thread::scope(|scope| {
+            let opened: Vec<&UdpSocket> = opened.iter().collect();
+            let opened_side = scope.spawn(move || spray(&opened, &[sprayer_addr], 3).unwrap());
+            let sprayer_side = scope.spawn(|| {
+                let hit = spray(&[&sprayer], &targets, 3).unwrap();
+                // the handshake which follows reaches the opened socket, even if no spray packet did
+                if let Some((_, addr)) = hit {
+                    sprayer.send_to(&[0], addr).unwrap();
+                }
+                hit
+            });
+            (opened_side.join().unwrap(), sprayer_side.join().unwrap())
+        });
+        assert_eq!(opened_hit, Some((2, sprayer_addr)));
+        assert_eq!(sprayer_hit, Some((0, loopback(&opened[2]))));
+    }
+
+    #[test]
+    fn test_punch_predicted() {
+        let mut first = bind_dual_stack().unwrap();
+        let mut second = bind_dual_stack().unwrap();
+        let (first_addr, second_addr) = (loopback(&first), loopback(&second));
+
+        // the first peer opens sockets, the second one sprays random ports after the public address
+        let first_prediction = Prediction { own: PortAllocation::Random, peer: PortAllocation::Preserving };
+        let second_prediction = Prediction { own: PortAllocation::Preserving, peer: PortAllocation::Random };
+        let (first_hit, second_hit) = thread::scope(|scope| {
+            let first = scope.spawn(|| punch_predicted(&mut first, second_addr, 0, &first_prediction, 4).unwrap());
+            let second = scope.spawn(|| punch_predicted(&mut second, first_addr, 0, &second_prediction, 4).unwrap());
+            (first.join().unwrap(), second.join().unwrap())
+        });
+        assert_eq!(first_hit, Some(second_addr));
+        assert_eq!(second_hit, Some(first_addr));
+        assert_eq!(canonical(second.peer_addr().unwrap()), first_addr);
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use humansize::{DECIMAL, format_size};
+use serde::{Deserialize, Deserializer};
+
+use crate::error::NudgeError;
+use crate::utils::size::{byte_multiplier, split_unit};
+
+/// A transfer rate like `5MB/s`, or `unlimited`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(pub Option<u64>);
+
+impl Rate {
+    pub const UNLIMITED: Rate = Rate(None);
+
+    /// Bytes per second, `None` if unlimited.
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        self.0
+    }
+
+    /// The lower of both rates.
+    pub fn min(self, other: Rate) -> Rate {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => Rate(Some(a.min(b))),
+            (a, b) => Rate(a.or(b)),
+        }
+    }
+}
+
+impl FromStr for Rate {
+    type Err = NudgeError;
+
+    /// Parses rates like `5MB/s`, `500 KiB/s`, `100Mbit/s` or `unlimited`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let normalized = value.trim().to_lowercase();
+        if normalized == "unlimited" {
+            return Ok(Rate::UNLIMITED);
+        }
+
+        let without_suffix = normalized.strip_suffix("/s").unwrap_or(&normalized);
+        let (number, unit) = split_unit(without_suffix);
+
+        let multiplier = match unit {
+            "kbit" => 1e3 / 8.0,
+            "mbit" => 1e6 / 8.0,
+            "gbit" => 1e9 / 8.0,
+            unit => byte_multiplier(unit).ok_or_else(|| NudgeError::InvalidRate(value.to_string()))?,
+        };
+        let number: f64 = number.parse().map_err(|_| NudgeError::InvalidRate(value.to_string()))?;
+
+        let bytes_per_sec = (number * multiplier) as u64;
+        if bytes_per_sec == 0 {
+            return Err(NudgeError::InvalidRate(value.to_string()));
+        }
+        Ok(Rate(Some(bytes_per_sec)))
+    }
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(bytes_per_sec) => write!(f, "{}/s", format_size(bytes_per_sec, DECIMAL)),
+            None => f.write_str("unlimited"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Rate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A time of day like `19:00`, stored as minutes since midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(pub u16);
+
+impl FromStr for TimeOfDay {
+    type Err = NudgeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || NudgeError::InvalidTimeOfDay(value.to_string());
+        let (hours, minutes) = value.trim().split_once(':').ok_or_else(invalid)?;
+        let hours: u16 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay(hours * 60 + minutes))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// One entry of a schedule: from `from` on, until the next entry starts, `limit` applies
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+    pub(crate) from: TimeOfDay,
+    pub(crate) limit: Rate,
+}
+
+/// Rate limit which depends on the time of day
+#[derive(Debug, Clone)]
+pub struct RateSchedule {
+    /// Applies before the first entry of the day, and all day if there are no entries
+    default: Rate,
+    /// Sorted by start time
+    entries: Vec<ScheduleEntry>,
+}
+
+impl RateSchedule {
+    /// A schedule with the same rate all day.
+    pub fn fixed(rate: Rate) -> Self {
+        RateSchedule { default: rate, entries: Vec::new() }
+    }
+
+    /// A schedule in which every entry applies until the next one starts or the day ends, `default` before the first one.
+    pub fn new(default: Rate, mut entries: Vec<ScheduleEntry>) -> Self {
+        entries.sort_by_key(|entry| entry.from);
+        RateSchedule { default, entries }
+    }
+
+    /// The rate at the given time of day.
+    pub fn rate_at(&self, time: TimeOfDay) -> Rate {
+        self.entries.iter()
+            .rev()
+            .find(|entry| entry.from <= time)
+            .map_or(self.default, |entry| entry.limit)
+    }
+
+    /// The rate right now, in local time.
+    pub fn current_rate(&self) -> Rate {
+        use chrono::Timelike;
+
+        let now = chrono::Local::now();
+        self.rate_at(TimeOfDay((now.hour() * 60 + now.minute()) as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!("5MB/s".parse::<Rate>().unwrap(), Rate(Some(5_000_000)));
+        assert_eq!("5 mb/s".parse::<Rate>().unwrap(), Rate(Some(5_000_000)));
+        assert_eq!("1.5MiB/s".parse::<Rate>().unwrap(), Rate(Some(1_572_864)));
+        assert_eq!("100Mbit/s".parse::<Rate>().unwrap(), Rate(Some(12_500_000)));
+        assert_eq!("800".parse::<Rate>().unwrap(), Rate(Some(800)));
+        assert_eq!("unlimited".parse::<Rate>().unwrap(), Rate::UNLIMITED);
+
+        for invalid in ["", "0MB/s", "fast", "5XB/s", "MB/s"] {
+            assert!(matches!(invalid.parse::<Rate>(), Err(NudgeError::InvalidRate(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_rate_min() {
+        assert_eq!(Rate(Some(5)).min(Rate(Some(3))), Rate(Some(3)));
+        assert_eq!(Rate(Some(5)).min(Rate::UNLIMITED), Rate(Some(5)));
+        assert_eq!(Rate::UNLIMITED.min(Rate::UNLIMITED), Rate::UNLIMITED);
+    }
+
+    #[test]
+    fn test_parse_time_of_day() {
+        assert_eq!("19:00".parse::<TimeOfDay>().unwrap(), TimeOfDay(19 * 60));
+        assert_eq!("7:30".parse::<TimeOfDay>().unwrap(), TimeOfDay(7 * 60 + 30));
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+        assert!("19".parse::<TimeOfDay>().is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let office_hours = Rate(Some(5_000_000));
+        let schedule = RateSchedule::new(office_hours, vec![
+            ScheduleEntry { from: TimeOfDay(19 * 60), limit: Rate::UNLIMITED },
+            ScheduleEntry { from: TimeOfDay(7 * 60), limit: office_hours },
+        ]);
+
+        assert_eq!(schedule.rate_at(TimeOfDay(12 * 60)), office_hours);
+        assert_eq!(schedule.rate_at(TimeOfDay(19 * 60)), Rate::UNLIMITED);
+        assert_eq!(schedule.rate_at(TimeOfDay(23 * 60)), Rate::UNLIMITED);
+        // does not wrap around midnight, the default applies before the first entry
+        assert_eq!(schedule.rate_at(TimeOfDay(3 * 60)), office_hours);
+        assert_eq!(schedule.rate_at(TimeOfDay(7 * 60)), office_hours);
+
+        assert_eq!(RateSchedule::fixed(office_hours).rate_at(TimeOfDay(0)), office_hours);
+    }
+}
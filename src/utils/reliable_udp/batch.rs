@@ -0,0 +1,479 @@
+use std::io;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+/// Most datagrams handed to or taken from the kernel at once
+pub const MAX_BATCH: usize = 64;
+
+/// How often `SendBatch::flush` tries to hand a datagram to the kernel before giving up
+const MAX_SEND_ATTEMPTS: u32 = 250;
+
+/// When batching, a packet may leave up to this many microseconds before its pacing slot
+const BATCH_PACING_SLACK_MICROS: u64 = 200;
+
+/// How datagrams are handed to the kernel
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SendPath {
+    /// Fastest path supported by the platform
+    #[default]
+    Auto,
+    /// One `send` call per datagram
+    Single,
+    /// Batches of datagrams with `sendmmsg` (Linux only)
+    Sendmmsg,
+    /// Batches of equally sized datagrams with UDP segmentation offload, falls back to `sendmmsg` (Linux only)
+    Gso,
+}
+
+impl SendPath {
+    /// Replaces `Auto` and paths the platform does not support.
+    fn resolve(self) -> SendPath {
+        if !cfg!(target_os = "linux") {
+            return SendPath::Single;
+        }
+        match self {
+            SendPath::Auto => SendPath::Gso,
+            path => path,
+        }
+    }
+}
+
+/// Collects outgoing datagrams and sends them with as few syscalls as the send path allows.
+///
+/// With `SendPath::Single` every datagram is sent right away, like an unbatched socket would.
+pub struct SendBatch {
+    path: SendPath,
+    /// Reused datagram buffers, the first `len` of them are queued
+    slots: Vec<Vec<u8>>,
+    len: usize,
+    /// Reused buffer for the concatenated segments of a GSO send
+    gso_buffer: Vec<u8>,
+}
+
+impl SendBatch {
+    pub fn new(path: SendPath) -> Self {
+        SendBatch {
+            path: path.resolve(),
+            slots: Vec::new(),
+            len: 0,
+            gso_buffer: Vec::new(),
+        }
+    }
+
+    /// The send path in use, after resolving `Auto` and falling back from GSO.
+    pub fn path(&self) -> SendPath {
+        self.path
+    }
+
+    /// How many microseconds a packet may be sent ahead of its pacing slot, so packets can be batched.
+    pub fn pacing_slack(&self) -> u64 {
+        match self.path {
+            SendPath::Single => 0,
+            _ => BATCH_PACING_SLACK_MICROS,
+        }
+    }
+
+    /// Queues a copy of `datagram` and sends the batch once it is full.
+    ///
+    /// # Errors
+    /// See `flush`.
+    pub fn push(&mut self, socket: &UdpSocket, datagram: &[u8]) -> io::Result<()> {
+        if self.len == self.slots.len() {
+            self.slots.push(Vec::new());
+        }
+        let slot = &mut self.slots[self.len];
+        slot.clear();
+        slot.extend_from_slice(datagram);
+        self.len += 1;
+
+        let capacity = if self.path == SendPath::Single { 1 } else { MAX_BATCH };
+        if self.len >= capacity {
+            self.flush(socket)?;
+        }
+        Ok(())
+    }
+
+    /// Sends all queued datagrams, retrying until the kernel accepted every one of them.
+    ///
+    /// # Errors
+    /// Returns the last error once `MAX_SEND_ATTEMPTS` tries in a row made no progress,
+    /// e.g. when the peer refuses the datagrams. The queued datagrams are discarded.
+    pub fn flush(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        let mut sent = 0;
+        let mut failed_attempts = 0;
+        let mut last_error;
+        while sent < self.len {
+            let pending = &self.slots[sent..self.len];
+            let result = match self.path {
+                SendPath::Gso => sys::send_gso(socket, pending, &mut self.gso_buffer),
+                SendPath::Sendmmsg => sys::send_mmsg(socket, pending),
+                _ => send_single(socket, &pending[0]),
+            };
+
+            match result {
+                Ok(count) if count > 0 => {
+                    sent += count;
+                    failed_attempts = 0;
+                    continue;
+                }
+                Err(e) if self.path == SendPath::Gso && sys::gso_unsupported(&e) => {
+                    debug!("UDP segmentation offload is not available ({}), using sendmmsg", e);
+                    self.path = SendPath::Sendmmsg;
+                    continue;
+                }
+                Err(e) => last_error = e,
+                Ok(_) => last_error = io::ErrorKind::WriteZero.into(),
+            }
+            failed_attempts += 1;
+            if failed_attempts >= MAX_SEND_ATTEMPTS {
+                self.len = 0;
+                return Err(last_error);
+            }
+            thread::sleep(Duration::from_millis(4)); // Minimal delay between retries
+        }
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// Receives as many datagrams as are waiting with a single syscall where possible (`recvmmsg` on Linux).
+pub struct RecvBatch {
+    /// One buffer per datagram, all of `slot_size` bytes
+    slots: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+    count: usize,
+}
+
+impl RecvBatch {
+    /// Creates a batch whose datagrams may be up to `slot_size` bytes long.
+    ///
+    /// Longer ones are dropped on Linux and truncated elsewhere.
+    pub fn new(slot_size: usize) -> Self {
+        RecvBatch {
+            slots: (0..sys::RECV_SLOTS).map(|_| vec![0; slot_size]).collect(),
+            lens: vec![0; sys::RECV_SLOTS],
+            count: 0,
+        }
+    }
+
+    pub fn slot_size(&self) -> usize {
+        self.slots[0].len()
+    }
+
+    /// Waits for at least one datagram (honoring the read timeout of the socket)
+    /// and takes all datagrams which are already waiting, up to the number of slots.
+    ///
+    /// Returns the number of datagrams received.
+    pub fn receive(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.count = 0;
+        self.count = sys::recv_mmsg(socket, &mut self.slots, &mut self.lens)?;
+        Ok(self.count)
+    }
+
+    /// The datagrams of the last `receive`.
+    pub fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        self.slots.iter()
+            .zip(&self.lens)
+            .take(self.count)
+            .map(|(slot, len)| &slot[..*len])
+    }
+}
+
+/// Sends a single datagram, returns how many datagrams were sent.
+fn send_single(socket: &UdpSocket, datagram: &[u8]) -> io::Result<usize> {
+    let bytes_sent = socket.send(datagram)?;
+    Ok(usize::from(bytes_sent == datagram.len()))
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::UdpSocket;
+    use std::os::fd::AsRawFd;
+
+    use super::{MAX_BATCH, send_single};
+
+    /// Largest UDP payload the kernel accepts before segmenting it
+    const MAX_GSO_BYTES: usize = 65507;
+
+    /// Most segments a single GSO send may be split into
+    const MAX_GSO_SEGMENTS: usize = 64;
+
+    /// Number of datagrams a `RecvBatch` takes at once
+    pub const RECV_SLOTS: usize = MAX_BATCH;
+
+    /// Sends up to `MAX_BATCH` datagrams with one `sendmmsg` call, returns how many were sent.
+    pub fn send_mmsg(socket: &UdpSocket, datagrams: &[Vec<u8>]) -> io::Result<usize> {
+        let count = datagrams.len().min(MAX_BATCH);
+        // SAFETY: both are plain C structs for which all zeroes is a valid value
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut messages: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+
+        for (index, datagram) in datagrams[..count].iter().enumerate() {
+            iovecs[index] = libc::iovec {
+                iov_base: datagram.as_ptr() as *mut libc::c_void,
+                iov_len: datagram.len(),
+            };
+            messages[index].msg_hdr.msg_iov = &mut iovecs[index];
+            messages[index].msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: the messages point into `iovecs` and `datagrams`, which outlive the call;
+        // the socket is connected, so no destination address is needed
+        let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), messages.as_mut_ptr(), count as libc::c_uint, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// Sends the leading run of equally sized datagrams as one GSO buffer, returns how many were sent.
+    ///
+    /// Only the last datagram of a run may be shorter than the others.
+    pub fn send_gso(socket: &UdpSocket, datagrams: &[Vec<u8>], buffer: &mut Vec<u8>) -> io::Result<usize> {
+        let segment_size = datagrams[0].len();
+        let mut count = 0;
+        buffer.clear();
+        for datagram in datagrams.iter().take(MAX_GSO_SEGMENTS) {
+            if datagram.len() > segment_size || buffer.len() + datagram.len() > MAX_GSO_BYTES {
+                break;
+            }
+            buffer.extend_from_slice(datagram);
+            count += 1;
+            if datagram.len() < segment_size {
+                break;
+            }
+        }
+
+        if count == 1 {
+            return send_single(socket, &datagrams[0]);
+        }
+
+        let mut iovec = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        // u64 keeps the control buffer aligned for `cmsghdr`
+        let mut control = [0u64; 4];
+        // SAFETY: all zeroes is a valid `msghdr`
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iovec;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        // SAFETY: CMSG_SPACE only computes a size
+        message.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+
+        // SAFETY: `control` is large enough for one cmsg carrying a u16, see `msg_controllen`
+        let sent = unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&message);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size as u16);
+            libc::sendmsg(socket.as_raw_fd(), &message, 0)
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(count)
+    }
+
+    /// Receives up to `slots.len()` datagrams with one `recvmmsg` call, returns how many were kept.
+    ///
+    /// Blocks only until the first datagram arrived. Truncated datagrams are dropped,
+    /// so the result may be 0.
+    pub fn recv_mmsg(socket: &UdpSocket, slots: &mut [Vec<u8>], lens: &mut [usize]) -> io::Result<usize> {
+        let count = slots.len().min(MAX_BATCH);
+        // SAFETY: both are plain C structs for which all zeroes is a valid value
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut messages: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+
+        for (index, slot) in slots[..count].iter_mut().enumerate() {
+            iovecs[index] = libc::iovec {
+                iov_base: slot.as_mut_ptr() as *mut libc::c_void,
+                iov_len: slot.len(),
+            };
+            messages[index].msg_hdr.msg_iov = &mut iovecs[index];
+            messages[index].msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: the messages point into `iovecs` and `slots`, which outlive the call
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                std::ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Datagrams longer than their slot are dropped, the kept ones move to the front
+        let mut kept = 0;
+        for (index, message) in messages[..received as usize].iter().enumerate() {
+            if message.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                debug!("Dropping a datagram longer than {} bytes", slots[index].len());
+                continue;
+            }
+            slots.swap(kept, index);
+            lens[kept] = message.msg_len as usize;
+            kept += 1;
+        }
+        Ok(kept)
+    }
+
+    /// Whether the error means that the kernel or the device cannot segment the buffer.
+    pub fn gso_unsupported(error: &io::Error) -> bool {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::EIO | libc::EINVAL | libc::ENOPROTOOPT | libc::EOPNOTSUPP)
+        )
+    }
+}
+
+/// Batching is only implemented on Linux, `SendPath::resolve` never selects these elsewhere.
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::net::UdpSocket;
+
+    use super::send_single;
+
+    pub const RECV_SLOTS: usize = 1;
+
+    pub fn recv_mmsg(socket: &UdpSocket, slots: &mut [Vec<u8>], lens: &mut [usize]) -> io::Result<usize> {
+        lens[0] = socket.recv(&mut slots[0])?;
+        Ok(1)
+    }
+
+    pub fn send_mmsg(socket: &UdpSocket, datagrams: &[Vec<u8>]) -> io::Result<usize> {
+        send_single(socket, &datagrams[0])
+    }
+
+    pub fn send_gso(socket: &UdpSocket, datagrams: &[Vec<u8>], _buffer: &mut Vec<u8>) -> io::Result<usize> {
+        send_single(socket, &datagrams[0])
+    }
+
+    pub fn gso_unsupported(_error: &io::Error) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `datagrams` over loopback with the given path and returns what arrived.
+    fn roundtrip(path: SendPath, datagrams: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let mut batch = SendBatch::new(path);
+        for datagram in datagrams {
+            batch.push(&sender, datagram).unwrap();
+        }
+        batch.flush(&sender).unwrap();
+
+        let mut buffer = [0u8; 2048];
+        (0..datagrams.len())
+            .map(|_| {
+                let len = receiver.recv(&mut buffer).unwrap();
+                buffer[..len].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_all_paths_preserve_datagrams() {
+        // equally sized runs, a shorter tail and a size change in between
+        let mut datagrams: Vec<Vec<u8>> = (0..70u8).map(|i| vec![i; 200]).collect();
+        datagrams.push(vec![70; 10]);
+        datagrams.extend((71..80u8).map(|i| vec![i; 100]));
+
+        for path in [SendPath::Single, SendPath::Sendmmsg, SendPath::Gso, SendPath::Auto] {
+            assert_eq!(roundtrip(path, &datagrams), datagrams, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn test_receive_batch() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let datagrams: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; i as usize % 64 + 1]).collect();
+        for datagram in &datagrams {
+            sender.send(datagram).unwrap();
+        }
+
+        let mut batch = RecvBatch::new(64);
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            assert!(batch.receive(&receiver).unwrap() > 0);
+            received.extend(batch.datagrams().map(<[u8]>::to_vec));
+        }
+
+        assert_eq!(received, datagrams);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_receive_batch_drops_truncated_datagrams() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let datagrams: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; i as usize + 1]).collect();
+        for datagram in &datagrams {
+            sender.send(datagram).unwrap();
+        }
+        sender.send(b"end").unwrap();
+
+        let mut batch = RecvBatch::new(64);
+        let mut received = Vec::new();
+        while received.last().map(Vec::as_slice) != Some(b"end".as_slice()) {
+            batch.receive(&receiver).unwrap();
+            received.extend(batch.datagrams().map(<[u8]>::to_vec));
+        }
+
+        let mut expected: Vec<Vec<u8>> = datagrams.into_iter().filter(|d| d.len() <= 64).collect();
+        expected.push(b"end".to_vec());
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_flush_gives_up_on_persistent_errors() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+
+        // larger than any UDP payload, the kernel rejects it on every attempt
+        let mut batch = SendBatch::new(SendPath::Single);
+        assert!(batch.push(&sender, &vec![0; 70000]).is_err());
+        batch.push(&sender, b"hello").unwrap();
+    }
+
+    #[test]
+    fn test_single_path_sends_immediately() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        receiver.set_nonblocking(true).unwrap();
+
+        let mut batch = SendBatch::new(SendPath::Single);
+        batch.push(&sender, b"hello").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(receiver.recv(&mut [0u8; 16]).unwrap(), 5);
+    }
+}
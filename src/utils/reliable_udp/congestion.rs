@@ -0,0 +1,249 @@
+/// Queuing delay (in microseconds) the controller tries to keep on the path, see RFC 6817
+const TARGET_DELAY_MICROS: f64 = 25_000.0;
+
+/// How aggressively the window reacts to the distance from the target delay
+const GAIN: f64 = 1.0;
+
+/// Window (in packets) before the first acknowledgment arrived
+const INITIAL_WINDOW: f64 = 10.0;
+
+/// The window never shrinks below this many packets
+const MIN_WINDOW: f64 = 2.0;
+
+/// Packets are paced slightly faster than one window per RTT, so the window can actually be filled
+const PACING_GAIN: f64 = 1.25;
+
+/// Retransmission timeout before any RTT was measured, see RFC 6298
+const INITIAL_RTO_MICROS: u64 = 1_000_000;
+const MIN_RTO_MICROS: u64 = 200_000;
+const MAX_RTO_MICROS: u64 = 60_000_000;
+
+/// Smoothed round-trip time estimation from acknowledgment samples (RFC 6298)
+#[derive(Debug, Default)]
+pub struct RttEstimator {
+    smoothed: Option<u64>,
+    variation: u64,
+    min: Option<u64>,
+    latest: Option<u64>,
+    backoff: u32,
+}
+
+impl RttEstimator {
+    pub fn update(&mut self, sample: u64) {
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(sample);
+                self.variation = sample / 2;
+            }
+            Some(smoothed) => {
+                self.variation = (3 * self.variation + smoothed.abs_diff(sample)) / 4;
+                self.smoothed = Some((7 * smoothed + sample) / 8);
+            }
+        }
+        self.min = Some(self.min.map_or(sample, |min| min.min(sample)));
+        self.latest = Some(sample);
+        self.backoff = 0;
+    }
+
+    /// Smoothed RTT in microseconds, if at least one sample was taken.
+    pub fn smoothed(&self) -> Option<u64> {
+        self.smoothed
+    }
+
+    /// Lowest RTT seen so far, used as the base delay of the path.
+    pub fn min(&self) -> Option<u64> {
+        self.min
+    }
+
+    /// Current retransmission timeout in microseconds, including the exponential backoff.
+    pub fn rto(&self) -> u64 {
+        let rto = match self.smoothed {
+            None => INITIAL_RTO_MICROS,
+            Some(smoothed) => (smoothed + 4 * self.variation).clamp(MIN_RTO_MICROS, MAX_RTO_MICROS),
+        };
+        rto.saturating_mul(1 << self.backoff.min(6)).min(MAX_RTO_MICROS)
+    }
+
+    /// Doubles the retransmission timeout until the next sample arrives.
+    pub fn back_off(&mut self) {
+        self.backoff += 1;
+    }
+
+    /// Estimated queuing delay: how much the latest sample exceeds the base delay.
+    fn queuing_delay(&self) -> Option<u64> {
+        Some(self.latest? - self.min()?)
+    }
+}
+
+/// Delay-based congestion controller in the spirit of LEDBAT (RFC 6817).
+///
+/// Grows the window while the measured queuing delay stays below `TARGET_DELAY_MICROS`,
+/// shrinks it when the delay rises above the target or packets get lost,
+/// and paces the packets of a window evenly across one round trip.
+pub struct CongestionController {
+    rtt: RttEstimator,
+    window: f64,
+    slow_start_threshold: f64,
+    max_window: usize,
+    last_reduction_at: u64,
+    next_send_at: u64,
+}
+
+impl CongestionController {
+    /// Creates a new controller whose window never exceeds `max_window` packets.
+    pub fn new(max_window: usize) -> Self {
+        CongestionController {
+            rtt: RttEstimator::default(),
+            window: INITIAL_WINDOW.min(max_window as f64),
+            slow_start_threshold: f64::INFINITY,
+            max_window,
+            last_reduction_at: 0,
+            next_send_at: 0,
+        }
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Number of packets which may be in flight at the moment.
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// Updates the window after `acked` packets were acknowledged.
+    pub fn on_ack(&mut self, acked: usize, rtt_sample: Option<u64>) {
+        if let Some(sample) = rtt_sample {
+            self.rtt.update(sample);
+        }
+        if acked == 0 {
+            return;
+        }
+
+        let queuing_delay = self.rtt.queuing_delay().unwrap_or_default() as f64;
+        if self.window < self.slow_start_threshold && queuing_delay < TARGET_DELAY_MICROS / 2.0 {
+            self.window += acked as f64;
+        } else {
+            let off_target = (TARGET_DELAY_MICROS - queuing_delay) / TARGET_DELAY_MICROS;
+            // never grow faster than slow start would
+            self.window += (GAIN * off_target * acked as f64 / self.window).min(acked as f64);
+        }
+        self.window = self.window.clamp(MIN_WINDOW, self.max_window as f64);
+    }
+
+    /// Halves the window after a loss, at most once per round trip.
+    pub fn on_loss(&mut self, now: u64) {
+        let round_trip = self.rtt.smoothed().unwrap_or(INITIAL_RTO_MICROS);
+        if now.saturating_sub(self.last_reduction_at) < round_trip {
+            return;
+        }
+        self.last_reduction_at = now;
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+        self.slow_start_threshold = self.window;
+    }
+
+    /// Collapses the window after the retransmission timer expired.
+    pub fn on_timeout(&mut self, now: u64) {
+        self.slow_start_threshold = (self.window / 2.0).max(MIN_WINDOW);
+        self.window = MIN_WINDOW;
+        self.last_reduction_at = now;
+        self.rtt.back_off();
+    }
+
+    /// Gap between two packets in microseconds, so that one window is spread over one round trip.
+    pub fn pacing_interval(&self) -> u64 {
+        match self.rtt.smoothed() {
+            Some(smoothed) => (smoothed as f64 / (self.window * PACING_GAIN)) as u64,
+            // burst the initial window until we know the RTT
+            None => 0,
+        }
+    }
+
+    /// Returns how many microseconds to wait before the next packet may be sent.
+    pub fn time_until_send(&self, now: u64) -> u64 {
+        self.next_send_at.saturating_sub(now)
+    }
+
+    /// Records that a packet was sent at `now` and schedules the next one.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Current time in microseconds.
+    /// * `min_delay` - User-defined minimum gap between two packets in microseconds (0 for none).
+    pub fn on_packet_sent(&mut self, now: u64, min_delay: u64) {
+        let interval = self.pacing_interval().max(min_delay);
+        self.next_send_at = self.next_send_at.max(now) + interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_estimator() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.rto(), INITIAL_RTO_MICROS);
+
+        rtt.update(100_000);
+        assert_eq!(rtt.smoothed(), Some(100_000));
+        assert_eq!(rtt.rto(), 300_000);
+
+        rtt.update(20_000);
+        assert_eq!(rtt.min(), Some(20_000));
+        assert_eq!(rtt.smoothed(), Some(90_000));
+
+        let rto = rtt.rto();
+        rtt.back_off();
+        assert_eq!(rtt.rto(), rto * 2);
+    }
+
+    #[test]
+    fn test_window_grows_without_queuing_delay() {
+        let mut controller = CongestionController::new(256);
+        for _ in 0..10 {
+            controller.on_ack(1, Some(1_000));
+        }
+        assert_eq!(controller.window(), 20);
+    }
+
+    #[test]
+    fn test_window_shrinks_above_target_delay() {
+        let mut controller = CongestionController::new(256);
+        controller.on_ack(1, Some(1_000));
+        controller.on_loss(10_000_000);
+        let window = controller.window;
+
+        // latest RTT is far above the base delay
+        controller.on_ack(1, Some(1_000 + 3 * TARGET_DELAY_MICROS as u64));
+        assert!(controller.window < window);
+    }
+
+    #[test]
+    fn test_loss_halves_window_once_per_round_trip() {
+        let mut controller = CongestionController::new(256);
+        controller.on_ack(10, Some(10_000));
+        assert_eq!(controller.window(), 20);
+
+        controller.on_loss(1_000_000);
+        assert_eq!(controller.window(), 10);
+        controller.on_loss(1_000_001);
+        assert_eq!(controller.window(), 10);
+
+        controller.on_timeout(2_000_000);
+        assert_eq!(controller.window(), MIN_WINDOW as usize);
+    }
+
+    #[test]
+    fn test_pacing_respects_min_delay() {
+        let mut controller = CongestionController::new(256);
+        controller.on_ack(0, Some(10_000));
+        assert_eq!(controller.pacing_interval(), 800);
+
+        controller.on_packet_sent(0, 0);
+        assert_eq!(controller.time_until_send(0), 800);
+
+        controller.on_packet_sent(800, 5_000);
+        assert_eq!(controller.time_until_send(800), 5_000);
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use super::window::{offset, read_seq, SeqNo, SEQ_SIZE};
+
+/// Number of data packets protected by the same parity packets
+pub const GROUP_SIZE: usize = 16;
+
+/// Upper bound of parity packets per group
+const MAX_PARITY: usize = GROUP_SIZE / 2;
+
+/// Parity packets need this many bytes more than the data packets they protect,
+/// the sender keeps its payloads this much smaller to stay within the path MTU
+pub const FEC_OVERHEAD: usize = 5;
+
+/// Loss rate assumed before anything was measured, so the first group is already protected
+const INITIAL_LOSS_RATE: f64 = 0.03;
+
+/// Below this loss rate no parity is sent at all
+const MIN_LOSS_RATE: f64 = 0.001;
+
+/// Weight of a new loss sample in the moving average
+const LOSS_RATE_GAIN: f64 = 0.25;
+
+/// Parity packets are sized for twice the measured loss, since losses come in bursts
+const PARITY_MARGIN: f64 = 2.0;
+
+/// How far behind the newest packet the receiver keeps data for a possible reconstruction
+const RETAIN_PACKETS: usize = 1024;
+
+/// Marks the end of the session in the length field of a shard
+const END_SESSION_LEN: u16 = u16::MAX;
+
+/// Size of the parity header after the sequence number and packet type: group size, parity count and index
+const PARITY_HEADER_SIZE: usize = 3;
+
+/// Packet types a shard can restore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardKind {
+    Write,
+    EndSession,
+}
+
+/// Encodes a packet as a shard: `[length u16][payload]`, the length of an end of session is `END_SESSION_LEN`.
+fn encode_shard(kind: ShardKind, payload: &[u8]) -> Vec<u8> {
+    let len = match kind {
+        ShardKind::Write => payload.len() as u16,
+        ShardKind::EndSession => END_SESSION_LEN,
+    };
+    let mut shard = Vec::with_capacity(2 + payload.len());
+    shard.extend_from_slice(&len.to_be_bytes());
+    shard.extend_from_slice(payload);
+    shard
+}
+
+fn decode_shard(shard: &[u8]) -> Option<(ShardKind, &[u8])> {
+    let len = u16::from_be_bytes(shard.get(..2)?.try_into().unwrap());
+    if len == END_SESSION_LEN {
+        return Some((ShardKind::EndSession, &[]));
+    }
+    Some((ShardKind::Write, shard.get(2..2 + len as usize)?))
+}
+
+/// Reed-Solomon codecs by group and parity size, setting one up is not free
+#[derive(Default)]
+struct Codecs(HashMap<(usize, usize), ReedSolomon>);
+
+impl Codecs {
+    fn get(&mut self, data: usize, parity: usize) -> &ReedSolomon {
+        self.0.entry((data, parity))
+            .or_insert_with(|| ReedSolomon::new(data, parity).expect("group and parity sizes are valid"))
+    }
+}
+
+/// Sender side of the forward error correction.
+///
+/// Collects the data packets of a group and computes parity packets for them once the group is complete.
+/// The number of parity packets follows the loss rate the sender observes.
+pub struct FecEncoder {
+    codecs: Codecs,
+    group_start: SeqNo,
+    shards: Vec<Vec<u8>>,
+    loss_rate: f64,
+    /// Losses reported since the last group was closed
+    losses: u64,
+}
+
+impl FecEncoder {
+    pub fn new() -> Self {
+        FecEncoder {
+            codecs: Codecs::default(),
+            group_start: 0,
+            shards: Vec::with_capacity(GROUP_SIZE),
+            loss_rate: INITIAL_LOSS_RATE,
+            losses: 0,
+        }
+    }
+
+    /// Number of parity packets for the next group.
+    pub fn parity_count(&self) -> usize {
+        if self.loss_rate < MIN_LOSS_RATE {
+            return 0;
+        }
+        ((self.loss_rate * GROUP_SIZE as f64 * PARITY_MARGIN).ceil() as usize).clamp(1, MAX_PARITY)
+    }
+
+    /// Records packets that were lost, or that the receiver had to rebuild.
+    pub fn on_loss(&mut self, packets: u64) {
+        self.losses += packets;
+    }
+
+    /// Number of packets still to come in the current group.
+    ///
+    /// A gap in the group may still be filled by its parity, which is only sent after them.
+    pub fn remaining_in_group(&self) -> usize {
+        if self.shards.is_empty() || self.parity_count() == 0 {
+            0
+        } else {
+            GROUP_SIZE - self.shards.len()
+        }
+    }
+
+    /// Adds a data packet (with its header) to the current group.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<Vec<u8>>` - The parity packets to send, once the group is complete.
+    pub fn push(&mut self, datagram: &[u8]) -> Vec<Vec<u8>> {
+        let seq = read_seq(datagram);
+        if self.shards.is_empty() {
+            self.group_start = seq;
+        }
+        let kind = if datagram[SEQ_SIZE] == super::PacketType::EndSession as u8 {
+            ShardKind::EndSession
+        } else {
+            ShardKind::Write
+        };
+        self.shards.push(encode_shard(kind, &datagram[SEQ_SIZE + 1..]));
+
+        if self.shards.len() == GROUP_SIZE {
+            self.finish_group()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Closes the current group even if it is not complete, e.g. before waiting for the last acknowledgments.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<Vec<u8>>` - The parity packets to send.
+    pub fn finish_group(&mut self) -> Vec<Vec<u8>> {
+        if self.shards.is_empty() {
+            return Vec::new();
+        }
+        let mut shards = std::mem::take(&mut self.shards);
+        let data_count = shards.len();
+
+        // every group is a sample of the loss rate
+        let sample = self.losses as f64 / data_count as f64;
+        self.loss_rate += LOSS_RATE_GAIN * (sample.min(1.0) - self.loss_rate);
+        self.losses = 0;
+
+        let parity_count = self.parity_count();
+        if parity_count == 0 {
+            return Vec::new();
+        }
+
+        let shard_len = shards.iter().map(Vec::len).max().unwrap_or_default();
+        for shard in &mut shards {
+            shard.resize(shard_len, 0);
+        }
+        let mut parity = vec![vec![0u8; shard_len]; parity_count];
+        self.codecs.get(data_count, parity_count)
+            .encode_sep(&shards, &mut parity)
+            .expect("shards have the same length");
+
+        parity.into_iter()
+            .enumerate()
+            .map(|(index, shard)| {
+                let mut datagram = Vec::with_capacity(SEQ_SIZE + 1 + PARITY_HEADER_SIZE + shard.len());
+                datagram.extend_from_slice(&self.group_start.to_be_bytes());
+                datagram.push(super::PacketType::Parity as u8);
+                datagram.extend_from_slice(&[data_count as u8, parity_count as u8, index as u8]);
+                datagram.extend_from_slice(&shard);
+                datagram
+            })
+            .collect()
+    }
+}
+
+/// Parity packets received for one group
+struct Group {
+    data_count: usize,
+    parity: Vec<Option<Vec<u8>>>,
+    complete: bool,
+}
+
+/// Receiver side of the forward error correction.
+///
+/// Keeps the recent data packets and the parity packets of their groups,
+/// and rebuilds data packets which got lost once enough of their group arrived.
+#[derive(Default)]
+pub struct FecDecoder {
+    codecs: Codecs,
+    /// Whether `newest` was set, by the first packet received
+    started: bool,
+    newest: SeqNo,
+    shards: HashMap<SeqNo, Vec<u8>>,
+    groups: HashMap<SeqNo, Group>,
+}
+
+impl FecDecoder {
+    /// Keeps a received data packet for the reconstruction of other packets of its group.
+    ///
+    /// The parity of a group follows its data, so data is kept from the first packet on.
+    pub fn add_data(&mut self, seq: SeqNo, kind: ShardKind, payload: &[u8]) {
+        self.advance(seq);
+        self.shards.entry(seq).or_insert_with(|| encode_shard(kind, payload));
+    }
+
+    /// Stores a received parity packet.
+    pub fn add_parity(&mut self, datagram: &[u8]) {
+        let Some(header) = datagram.get(SEQ_SIZE + 1..SEQ_SIZE + 1 + PARITY_HEADER_SIZE) else {
+            return;
+        };
+        let (data_count, parity_count, index) = (header[0] as usize, header[1] as usize, header[2] as usize);
+        if data_count == 0 || parity_count == 0 || index >= parity_count || data_count + parity_count > 256 {
+            return;
+        }
+        let start = read_seq(datagram);
+        self.advance(start);
+
+        let group = self.groups.entry(start).or_insert_with(|| Group {
+            data_count,
+            parity: vec![None; parity_count],
+            complete: false,
+        });
+        if group.data_count == data_count && group.parity.len() == parity_count {
+            group.parity[index] = Some(datagram[SEQ_SIZE + 1 + PARITY_HEADER_SIZE..].to_vec());
+        }
+    }
+
+    /// Rebuilds the data packets that can be restored from the parity received so far.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<(SeqNo, ShardKind, Vec<u8>)>` - Sequence number, type and payload of every rebuilt packet.
+    pub fn recover(&mut self) -> Vec<(SeqNo, ShardKind, Vec<u8>)> {
+        let mut recovered = Vec::new();
+        for (start, group) in self.groups.iter_mut().filter(|(_, group)| !group.complete) {
+            let seqs = (0..group.data_count).map(|index| start.wrapping_add(index as SeqNo));
+            let missing = seqs.clone().filter(|seq| !self.shards.contains_key(seq)).count();
+            if missing == 0 {
+                group.complete = true;
+                continue;
+            }
+            if missing > group.parity.iter().flatten().count() {
+                continue;
+            }
+
+            let Some(shard_len) = group.parity.iter().flatten().map(Vec::len).next() else {
+                continue;
+            };
+            let mut shards: Vec<Option<Vec<u8>>> = seqs.clone()
+                .map(|seq| self.shards.get(&seq).map(|shard| {
+                    let mut shard = shard.clone();
+                    shard.resize(shard_len, 0);
+                    shard
+                }))
+                .chain(group.parity.iter().cloned())
+                .collect();
+            let codec = self.codecs.get(group.data_count, group.parity.len());
+            if codec.reconstruct_data(&mut shards).is_err() {
+                continue;
+            }
+            group.complete = true;
+
+            for (seq, shard) in seqs.zip(shards) {
+                if self.shards.contains_key(&seq) {
+                    continue;
+                }
+                let Some((kind, payload)) = shard.as_deref().and_then(decode_shard) else {
+                    continue;
+                };
+                recovered.push((seq, kind, payload.to_vec()));
+            }
+        }
+
+        for (seq, kind, payload) in &recovered {
+            self.shards.insert(*seq, encode_shard(*kind, payload));
+        }
+        self.forget_old();
+        recovered
+    }
+
+    /// Moves the newest sequence number forward to `seq`, unless `seq` is an old packet.
+    fn advance(&mut self, seq: SeqNo) {
+        if !self.started {
+            self.started = true;
+            self.newest = seq;
+        } else if offset(self.newest, seq) < RETAIN_PACKETS {
+            self.newest = seq;
+        }
+    }
+
+    /// Drops data and groups too far behind the newest packet to still matter.
+    fn forget_old(&mut self) {
+        let newest = self.newest;
+        let is_recent = |seq: &SeqNo| offset(*seq, newest) < RETAIN_PACKETS;
+        self.shards.retain(|seq, _| is_recent(seq));
+        self.groups.retain(|start, _| is_recent(start));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PacketType;
+    use super::*;
+
+    fn datagram(seq: SeqNo, packet_type: PacketType, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = seq.to_be_bytes().to_vec();
+        datagram.push(packet_type as u8);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    fn lossy_encoder(loss_rate: f64) -> FecEncoder {
+        let mut encoder = FecEncoder::new();
+        encoder.loss_rate = loss_rate;
+        encoder
+    }
+
+    #[test]
+    fn test_parity_follows_loss_rate() {
+        assert_eq!(lossy_encoder(0.0).parity_count(), 0);
+        assert_eq!(lossy_encoder(0.01).parity_count(), 1);
+        assert_eq!(lossy_encoder(0.1).parity_count(), 4);
+        assert_eq!(lossy_encoder(0.9).parity_count(), MAX_PARITY);
+
+        // without losses the parity fades out
+        let mut encoder = FecEncoder::new();
+        for seq in 0..(GROUP_SIZE * 40) as SeqNo {
+            encoder.push(&datagram(seq, PacketType::Write, &[1, 2, 3]));
+        }
+        assert_eq!(encoder.parity_count(), 0);
+
+        encoder.on_loss(4);
+        encoder.push(&datagram(0, PacketType::Write, &[1]));
+        assert!(!encoder.finish_group().is_empty());
+    }
+
+    #[test]
+    fn test_recovers_lost_packets() {
+        let mut encoder = lossy_encoder(0.15);
+        let mut decoder = FecDecoder::default();
+        let start: SeqNo = SeqNo::MAX - 5; // the group wraps around the sequence space
+        let payloads: Vec<Vec<u8>> = (0..GROUP_SIZE).map(|index| vec![index as u8; 10 + index]).collect();
+
+        let mut parity = Vec::new();
+        for (index, payload) in payloads.iter().enumerate() {
+            parity = encoder.push(&datagram(start.wrapping_add(index as SeqNo), PacketType::Write, payload));
+        }
+        assert_eq!(parity.len(), 4);
+        assert!(parity.iter().all(|datagram| datagram[SEQ_SIZE] == PacketType::Parity as u8));
+
+        // lose 3 data packets and one parity packet
+        decoder.add_parity(&parity[0]);
+        for (index, payload) in payloads.iter().enumerate().filter(|(index, _)| ![2, 7, 15].contains(index)) {
+            decoder.add_data(start.wrapping_add(index as SeqNo), ShardKind::Write, payload);
+        }
+        assert!(decoder.recover().is_empty());
+        decoder.add_parity(&parity[2]);
+        decoder.add_parity(&parity[3]);
+
+        let mut recovered = decoder.recover();
+        recovered.sort_by_key(|(seq, _, _)| offset(start, *seq));
+        let expected: Vec<_> = [2, 7, 15].iter()
+            .map(|index| (start.wrapping_add(*index as SeqNo), ShardKind::Write, payloads[*index].clone()))
+            .collect();
+        assert_eq!(recovered, expected);
+        assert!(decoder.recover().is_empty());
+    }
+
+    #[test]
+    fn test_recovers_packet_of_the_first_group() {
+        let mut encoder = FecEncoder::new();
+        let mut decoder = FecDecoder::default();
+        let start: SeqNo = 1000;
+        let payloads: Vec<Vec<u8>> = (0..GROUP_SIZE).map(|index| vec![index as u8; 20]).collect();
+
+        // the data arrives before the parity, as the sender sends it
+        let mut parity = Vec::new();
+        for (index, payload) in payloads.iter().enumerate() {
+            let seq = start + index as SeqNo;
+            parity = encoder.push(&datagram(seq, PacketType::Write, payload));
+            if index != 3 {
+                decoder.add_data(seq, ShardKind::Write, payload);
+            }
+        }
+        assert!(!parity.is_empty());
+        for datagram in &parity {
+            decoder.add_parity(datagram);
+        }
+        assert_eq!(decoder.recover(), vec![(start + 3, ShardKind::Write, payloads[3].clone())]);
+    }
+
+    #[test]
+    fn test_recovers_end_of_session_in_partial_group() {
+        let mut encoder = lossy_encoder(0.1);
+        let mut decoder = FecDecoder::default();
+        encoder.push(&datagram(0, PacketType::Write, b"data"));
+        encoder.push(&datagram(1, PacketType::EndSession, &[]));
+        let parity = encoder.finish_group();
+
+        decoder.add_parity(&parity[0]);
+        decoder.add_data(0, ShardKind::Write, b"data");
+        assert_eq!(decoder.recover(), vec![(1, ShardKind::EndSession, Vec::new())]);
+    }
+}
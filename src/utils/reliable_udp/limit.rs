@@ -0,0 +1,173 @@
+use std::sync::Mutex;
+
+use crate::utils::rate::{Rate, RateSchedule};
+
+/// The bucket holds at most this much sending time worth of tokens
+const BURST_MICROS: f64 = 50_000.0;
+
+/// The bucket can always hold at least one datagram
+const MIN_BURST_BYTES: f64 = 65_536.0;
+
+/// How often the schedule is checked for a new rate
+const SCHEDULE_CHECK_MICROS: u64 = 1_000_000;
+
+/// Token bucket counting bytes, refilled at a fixed rate.
+///
+/// Reservations may take more tokens than available, the debt is paid by waiting.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: u64,
+    tokens: f64,
+    updated_at: u64,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket refilled with `rate` bytes per second.
+    pub fn new(rate: u64, now: u64) -> Self {
+        let mut bucket = TokenBucket { rate, tokens: 0.0, updated_at: now };
+        bucket.tokens = bucket.burst();
+        bucket
+    }
+
+    fn burst(&self) -> f64 {
+        (self.rate as f64 * BURST_MICROS / 1_000_000.0).max(MIN_BURST_BYTES)
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.tokens = (self.tokens + elapsed * self.rate as f64 / 1_000_000.0).min(self.burst());
+        self.updated_at = self.updated_at.max(now);
+    }
+
+    /// Changes the refill rate, tokens collected so far are kept.
+    pub fn set_rate(&mut self, rate: u64, now: u64) {
+        self.refill(now);
+        self.rate = rate;
+    }
+
+    /// Takes `bytes` tokens and returns how many microseconds to wait before sending them.
+    pub fn reserve(&mut self, bytes: usize, now: u64) -> u64 {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            0
+        } else {
+            (-self.tokens * 1_000_000.0 / self.rate as f64) as u64
+        }
+    }
+
+    /// Takes `bytes` tokens if they are available, without going into debt.
+    pub fn try_take(&mut self, bytes: usize, now: u64) -> bool {
+        self.refill(now);
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+}
+
+/// Limits the sending rate of all streams of a transfer together.
+///
+/// The rate follows a schedule, capped by the rate the receiver asked for.
+pub struct RateLimiter {
+    schedule: RateSchedule,
+    cap: Rate,
+    state: Mutex<LimiterState>,
+}
+
+struct LimiterState {
+    /// `None` while the rate is unlimited
+    bucket: Option<TokenBucket>,
+    checked_at: Option<u64>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: RateSchedule, cap: Rate) -> Self {
+        RateLimiter {
+            schedule,
+            cap,
+            state: Mutex::new(LimiterState { bucket: None, checked_at: None }),
+        }
+    }
+
+    /// The rate which applies right now.
+    pub fn current_rate(&self) -> Rate {
+        self.schedule.current_rate().min(self.cap)
+    }
+
+    /// Takes `bytes` from the shared budget and returns how many microseconds to wait before sending them.
+    pub fn reserve(&self, bytes: usize, now: u64) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let due = state.checked_at.is_none_or(|checked_at| now.saturating_sub(checked_at) >= SCHEDULE_CHECK_MICROS);
+        if due {
+            state.checked_at = Some(now);
+            state.bucket = match (self.current_rate().bytes_per_sec(), state.bucket.take()) {
+                (None, _) => None,
+                (Some(rate), Some(mut bucket)) => {
+                    bucket.set_rate(rate, now);
+                    Some(bucket)
+                }
+                (Some(rate), None) => Some(TokenBucket::new(rate, now)),
+            };
+        }
+
+        state.bucket.as_mut().map_or(0, |bucket| bucket.reserve(bytes, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_burst_then_waits() {
+        let mut bucket = TokenBucket::new(1_000_000, 0);
+        // 65536 bytes burst
+        assert_eq!(bucket.reserve(65_536, 0), 0);
+        // 1000 bytes at 1 MB/s take 1ms
+        assert_eq!(bucket.reserve(1_000, 0), 1_000);
+        // after the debt is paid, the next reservation waits for itself only
+        assert_eq!(bucket.reserve(1_000, 1_000), 1_000);
+    }
+
+    #[test]
+    fn test_bucket_try_take_never_goes_into_debt() {
+        let mut bucket = TokenBucket::new(1_000_000, 0);
+        assert!(bucket.try_take(65_000, 0));
+        assert!(!bucket.try_take(1_000, 0));
+        // the refused bytes were not taken, 1ms refills them
+        assert!(bucket.try_take(1_000, 1_000));
+    }
+
+    #[test]
+    fn test_bucket_average_rate() {
+        let mut bucket = TokenBucket::new(5_000_000, 0);
+        let mut now = 0;
+        let mut sent = 0u64;
+        // send back to back for one simulated second
+        while now < 1_000_000 {
+            now += bucket.reserve(1_400, now);
+            sent += 1_400;
+        }
+        // one second at the rate plus the initial burst of 50ms
+        let expected = 5_000_000 + 250_000;
+        assert!(sent.abs_diff(expected) < 10_000, "sent {} bytes", sent);
+    }
+
+    #[test]
+    fn test_limiter_is_shared_and_capped() {
+        let limiter = RateLimiter::new(RateSchedule::fixed(Rate(Some(10_000_000))), Rate(Some(1_000_000)));
+        assert_eq!(limiter.current_rate(), Rate(Some(1_000_000)));
+
+        // two streams draw from the same bucket
+        assert_eq!(limiter.reserve(65_536, 0), 0);
+        assert_eq!(limiter.reserve(500, 0), 500);
+        assert_eq!(limiter.reserve(500, 0), 1_000);
+
+        let unlimited = RateLimiter::new(RateSchedule::fixed(Rate::UNLIMITED), Rate::UNLIMITED);
+        assert_eq!(unlimited.reserve(1_000_000, 0), 0);
+    }
+}
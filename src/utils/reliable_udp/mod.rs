@@ -0,0 +1,733 @@
+use std::mem;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::{current_unix_micros, current_unix_millis};
+
+use self::batch::{RecvBatch, SendBatch, SendPath};
+use self::congestion::CongestionController;
+use self::fec::{FecDecoder, FecEncoder, ShardKind};
+use self::limit::RateLimiter;
+use self::stats::TransferStats;
+use self::window::{Accepted, decode_recovered, decode_sack_ranges, DEFAULT_WINDOW_SIZE, encode_sack_ranges, read_seq, ReceiveWindow, SendWindow, SeqNo, SEQ_SIZE};
+
+pub mod batch;
+pub mod congestion;
+pub mod fec;
+pub mod limit;
+pub mod stats;
+pub mod window;
+
+/// Size of the packet header: 4 bytes sequence number, 1 byte packet type
+pub const HEADER_SIZE: usize = SEQ_SIZE + 1;
+
+/// How long a single wait for acknowledgments may block before checking for timeouts
+const FEEDBACK_POLL_MILLIS: u64 = 100;
+
+/// Sessions which exchange metadata start half way through the sequence space,
+/// so late duplicates of their packets cannot be taken for packets of the transfer after them
+const METADATA_SEQ: SeqNo = 1 << (SeqNo::BITS - 1);
+
+/// What a metadata session carries.
+///
+/// Every kind starts at its own sequence number, so sessions of different kinds which follow
+/// each other in the same direction cannot mix up their packets either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataKind {
+    Signature = 0,
+    HashTree = 1,
+    Attributes = 2,
+}
+
+impl MetadataKind {
+    fn start_seq(self) -> SeqNo {
+        METADATA_SEQ + ((self as SeqNo) << 24)
+    }
+}
+
+#[derive(Ord, Eq, PartialOrd, PartialEq, Clone, Copy)]
+enum PacketType {
+    Write = 0,
+    Acknowledgment = 1,
+    EndSession = 3,
+    Parity = 4,
+}
+
+/// Handles reliable data transmission over UDP with a selective-repeat sliding window.
+///
+/// The receiver buffers packets arriving out of order and answers every data packet with
+/// a cumulative acknowledgment plus SACK ranges, so the sender only retransmits the gaps.
+/// How fast packets are sent is decided by a delay-based congestion controller,
+/// which measures the RTT from these acknowledgments.
+/// Outgoing packets are handed to the kernel in batches where the `SendPath` allows it.
+/// Optionally, the sender adds parity packets, so the receiver can rebuild lost packets without a retransmit.
+/// Heavily inspired by SafeReadWrite from https://github.com/TudbuT/qft/blob/master/src/main.rs
+pub struct ReliableUdpSocket {
+    socket: UdpSocket,
+    batch: SendBatch,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    send_window: SendWindow,
+    congestion: CongestionController,
+    receive_window: ReceiveWindow,
+    /// Set if parity packets are sent
+    fec: Option<FecEncoder>,
+    /// Set if the peer announced parity packets
+    fec_decoder: Option<FecDecoder>,
+    /// Highest count of rebuilt packets the receiver reported
+    peer_recovered: u32,
+    /// Created by the first `read`, once the packet size is known
+    recv_batch: Option<RecvBatch>,
+    /// Payload buffers to receive into, recycled from `read`
+    free_buffers: Vec<Vec<u8>>,
+    stats: TransferStats,
+}
+
+impl ReliableUdpSocket {
+    /// Creates a new instance bound to the provided UDP socket.
+    pub fn new(socket: UdpSocket) -> Self {
+        Self::with_send_path(socket, SendPath::Auto)
+    }
+
+    /// Creates a new instance which sends its packets through the given `SendPath`.
+    pub fn with_send_path(socket: UdpSocket, send_path: SendPath) -> Self {
+        ReliableUdpSocket {
+            socket,
+            batch: SendBatch::new(send_path),
+            rate_limiter: None,
+            send_window: SendWindow::new(DEFAULT_WINDOW_SIZE),
+            congestion: CongestionController::new(DEFAULT_WINDOW_SIZE),
+            receive_window: ReceiveWindow::new(DEFAULT_WINDOW_SIZE),
+            fec: None,
+            fec_decoder: None,
+            peer_recovered: 0,
+            recv_batch: None,
+            free_buffers: Vec::new(),
+            stats: TransferStats::default(),
+        }
+    }
+
+    /// Safely writes data to the socket with an optional flush.
+    ///
+    /// Without `should_flush` the call only blocks while the congestion window is full
+    /// or the pacing requires a gap. `delay` is the minimum gap between two packets
+    /// in microseconds (0 to leave pacing entirely to the congestion controller).
+    pub fn write_and_flush(&mut self, data: &[u8], should_flush: bool, delay: u64) -> Result<()> {
+        self.internal_write(data, PacketType::Write, should_flush, false, delay)
+    }
+
+    /// Reads the next packet in order, buffering packets which arrive ahead of it.
+    ///
+    /// The packet replaces the contents of `buffer`. The previous allocation of `buffer` is kept
+    /// to receive later packets into, so a caller cycling its buffers causes no allocations.
+    /// `max_len` is the largest payload the peer sends.
+    ///
+    /// Returns the length of the packet; 0 means the sender ended the session.
+    pub fn read(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<usize> {
+        if max_len > 0xfffc {
+            return Err(NudgeError::BufferSizeLimitExceeded(max_len));
+        }
+        let slot_size = max_len + HEADER_SIZE;
+        if self.recv_batch.as_ref().map(RecvBatch::slot_size) != Some(slot_size) {
+            self.recv_batch = Some(RecvBatch::new(slot_size));
+        }
+
+        loop {
+            if let Some((packet_type, mut payload)) = self.receive_window.pop_ready() {
+                if packet_type == PacketType::EndSession as u8 {
+                    return Ok(0);
+                }
+                let len = payload.len();
+                self.stats.packets_received += 1;
+                self.stats.bytes_received += len as u64;
+
+                mem::swap(buffer, &mut payload);
+                self.free_buffers.push(payload);
+                return Ok(len);
+            }
+
+            let recv_batch = self.recv_batch.as_mut().expect("receive batch is initialized above");
+            if recv_batch.receive(&self.socket).is_err() {
+                continue;
+            }
+
+            let mut should_acknowledge = false;
+            for datagram in recv_batch.datagrams() {
+                if datagram.len() < HEADER_SIZE {
+                    continue;
+                }
+                let packet_type = datagram[SEQ_SIZE];
+                if packet_type == PacketType::Parity as u8 {
+                    if let Some(fec_decoder) = &mut self.fec_decoder {
+                        fec_decoder.add_parity(datagram);
+                    }
+                    continue;
+                }
+                if packet_type != PacketType::Write as u8 && packet_type != PacketType::EndSession as u8 {
+                    continue;
+                }
+                should_acknowledge = true;
+
+                let seq = read_seq(datagram);
+                if let Some(fec_decoder) = &mut self.fec_decoder {
+                    let kind = if packet_type == PacketType::EndSession as u8 { ShardKind::EndSession } else { ShardKind::Write };
+                    fec_decoder.add_data(seq, kind, &datagram[HEADER_SIZE..]);
+                }
+                let mut payload = self.free_buffers.pop().unwrap_or_default();
+                payload.clear();
+                payload.extend_from_slice(&datagram[HEADER_SIZE..]);
+                match self.receive_window.accept(seq, packet_type, payload) {
+                    Accepted::Duplicate => self.stats.duplicates += 1,
+                    Accepted::OutOfWindow => {
+                        debug!("Dropped packet {} which is ahead of the receive window", seq);
+                        self.stats.out_of_window += 1;
+                    }
+                    Accepted::Buffered => {}
+                }
+            }
+
+            let recovered = self.fec_decoder.as_mut().map(FecDecoder::recover).unwrap_or_default();
+            for (seq, kind, payload) in recovered {
+                let packet_type = match kind {
+                    ShardKind::Write => PacketType::Write,
+                    ShardKind::EndSession => PacketType::EndSession,
+                };
+                if self.receive_window.accept(seq, packet_type as u8, payload) == Accepted::Buffered {
+                    self.stats.fec_recovered += 1;
+                    should_acknowledge = true;
+                }
+            }
+
+            // one acknowledgment covers the whole batch
+            if should_acknowledge {
+                self.send_ack()?;
+            }
+        }
+    }
+
+    /// Sends `bytes` in a session of their own, e.g. metadata the peer needs before the transfer.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the socket fails
+    pub fn send_metadata(socket: UdpSocket, kind: MetadataKind, bytes: &[u8], chunk_size: usize) -> Result<()> {
+        let mut safe_connection = ReliableUdpSocket::new(socket);
+        safe_connection.send_window.start_at(kind.start_seq());
+        for chunk in bytes.chunks(chunk_size) {
+            safe_connection.write_and_flush(chunk, false, 0)?;
+        }
+        safe_connection.end();
+        Ok(())
+    }
+
+    /// Receives the bytes of a session started with `send_metadata`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the socket fails
+    pub fn receive_metadata(socket: UdpSocket, kind: MetadataKind, chunk_size: usize) -> Result<Vec<u8>> {
+        let mut safe_connection = ReliableUdpSocket::new(socket);
+        safe_connection.receive_window.start_at(kind.start_seq());
+        let mut bytes = Vec::new();
+        let mut buffer = Vec::new();
+        while safe_connection.read(&mut buffer, chunk_size)? > 0 {
+            bytes.extend_from_slice(&buffer);
+        }
+        Ok(bytes)
+    }
+
+    /// Limits the sending rate, the limiter may be shared with other sockets of the same transfer.
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Sends parity packets, so the receiver can rebuild lost packets without waiting for a retransmit.
+    ///
+    /// The share of parity packets follows the observed loss rate.
+    pub fn with_fec(mut self) -> Self {
+        self.fec = Some(FecEncoder::new());
+        self
+    }
+
+    /// Rebuilds lost packets from the parity packets of a peer which sends `with_fec`.
+    ///
+    /// Without it, parity packets are ignored and received packets are not kept for a reconstruction.
+    pub fn with_fec_decoder(mut self) -> Self {
+        self.fec_decoder = Some(FecDecoder::default());
+        self
+    }
+
+    /// The send path in use, see `SendBatch::path`.
+    pub fn send_path(&self) -> SendPath {
+        self.batch.path()
+    }
+
+    /// Returns the statistics of the session so far.
+    pub fn stats(&self) -> &TransferStats {
+        &self.stats
+    }
+
+    /// Ends the session, ensuring all data is flushed and the socket is properly closed.
+    ///
+    /// Returns the statistics of the whole session.
+    pub fn end(mut self) -> TransferStats {
+        let _ = self.internal_write(&[], PacketType::EndSession, true, true, 0);
+        self.stats
+    }
+
+    /// Internal method to handle packet writing with retries and error handling.
+    fn internal_write(
+        &mut self,
+        data: &[u8],
+        packet_type: PacketType,
+        flush: bool,
+        exit_on_lost: bool,
+        delay: u64,
+    ) -> Result<()> {
+        if data.len() > 0xfffc {
+            return Err(NudgeError::DataPacketLimitExceeded(data.len()));
+        }
+
+        // Wait until the congestion window has room
+        while self.send_window.is_full() || self.send_window.len() >= self.congestion.window() {
+            self.poll_feedback(true)?;
+        }
+        self.wait_for_pacing(data.len() + HEADER_SIZE)?;
+
+        let mut data_buffer = Vec::with_capacity(data.len() + HEADER_SIZE);
+        data_buffer.extend_from_slice(&self.send_window.next_seq().to_be_bytes());
+        data_buffer.push(packet_type as u8);
+        data_buffer.extend_from_slice(data);
+
+        self.batch.push(&self.socket, &data_buffer)?;
+        let now = current_unix_micros();
+        self.congestion.on_packet_sent(now, delay);
+        let parity = self.fec.as_mut().map(|fec| fec.push(&data_buffer)).unwrap_or_default();
+        // a gap may still be filled by the parity of its group, which is sent after the rest of the group
+        let hold = self.fec.as_ref().map_or(0, FecEncoder::remaining_in_group);
+        self.send_window.push(data_buffer, now, hold);
+        if packet_type == PacketType::Write {
+            self.stats.packets_sent += 1;
+            self.stats.bytes_sent += data.len() as u64;
+        }
+        self.send_parity(parity, delay)?;
+
+        // Process acknowledgments which already arrived without blocking
+        self.poll_feedback(false)?;
+
+        if flush {
+            self.wait_for_acknowledgment(exit_on_lost)?;
+        }
+        Ok(())
+    }
+
+    /// Sleeps until the congestion controller and the rate limit allow the next packet of `len` bytes to be sent.
+    ///
+    /// Packets queued for batching are sent before sleeping.
+    fn wait_for_pacing(&mut self, len: usize) -> Result<()> {
+        let now = current_unix_micros();
+        let rate_limited = self.rate_limiter.as_ref().map_or(0, |limiter| limiter.reserve(len, now));
+        let wait = self.congestion.time_until_send(now).max(rate_limited);
+        if wait > self.batch.pacing_slack() {
+            self.batch.flush(&self.socket)?;
+            thread::sleep(Duration::from_micros(wait));
+        }
+        Ok(())
+    }
+
+    /// Sends the parity packets of a group, paced like data packets but not tracked in the send window.
+    fn send_parity(&mut self, datagrams: Vec<Vec<u8>>, delay: u64) -> Result<()> {
+        for datagram in datagrams {
+            self.wait_for_pacing(datagram.len())?;
+            self.batch.push(&self.socket, &datagram)?;
+            self.congestion.on_packet_sent(current_unix_micros(), delay);
+            self.stats.parity_sent += 1;
+        }
+        Ok(())
+    }
+
+    /// Blocks until every packet in the send window was acknowledged.
+    fn wait_for_acknowledgment(&mut self, exit_on_lost: bool) -> Result<()> {
+        // the last packets are protected as well, even if their group is not complete
+        if let Some(parity) = self.fec.as_mut().map(FecEncoder::finish_group) {
+            self.send_parity(parity, 0)?;
+        }
+        let mut last_progress = current_unix_millis();
+        let mut warned = false;
+
+        while !self.send_window.is_empty() {
+            let remaining = self.send_window.len();
+            self.poll_feedback(true)?;
+            if self.send_window.len() < remaining {
+                last_progress = current_unix_millis();
+                warned = false;
+                continue;
+            }
+
+            let stalled_for = current_unix_millis() - last_progress;
+            if stalled_for > 5000 && exit_on_lost {
+                println!("WARN: No acknowledgment received within 5 seconds, potential packet loss");
+                break; // Exit if no response and exiting on loss is specified.
+            }
+            if stalled_for > 10000 && !warned {
+                println!("WARN: Connection may be disrupted. It's been 10 seconds since the last packet was acknowledged. Resending...");
+                warned = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes acknowledgments from the receiver and retransmits packets which were lost.
+    ///
+    /// If `block` is set, waits up to `FEEDBACK_POLL_MILLIS` for the first acknowledgment,
+    /// otherwise only drains what already arrived.
+    fn poll_feedback(&mut self, block: bool) -> Result<()> {
+        if block {
+            // the acknowledgments we wait for may depend on packets still queued
+            self.batch.flush(&self.socket)?;
+            self.socket.set_read_timeout(Some(Duration::from_millis(FEEDBACK_POLL_MILLIS)))?;
+        } else {
+            self.socket.set_nonblocking(true)?;
+        }
+
+        let mut buffer = [0u8; HEADER_SIZE + 1 + window::MAX_SACK_RANGES * 2 * SEQ_SIZE + size_of::<u32>()];
+        // stops once the wait timed out or nothing is left to drain
+        while let Ok(bytes_read) = self.socket.recv(&mut buffer) {
+            if bytes_read < HEADER_SIZE || buffer[SEQ_SIZE] != PacketType::Acknowledgment as u8 {
+                continue;
+            }
+            let cumulative = read_seq(&buffer);
+            let ranges = decode_sack_ranges(&buffer[HEADER_SIZE..bytes_read]);
+            if let (Some(fec), Some(recovered)) = (&mut self.fec, decode_recovered(&buffer[HEADER_SIZE..bytes_read])) {
+                // packets which had to be rebuilt were lost as well
+                fec.on_loss(recovered.saturating_sub(self.peer_recovered) as u64);
+                self.peer_recovered = self.peer_recovered.max(recovered);
+            }
+            let outcome = self.send_window.on_ack(cumulative, &ranges, current_unix_micros());
+            self.congestion.on_ack(outcome.acked, outcome.rtt_sample);
+            self.stats.acks_received += 1;
+            if let Some(sample) = outcome.rtt_sample {
+                self.stats.record_rtt(sample);
+            }
+
+            // after the first acknowledgment, only drain the rest
+            if block {
+                self.socket.set_nonblocking(true)?;
+            }
+        }
+        self.socket.set_nonblocking(false)?;
+
+        let now = current_unix_micros();
+        let retransmissions = self.send_window.take_retransmissions(now, self.congestion.rtt().rto());
+        if retransmissions.timed_out > 0 {
+            self.congestion.on_timeout(now);
+        } else if retransmissions.lost > 0 {
+            self.congestion.on_loss(now);
+        }
+        self.stats.timeouts += retransmissions.timed_out as u64;
+        self.stats.resend_requests += retransmissions.lost as u64;
+        self.stats.retransmissions += retransmissions.datagrams.len() as u64;
+        if let Some(fec) = &mut self.fec {
+            fec.on_loss((retransmissions.lost + retransmissions.timed_out) as u64);
+        }
+        if !retransmissions.datagrams.is_empty() {
+            for datagram in &retransmissions.datagrams {
+                // retransmissions are not delayed, but count against the rate limit of later packets
+                if let Some(limiter) = &self.rate_limiter {
+                    limiter.reserve(datagram.len(), now);
+                }
+                self.batch.push(&self.socket, datagram)?;
+            }
+            self.batch.flush(&self.socket)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a cumulative acknowledgment together with the SACK ranges of buffered packets.
+    fn send_ack(&mut self) -> Result<()> {
+        let (cumulative, ranges) = self.receive_window.ack();
+        let mut packet = Vec::with_capacity(HEADER_SIZE + 1 + ranges.len() * 2 * SEQ_SIZE + size_of::<u32>());
+        packet.extend_from_slice(&cumulative.to_be_bytes());
+        packet.push(PacketType::Acknowledgment as u8);
+        packet.extend_from_slice(&encode_sack_ranges(&ranges));
+        packet.extend_from_slice(&(self.stats.fec_recovered as u32).to_be_bytes());
+        self.socket.send(&packet)?;
+        self.stats.acks_sent += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn test_reliable_udp_socket_new() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let reliable_socket = ReliableUdpSocket::new(socket.try_clone().unwrap());
+        assert_eq!(reliable_socket.stats().packets_sent, 0);
+        assert_eq!(reliable_socket.stats().packets_received, 0);
+    }
+
+    #[test]
+    fn test_internal_write_data_packet_limit_exceeded() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut reliable_socket = ReliableUdpSocket::new(socket.try_clone().unwrap());
+
+        let large_data = vec![0u8; 0x10000];
+        let result = reliable_socket.internal_write(&large_data, PacketType::Write, false, false, 10);
+        assert!(matches!(result, Err(NudgeError::DataPacketLimitExceeded(_))));
+    }
+
+    #[test]
+    fn test_transfer_over_loopback() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+        let receiver = thread::spawn(move || {
+            let mut reliable_socket = ReliableUdpSocket::new(receiver_socket);
+            let mut data = Vec::new();
+            let mut received = Vec::new();
+            loop {
+                let len = reliable_socket.read(&mut data, 64).unwrap();
+                if len == 0 {
+                    break;
+                }
+                received.push(data[0]);
+            }
+            (received, reliable_socket.stats().clone())
+        });
+
+        let mut reliable_socket = ReliableUdpSocket::new(sender_socket);
+        for i in 0..=255u8 {
+            reliable_socket.write_and_flush(&[i; 32], false, 0).unwrap();
+        }
+        let sender_stats = reliable_socket.end();
+
+        let (received, receiver_stats) = receiver.join().unwrap();
+        assert_eq!(received, (0..=255u8).collect::<Vec<_>>());
+        assert_eq!(sender_stats.packets_sent, 256);
+        assert_eq!(sender_stats.bytes_sent, 256 * 32);
+        assert!(sender_stats.rtt_samples > 0);
+        assert_eq!(receiver_stats.packets_received, 256);
+        assert_eq!(receiver_stats.bytes_received, 256 * 32);
+        assert!(receiver_stats.acks_sent > 0);
+    }
+
+    #[test]
+    fn test_transfer_without_fec_leaves_decoder_empty() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+        let receiver = thread::spawn(move || {
+            let mut reliable_socket = ReliableUdpSocket::new(receiver_socket);
+            let mut data = Vec::new();
+            while reliable_socket.read(&mut data, 64).unwrap() > 0 {}
+            (reliable_socket.fec_decoder.is_none(), reliable_socket.stats().packets_received)
+        });
+
+        let mut reliable_socket = ReliableUdpSocket::new(sender_socket);
+        for i in 0..100u8 {
+            reliable_socket.write_and_flush(&[i; 40], false, 0).unwrap();
+        }
+        reliable_socket.end();
+
+        assert_eq!(receiver.join().unwrap(), (true, 100));
+    }
+
+    #[test]
+    fn test_transfer_with_fec() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+        let receiver = thread::spawn(move || {
+            let mut reliable_socket = ReliableUdpSocket::new(receiver_socket).with_fec_decoder();
+            let mut data = Vec::new();
+            let mut received = Vec::new();
+            while reliable_socket.read(&mut data, 64).unwrap() > 0 {
+                received.push(data[0]);
+            }
+            received
+        });
+
+        let mut reliable_socket = ReliableUdpSocket::new(sender_socket).with_fec();
+        for i in 0..100u8 {
+            reliable_socket.write_and_flush(&[i; 40], false, 0).unwrap();
+        }
+        let sender_stats = reliable_socket.end();
+
+        assert_eq!(receiver.join().unwrap(), (0..100u8).collect::<Vec<_>>());
+        // the initial loss estimate protects the first groups
+        assert!(sender_stats.parity_sent > 0);
+    }
+
+    #[test]
+    fn test_metadata_before_transfer() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+        let receiver = thread::spawn(move || {
+            let hash_tree = ReliableUdpSocket::receive_metadata(receiver_socket.try_clone().unwrap(), MetadataKind::HashTree, 64).unwrap();
+            let attributes = ReliableUdpSocket::receive_metadata(receiver_socket.try_clone().unwrap(), MetadataKind::Attributes, 64).unwrap();
+            let mut reliable_socket = ReliableUdpSocket::new(receiver_socket);
+            let mut data = Vec::new();
+            assert_eq!(reliable_socket.read(&mut data, 64).unwrap(), 3);
+            (hash_tree, attributes, data)
+        });
+
+        // a late duplicate of a metadata packet must not show up in the sessions after it
+        let send_duplicate = |kind: MetadataKind| {
+            let mut duplicate = kind.start_seq().to_be_bytes().to_vec();
+            duplicate.push(PacketType::Write as u8);
+            duplicate.extend_from_slice(&[9; 10]);
+            sender_socket.send(&duplicate).unwrap();
+        };
+        ReliableUdpSocket::send_metadata(sender_socket.try_clone().unwrap(), MetadataKind::HashTree, &[7; 100], 64).unwrap();
+        send_duplicate(MetadataKind::HashTree);
+        ReliableUdpSocket::send_metadata(sender_socket.try_clone().unwrap(), MetadataKind::Attributes, &[8; 10], 64).unwrap();
+        send_duplicate(MetadataKind::Attributes);
+
+        let mut reliable_socket = ReliableUdpSocket::new(sender_socket);
+        reliable_socket.write_and_flush(&[1, 2, 3], true, 0).unwrap();
+
+        let (hash_tree, attributes, data) = receiver.join().unwrap();
+        assert_eq!(hash_tree, vec![7; 100]);
+        assert_eq!(attributes, vec![8; 10]);
+        assert_eq!(data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_fec_rebuilds_lost_packets() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let proxy_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (sender_addr, receiver_addr) = (sender_socket.local_addr().unwrap(), receiver_socket.local_addr().unwrap());
+        sender_socket.connect(proxy_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(proxy_socket.local_addr().unwrap()).unwrap();
+
+        // forwards everything, except for the first transmission of every 20th data packet
+        let proxy = thread::spawn(move || {
+            proxy_socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let mut seen = std::collections::HashSet::new();
+            let mut buffer = [0u8; 2048];
+            while let Ok((len, from)) = proxy_socket.recv_from(&mut buffer) {
+                let datagram = &buffer[..len];
+                if from == receiver_addr {
+                    proxy_socket.send_to(datagram, sender_addr).unwrap();
+                    continue;
+                }
+                let seq = read_seq(datagram);
+                if datagram[SEQ_SIZE] == PacketType::Write as u8 && seq % 20 == 7 && seen.insert(seq) {
+                    continue;
+                }
+                proxy_socket.send_to(datagram, receiver_addr).unwrap();
+            }
+        });
+
+        let receiver = thread::spawn(move || {
+            let mut reliable_socket = ReliableUdpSocket::new(receiver_socket).with_fec_decoder();
+            let mut data = Vec::new();
+            let mut received = Vec::new();
+            while reliable_socket.read(&mut data, 64).unwrap() > 0 {
+                received.push(data[0]);
+            }
+            (received, reliable_socket.stats().clone())
+        });
+
+        let mut reliable_socket = ReliableUdpSocket::new(sender_socket).with_fec();
+        for i in 0..=255u8 {
+            reliable_socket.write_and_flush(&[i; 40], false, 0).unwrap();
+        }
+        reliable_socket.end();
+
+        let (received, receiver_stats) = receiver.join().unwrap();
+        proxy.join().unwrap();
+        assert_eq!(received, (0..=255u8).collect::<Vec<_>>());
+        assert!(receiver_stats.fec_recovered > 0);
+    }
+
+    #[test]
+    fn test_transfer_beyond_u16_sequence_numbers() {
+        let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+        receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+        const PACKETS: u32 = 0x10000 + 1000;
+
+        let receiver = thread::spawn(move || {
+            let mut reliable_socket = ReliableUdpSocket::new(receiver_socket);
+            let mut data = Vec::new();
+            let mut expected = 0u32;
+            loop {
+                let len = reliable_socket.read(&mut data, 4).unwrap();
+                if len == 0 {
+                    break;
+                }
+                assert_eq!(data, expected.to_be_bytes());
+                expected += 1;
+            }
+            expected
+        });
+
+        let mut reliable_socket = ReliableUdpSocket::new(sender_socket);
+        for i in 0..PACKETS {
+            reliable_socket.write_and_flush(&i.to_be_bytes(), false, 0).unwrap();
+        }
+        reliable_socket.end();
+
+        assert_eq!(receiver.join().unwrap(), PACKETS);
+    }
+
+    /// Compares the throughput of the send paths over loopback:
+    /// `cargo test --release -- --ignored --nocapture bench_send_paths`
+    #[test]
+    #[ignore]
+    fn bench_send_paths() {
+        const PACKETS: usize = 32 * 1024;
+        const CHUNK_SIZE: usize = 8192;
+
+        for path in [SendPath::Single, SendPath::Sendmmsg, SendPath::Gso] {
+            let sender_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            sender_socket.connect(receiver_socket.local_addr().unwrap()).unwrap();
+            receiver_socket.connect(sender_socket.local_addr().unwrap()).unwrap();
+
+            let receiver = thread::spawn(move || {
+                let mut reliable_socket = ReliableUdpSocket::new(receiver_socket);
+                let mut data = Vec::new();
+                while reliable_socket.read(&mut data, CHUNK_SIZE).unwrap() > 0 {}
+            });
+
+            let started = std::time::Instant::now();
+            let mut reliable_socket = ReliableUdpSocket::with_send_path(sender_socket, path);
+            let chunk = [0u8; CHUNK_SIZE];
+            for _ in 0..PACKETS {
+                reliable_socket.write_and_flush(&chunk, false, 0).unwrap();
+            }
+            let stats = reliable_socket.end();
+            receiver.join().unwrap();
+
+            let elapsed = started.elapsed().as_secs_f64();
+            println!(
+                "{:?}: {:.0} MB/s, {} retransmissions",
+                path,
+                (PACKETS * CHUNK_SIZE) as f64 / elapsed / 1_000_000.0,
+                stats.retransmissions
+            );
+        }
+    }
+}
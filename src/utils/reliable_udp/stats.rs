@@ -0,0 +1,127 @@
+use serde::Serialize;
+
+/// Counters collected by a `ReliableUdpSocket` during one session.
+///
+/// Sender and receiver fill different counters, the ones which do not apply stay 0.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct TransferStats {
+    /// Data packets sent for the first time
+    pub packets_sent: u64,
+    /// Data packets delivered in order to the reader
+    pub packets_received: u64,
+    /// Payload bytes sent for the first time
+    pub bytes_sent: u64,
+    /// Payload bytes delivered in order to the reader
+    pub bytes_received: u64,
+    /// Packets sent again, for any reason
+    pub retransmissions: u64,
+    /// Gaps the receiver reported through SACK ranges
+    pub resend_requests: u64,
+    /// Retransmission timers which expired
+    pub timeouts: u64,
+    /// Packets the receiver already had
+    pub duplicates: u64,
+    /// Packets the receiver dropped because they were ahead of its window
+    pub out_of_window: u64,
+    /// Acknowledgments sent by the receiver
+    pub acks_sent: u64,
+    /// Acknowledgments processed by the sender
+    pub acks_received: u64,
+    /// Parity packets sent for forward error correction
+    pub parity_sent: u64,
+    /// Lost packets the receiver rebuilt from parity packets
+    pub fec_recovered: u64,
+    /// Number of RTT samples taken from acknowledgments
+    pub rtt_samples: u64,
+    /// Lowest RTT sample in microseconds
+    pub rtt_min_micros: Option<u64>,
+    /// Mean of all RTT samples in microseconds
+    pub rtt_mean_micros: Option<u64>,
+    /// Highest RTT sample in microseconds
+    pub rtt_max_micros: Option<u64>,
+    #[serde(skip)]
+    rtt_total_micros: u64,
+}
+
+impl TransferStats {
+    /// Records one RTT sample in microseconds.
+    pub fn record_rtt(&mut self, sample: u64) {
+        self.rtt_samples += 1;
+        self.rtt_total_micros += sample;
+        self.rtt_min_micros = Some(self.rtt_min_micros.map_or(sample, |min| min.min(sample)));
+        self.rtt_max_micros = Some(self.rtt_max_micros.map_or(sample, |max| max.max(sample)));
+        self.rtt_mean_micros = Some(self.rtt_total_micros / self.rtt_samples);
+    }
+
+    /// Adds the counters of another session, e.g. of a parallel stream.
+    pub fn merge(&mut self, other: &TransferStats) {
+        self.packets_sent += other.packets_sent;
+        self.packets_received += other.packets_received;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.retransmissions += other.retransmissions;
+        self.resend_requests += other.resend_requests;
+        self.timeouts += other.timeouts;
+        self.duplicates += other.duplicates;
+        self.out_of_window += other.out_of_window;
+        self.acks_sent += other.acks_sent;
+        self.acks_received += other.acks_received;
+        self.parity_sent += other.parity_sent;
+        self.fec_recovered += other.fec_recovered;
+
+        self.rtt_samples += other.rtt_samples;
+        self.rtt_total_micros += other.rtt_total_micros;
+        self.rtt_min_micros = min_option(self.rtt_min_micros, other.rtt_min_micros);
+        self.rtt_max_micros = self.rtt_max_micros.max(other.rtt_max_micros);
+        self.rtt_mean_micros = self.rtt_total_micros.checked_div(self.rtt_samples);
+    }
+}
+
+/// Minimum of two optional values, ignoring missing ones.
+fn min_option(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_rtt() {
+        let mut stats = TransferStats::default();
+        stats.record_rtt(300);
+        stats.record_rtt(100);
+        stats.record_rtt(200);
+
+        assert_eq!(stats.rtt_samples, 3);
+        assert_eq!(stats.rtt_min_micros, Some(100));
+        assert_eq!(stats.rtt_mean_micros, Some(200));
+        assert_eq!(stats.rtt_max_micros, Some(300));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = TransferStats { packets_sent: 10, retransmissions: 1, ..Default::default() };
+        a.record_rtt(100);
+        let mut b = TransferStats { packets_sent: 5, duplicates: 2, ..Default::default() };
+        b.record_rtt(300);
+        b.record_rtt(500);
+
+        a.merge(&b);
+        assert_eq!(a.packets_sent, 15);
+        assert_eq!(a.retransmissions, 1);
+        assert_eq!(a.duplicates, 2);
+        assert_eq!(a.rtt_samples, 3);
+        assert_eq!(a.rtt_min_micros, Some(100));
+        assert_eq!(a.rtt_mean_micros, Some(300));
+        assert_eq!(a.rtt_max_micros, Some(500));
+
+        // merging an empty session changes nothing
+        let before = a.clone();
+        a.merge(&TransferStats::default());
+        assert_eq!(a, before);
+    }
+}
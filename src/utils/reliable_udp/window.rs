@@ -0,0 +1,502 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Sequence number as it appears in the packet header.
+///
+/// Sequence numbers wrap around and are compared with serial number arithmetic (RFC 1982),
+/// so only their distance within half of the sequence space matters.
+pub type SeqNo = u32;
+
+/// Size of an encoded sequence number in bytes
+pub const SEQ_SIZE: usize = size_of::<SeqNo>();
+
+/// Distances of at least half the sequence space point backwards
+const SERIAL_HALF: usize = 1 << (SeqNo::BITS - 1);
+
+/// Number of packets that may be in flight (or buffered out of order) at the same time
+pub const DEFAULT_WINDOW_SIZE: usize = 256;
+
+/// Maximum number of SACK ranges carried in a single acknowledgment
+pub const MAX_SACK_RANGES: usize = 16;
+
+/// Number of selectively acknowledged packets above a gap before it is considered lost
+const DUPLICATE_THRESHOLD: usize = 3;
+
+/// Distance from `base` to `seq`, taking the wraparound of the sequence space into account.
+pub fn offset(base: SeqNo, seq: SeqNo) -> usize {
+    seq.wrapping_sub(base) as usize
+}
+
+/// Reads a big-endian sequence number from the start of `bytes`.
+pub fn read_seq(bytes: &[u8]) -> SeqNo {
+    let mut seq = [0u8; SEQ_SIZE];
+    seq.copy_from_slice(&bytes[..SEQ_SIZE]);
+    SeqNo::from_be_bytes(seq)
+}
+
+/// An inclusive range of sequence numbers the receiver already holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SackRange {
+    pub start: SeqNo,
+    pub end: SeqNo,
+}
+
+/// A packet which was sent but not yet acknowledged
+struct InFlightPacket {
+    seq: SeqNo,
+    datagram: Vec<u8>,
+    /// Time of the last transmission in microseconds
+    sent_at: u64,
+    transmissions: u32,
+    sacked: bool,
+    /// Packets to be acknowledged after this one in addition to `DUPLICATE_THRESHOLD` before it counts as lost
+    hold: usize,
+}
+
+/// Sender side of the sliding window.
+///
+/// Keeps every unacknowledged datagram until the receiver confirms it, either cumulatively
+/// or through a SACK range, and decides which gaps have to be retransmitted.
+pub struct SendWindow {
+    next_seq: SeqNo,
+    in_flight: VecDeque<InFlightPacket>,
+    capacity: usize,
+}
+
+impl SendWindow {
+    pub fn new(capacity: usize) -> Self {
+        SendWindow {
+            next_seq: 0,
+            in_flight: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Lets the first packet get sequence number `seq` instead of 0, only before anything was pushed.
+    pub fn start_at(&mut self, seq: SeqNo) {
+        debug_assert!(self.in_flight.is_empty());
+        self.next_seq = seq;
+    }
+
+    /// Sequence number the next pushed packet will get.
+    pub fn next_seq(&self) -> SeqNo {
+        self.next_seq
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.in_flight.len() >= self.capacity
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Registers a datagram which was just sent with the sequence number `next_seq()`.
+    ///
+    /// `hold` packets after it have to be acknowledged in addition before it is retransmitted early,
+    /// e.g. because parity sent later may still fill the gap.
+    pub fn push(&mut self, datagram: Vec<u8>, now: u64, hold: usize) -> SeqNo {
+        let seq = self.next_seq;
+        self.in_flight.push_back(InFlightPacket {
+            seq,
+            datagram,
+            sent_at: now,
+            transmissions: 1,
+            sacked: false,
+            hold,
+        });
+        self.next_seq = self.next_seq.wrapping_add(1);
+        seq
+    }
+
+    /// Applies an acknowledgment from the receiver.
+    ///
+    /// # Arguments
+    ///
+    /// * `cumulative` - The next sequence number the receiver expects; everything before it arrived.
+    /// * `ranges` - Packets above `cumulative` the receiver buffered out of order.
+    /// * `now` - Current time in microseconds, used to take an RTT sample.
+    ///
+    /// # Returns
+    ///
+    /// `AckOutcome` - How many packets were newly acknowledged and the RTT sample, if any.
+    pub fn on_ack(&mut self, cumulative: SeqNo, ranges: &[SackRange], now: u64) -> AckOutcome {
+        let mut outcome = AckOutcome::default();
+        let Some(base) = self.in_flight.front().map(|packet| packet.seq) else {
+            return outcome;
+        };
+
+        // ignore stale acknowledgments which point before the window
+        let acked = offset(base, cumulative);
+        if acked > self.in_flight.len() {
+            return outcome;
+        }
+
+        // the most recently sent packet that was never retransmitted gives an unambiguous RTT sample (Karn)
+        let mut newest_sample = None;
+        for packet in self.in_flight.drain(..acked) {
+            if !packet.sacked {
+                outcome.acked += 1;
+                if packet.transmissions == 1 {
+                    newest_sample = Some(packet.sent_at);
+                }
+            }
+        }
+
+        for range in ranges {
+            for packet in self.in_flight.iter_mut() {
+                let seq_offset = offset(range.start, packet.seq);
+                if !packet.sacked && seq_offset <= offset(range.start, range.end) {
+                    packet.sacked = true;
+                    outcome.acked += 1;
+                    if packet.transmissions == 1 {
+                        newest_sample = newest_sample.max(Some(packet.sent_at));
+                    }
+                }
+            }
+        }
+
+        outcome.rtt_sample = newest_sample.map(|sent_at| now.saturating_sub(sent_at));
+        outcome
+    }
+
+    /// Collects datagrams that have to be sent again and marks them as retransmitted.
+    ///
+    /// A packet is retransmitted once early if at least `DUPLICATE_THRESHOLD` (plus its hold) packets sent after it
+    /// were selectively acknowledged, and again every time `rto` microseconds pass without an acknowledgment.
+    pub fn take_retransmissions(&mut self, now: u64, rto: u64) -> Retransmissions {
+        let mut sacked_above = 0;
+        let mut retransmissions = Retransmissions::default();
+
+        for packet in self.in_flight.iter_mut().rev() {
+            if packet.sacked {
+                sacked_above += 1;
+                continue;
+            }
+            if now.saturating_sub(packet.sent_at) >= rto {
+                retransmissions.timed_out += 1;
+            } else if packet.transmissions == 1 && sacked_above >= DUPLICATE_THRESHOLD + packet.hold {
+                retransmissions.lost += 1;
+            } else {
+                continue;
+            }
+            packet.sent_at = now;
+            packet.transmissions += 1;
+            retransmissions.datagrams.push(packet.datagram.clone());
+        }
+
+        // oldest gaps first
+        retransmissions.datagrams.reverse();
+        retransmissions
+    }
+}
+
+/// Result of applying an acknowledgment to the send window
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AckOutcome {
+    /// Number of packets acknowledged for the first time
+    pub acked: usize,
+    /// Round-trip time in microseconds measured from a packet that was sent only once
+    pub rtt_sample: Option<u64>,
+}
+
+/// Datagrams the sender has to transmit again
+#[derive(Debug, Default)]
+pub struct Retransmissions {
+    pub datagrams: Vec<Vec<u8>>,
+    /// Number of gaps detected through SACK ranges
+    pub lost: usize,
+    /// Number of packets whose retransmission timer expired
+    pub timed_out: usize,
+}
+
+/// Receiver side of the sliding window.
+///
+/// Buffers packets which arrive ahead of the expected sequence number and hands them out in order.
+pub struct ReceiveWindow {
+    next_expected: SeqNo,
+    buffered: HashMap<SeqNo, (u8, Vec<u8>)>,
+    capacity: usize,
+}
+
+/// Outcome of offering a packet to the receive window
+#[derive(Debug, PartialEq, Eq)]
+pub enum Accepted {
+    /// The packet was already received before
+    Duplicate,
+    /// The packet was stored and can be read once all packets before it arrived
+    Buffered,
+    /// The packet is too far ahead of the window and was dropped
+    OutOfWindow,
+}
+
+impl ReceiveWindow {
+    pub fn new(capacity: usize) -> Self {
+        ReceiveWindow {
+            next_expected: 0,
+            buffered: HashMap::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Expects the first packet with sequence number `seq` instead of 0, only before anything was accepted.
+    pub fn start_at(&mut self, seq: SeqNo) {
+        debug_assert!(self.buffered.is_empty());
+        self.next_expected = seq;
+    }
+
+    /// Offers a received packet to the window.
+    pub fn accept(&mut self, seq: SeqNo, packet_type: u8, payload: Vec<u8>) -> Accepted {
+        let seq_offset = offset(self.next_expected, seq);
+        if seq_offset >= SERIAL_HALF || self.buffered.contains_key(&seq) {
+            // behind the window, we already have it
+            return Accepted::Duplicate;
+        }
+        if seq_offset >= self.capacity {
+            return Accepted::OutOfWindow;
+        }
+        self.buffered.insert(seq, (packet_type, payload));
+        Accepted::Buffered
+    }
+
+    /// Removes and returns the next in-order packet, if it already arrived.
+    pub fn pop_ready(&mut self) -> Option<(u8, Vec<u8>)> {
+        let packet = self.buffered.remove(&self.next_expected)?;
+        self.next_expected = self.next_expected.wrapping_add(1);
+        Some(packet)
+    }
+
+    /// Builds the acknowledgment for the current state of the window.
+    ///
+    /// # Returns
+    ///
+    /// `(SeqNo, Vec<SackRange>)` - The next sequence number that is missing and the ranges received after it.
+    pub fn ack(&self) -> (SeqNo, Vec<SackRange>) {
+        let mut cumulative = self.next_expected;
+        while self.buffered.contains_key(&cumulative) {
+            cumulative = cumulative.wrapping_add(1);
+        }
+
+        let mut offsets: Vec<usize> = self.buffered.keys()
+            .map(|seq| offset(cumulative, *seq))
+            .filter(|seq_offset| *seq_offset < self.capacity)
+            .collect();
+        offsets.sort_unstable();
+
+        let mut ranges: Vec<SackRange> = Vec::new();
+        for seq_offset in offsets {
+            let seq = cumulative.wrapping_add(seq_offset as SeqNo);
+            if let Some(range) = ranges.last_mut().filter(|range| range.end.wrapping_add(1) == seq) {
+                range.end = seq;
+            } else if ranges.len() == MAX_SACK_RANGES {
+                break;
+            } else {
+                ranges.push(SackRange { start: seq, end: seq });
+            }
+        }
+
+        (cumulative, ranges)
+    }
+}
+
+/// Encodes SACK ranges into the payload of an acknowledgment packet.
+pub fn encode_sack_ranges(ranges: &[SackRange]) -> Vec<u8> {
+    let count = ranges.len().min(MAX_SACK_RANGES);
+    let mut payload = Vec::with_capacity(1 + count * 2 * SEQ_SIZE);
+    payload.push(count as u8);
+    for range in &ranges[..count] {
+        payload.extend_from_slice(&range.start.to_be_bytes());
+        payload.extend_from_slice(&range.end.to_be_bytes());
+    }
+    payload
+}
+
+/// Reads the number of packets the receiver rebuilt from parity, which follows the SACK ranges of an acknowledgment.
+pub fn decode_recovered(payload: &[u8]) -> Option<u32> {
+    let count = *payload.first()? as usize;
+    let start = 1 + count * 2 * SEQ_SIZE;
+    let bytes = payload.get(start..start + size_of::<u32>())?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decodes SACK ranges from the payload of an acknowledgment packet, ignoring truncated entries.
+pub fn decode_sack_ranges(payload: &[u8]) -> Vec<SackRange> {
+    let Some((&count, rest)) = payload.split_first() else {
+        return Vec::new();
+    };
+    rest.chunks_exact(2 * SEQ_SIZE)
+        .take(count as usize)
+        .map(|chunk| SackRange {
+            start: read_seq(chunk),
+            end: read_seq(&chunk[SEQ_SIZE..]),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receive_window_reorders_packets() {
+        let mut window = ReceiveWindow::new(DEFAULT_WINDOW_SIZE);
+        assert_eq!(window.accept(1, 0, vec![1]), Accepted::Buffered);
+        assert!(window.pop_ready().is_none());
+
+        assert_eq!(window.accept(0, 0, vec![0]), Accepted::Buffered);
+        assert_eq!(window.pop_ready(), Some((0, vec![0])));
+        assert_eq!(window.pop_ready(), Some((0, vec![1])));
+        assert!(window.pop_ready().is_none());
+
+        assert_eq!(window.accept(1, 0, vec![1]), Accepted::Duplicate);
+    }
+
+    #[test]
+    fn test_receive_window_sack_ranges() {
+        let mut window = ReceiveWindow::new(DEFAULT_WINDOW_SIZE);
+        for seq in [0, 1, 3, 4, 7] {
+            window.accept(seq, 0, Vec::new());
+        }
+        let (cumulative, ranges) = window.ack();
+        assert_eq!(cumulative, 2);
+        assert_eq!(ranges, vec![SackRange { start: 3, end: 4 }, SackRange { start: 7, end: 7 }]);
+    }
+
+    #[test]
+    fn test_receive_window_rejects_out_of_window() {
+        let mut window = ReceiveWindow::new(4);
+        assert_eq!(window.accept(4, 0, Vec::new()), Accepted::OutOfWindow);
+        assert_eq!(window.accept(3, 0, Vec::new()), Accepted::Buffered);
+    }
+
+    #[test]
+    fn test_send_window_retransmits_only_gaps() {
+        let mut window = SendWindow::new(DEFAULT_WINDOW_SIZE);
+        for seq in 0..6u8 {
+            window.push(vec![seq], 0, 0);
+        }
+
+        // packet 1 is missing, 2..=5 arrived
+        assert_eq!(window.on_ack(1, &[SackRange { start: 2, end: 5 }], 10).acked, 5);
+        let retransmissions = window.take_retransmissions(10, 1000);
+        assert_eq!(retransmissions.datagrams, vec![vec![1]]);
+        assert_eq!(retransmissions.lost, 1);
+
+        // fast retransmit happens only once
+        assert!(window.take_retransmissions(20, 1000).datagrams.is_empty());
+
+        assert_eq!(window.on_ack(6, &[], 30).acked, 1);
+        assert!(window.is_empty());
+    }
+
+    #[test]
+    fn test_send_window_retransmits_after_timeout() {
+        let mut window = SendWindow::new(DEFAULT_WINDOW_SIZE);
+        window.push(vec![0], 0, 0);
+        assert!(window.take_retransmissions(999, 1000).datagrams.is_empty());
+        let retransmissions = window.take_retransmissions(1000, 1000);
+        assert_eq!(retransmissions.datagrams, vec![vec![0]]);
+        assert_eq!(retransmissions.timed_out, 1);
+    }
+
+    #[test]
+    fn test_send_window_rtt_sample_ignores_retransmissions() {
+        let mut window = SendWindow::new(DEFAULT_WINDOW_SIZE);
+        window.push(vec![0], 100, 0);
+        window.push(vec![1], 200, 0);
+        assert_eq!(window.on_ack(1, &[], 400).rtt_sample, Some(300));
+
+        window.take_retransmissions(5000, 1000);
+        assert_eq!(window.on_ack(2, &[], 5100), AckOutcome { acked: 1, rtt_sample: None });
+    }
+
+    #[test]
+    fn test_sequence_wraparound() {
+        let mut receive_window = ReceiveWindow::new(DEFAULT_WINDOW_SIZE);
+        receive_window.next_expected = SeqNo::MAX;
+        receive_window.accept(0, 0, vec![1]);
+        receive_window.accept(SeqNo::MAX, 0, vec![0]);
+        assert_eq!(receive_window.pop_ready(), Some((0, vec![0])));
+        assert_eq!(receive_window.pop_ready(), Some((0, vec![1])));
+
+        let mut send_window = SendWindow::new(DEFAULT_WINDOW_SIZE);
+        send_window.next_seq = SeqNo::MAX;
+        send_window.push(vec![0], 0, 0);
+        send_window.push(vec![1], 0, 0);
+        assert_eq!(send_window.on_ack(1, &[], 0).acked, 2);
+    }
+
+    #[test]
+    fn test_stream_crosses_u16_wrap_point() {
+        let mut send_window = SendWindow::new(DEFAULT_WINDOW_SIZE);
+        let mut receive_window = ReceiveWindow::new(DEFAULT_WINDOW_SIZE);
+        let mut delivered = 0u64;
+
+        for i in 0..70_000u64 {
+            send_window.push(i.to_be_bytes().to_vec(), i, 0);
+        }
+        while !send_window.is_empty() {
+            // deliver the window in reverse order to force reordering on every batch
+            let batch: Vec<(SeqNo, Vec<u8>)> = send_window.in_flight.iter()
+                .map(|packet| (packet.seq, packet.datagram.clone()))
+                .take(8)
+                .collect();
+            for (seq, datagram) in batch.into_iter().rev() {
+                receive_window.accept(seq, 0, datagram);
+            }
+            while let Some((_, payload)) = receive_window.pop_ready() {
+                assert_eq!(payload, delivered.to_be_bytes());
+                delivered += 1;
+            }
+            let (cumulative, ranges) = receive_window.ack();
+            send_window.on_ack(cumulative, &ranges, 0);
+        }
+
+        assert_eq!(delivered, 70_000);
+        assert_eq!(send_window.next_seq(), 70_000);
+    }
+
+    #[test]
+    fn test_old_duplicates_after_u16_wrap_point() {
+        let mut window = ReceiveWindow::new(DEFAULT_WINDOW_SIZE);
+        window.next_expected = 0x10005;
+        // a late packet from the first lap of a 16 bit sequence space would have looked like the next one
+        assert_eq!(window.accept(5, 0, Vec::new()), Accepted::Duplicate);
+        assert_eq!(window.accept(0x10005, 0, Vec::new()), Accepted::Buffered);
+    }
+
+    #[test]
+    fn test_sack_ranges_roundtrip() {
+        let ranges = vec![SackRange { start: 3, end: 4 }, SackRange { start: 0x1_0009, end: 0x1_0012 }];
+        assert_eq!(decode_sack_ranges(&encode_sack_ranges(&ranges)), ranges);
+        assert!(decode_sack_ranges(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_recovered_count_follows_sack_ranges() {
+        let mut payload = encode_sack_ranges(&[SackRange { start: 3, end: 4 }]);
+        assert_eq!(decode_recovered(&payload), None);
+        payload.extend_from_slice(&7u32.to_be_bytes());
+        assert_eq!(decode_recovered(&payload), Some(7));
+        assert_eq!(decode_sack_ranges(&payload), vec![SackRange { start: 3, end: 4 }]);
+    }
+
+    #[test]
+    fn test_send_window_held_packet() {
+        let mut window = SendWindow::new(DEFAULT_WINDOW_SIZE);
+        window.push(vec![0], 0, 0);
+        window.push(vec![1], 0, 2);
+        for seq in 2..6u8 {
+            window.push(vec![seq], 0, 0);
+        }
+        window.on_ack(1, &[SackRange { start: 2, end: 5 }], 10);
+        assert!(window.take_retransmissions(10, 1000).datagrams.is_empty());
+
+        window.push(vec![6], 20, 0);
+        window.on_ack(1, &[SackRange { start: 2, end: 6 }], 30);
+        assert_eq!(window.take_retransmissions(30, 1000).datagrams, vec![vec![1]]);
+    }
+}
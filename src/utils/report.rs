@@ -0,0 +1,154 @@
+use clap::ValueEnum;
+use console::style;
+use humansize::{DECIMAL, format_size};
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::utils::reliable_udp::stats::TransferStats;
+
+/// How the statistics are printed after a transfer
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// Human-readable summary
+    Text,
+    /// A single line of JSON, e.g. to collect it in CI
+    Json,
+}
+
+/// Which side of the transfer a report describes
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// Summary of a finished transfer
+#[derive(Serialize, Debug)]
+pub struct TransferReport {
+    pub(crate) role: Role,
+    pub(crate) file_size: u64,
+    pub(crate) streams: usize,
+    pub(crate) chunk_size: u32,
+    pub(crate) duration_millis: u64,
+    /// File bytes per second, retransmissions and headers not included
+    pub(crate) goodput_bytes_per_sec: u64,
+    #[serde(flatten)]
+    pub(crate) stats: TransferStats,
+}
+
+impl TransferReport {
+    /// Creates a report from the statistics of all streams of a transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - Whether we sent or received the file.
+    /// * `file_size` - Size of the transferred file in bytes.
+    /// * `chunk_size` - Chunk size used by the streams.
+    /// * `duration_millis` - How long the transfer took.
+    /// * `streams` - Statistics of every stream.
+    pub fn new(
+        role: Role,
+        file_size: u64,
+        chunk_size: u32,
+        duration_millis: u64,
+        streams: &[TransferStats],
+    ) -> Self {
+        let mut stats = TransferStats::default();
+        for stream in streams {
+            stats.merge(stream);
+        }
+        TransferReport {
+            role,
+            file_size,
+            streams: streams.len(),
+            chunk_size,
+            duration_millis,
+            goodput_bytes_per_sec: file_size * 1000 / duration_millis.max(1),
+            stats,
+        }
+    }
+
+    /// Prints the report in the requested format to stdout.
+    pub fn print(&self, format: StatsFormat) -> Result<()> {
+        match format {
+            StatsFormat::Text => self.print_text(),
+            StatsFormat::Json => println!("{}", serde_json::to_string(self)?),
+        }
+        Ok(())
+    }
+
+    fn print_text(&self) {
+        let stats = &self.stats;
+        println!(
+            "{} Goodput: {}/s ({} in {}s over {} stream(s))",
+            style("[i]").bold().blue(),
+            format_size(self.goodput_bytes_per_sec, DECIMAL),
+            format_size(self.file_size, DECIMAL),
+            self.duration_millis as f64 / 1000.0,
+            self.streams
+        );
+        match self.role {
+            Role::Sender => {
+                println!(
+                    "    Packets: {} sent, {} retransmitted ({} resend requests, {} timeouts)",
+                    stats.packets_sent, stats.retransmissions, stats.resend_requests, stats.timeouts
+                );
+                if stats.parity_sent > 0 {
+                    println!("    FEC: {} parity packets sent", stats.parity_sent);
+                }
+                if let (Some(min), Some(mean), Some(max)) =
+                    (stats.rtt_min_micros, stats.rtt_mean_micros, stats.rtt_max_micros)
+                {
+                    println!(
+                        "    RTT: min {:.3}ms, mean {:.3}ms, max {:.3}ms ({} samples)",
+                        min as f64 / 1000.0,
+                        mean as f64 / 1000.0,
+                        max as f64 / 1000.0,
+                        stats.rtt_samples
+                    );
+                }
+            }
+            Role::Receiver => {
+                println!(
+                    "    Packets: {} received, {} duplicates, {} out of window, {} acknowledgments sent",
+                    stats.packets_received, stats.duplicates, stats.out_of_window, stats.acks_sent
+                );
+                if stats.fec_recovered > 0 {
+                    println!("    FEC: {} lost packets rebuilt from parity", stats.fec_recovered);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_merges_streams() {
+        let mut streams = [TransferStats::default(), TransferStats::default()];
+        streams[0].packets_sent = 3;
+        streams[0].retransmissions = 1;
+        streams[1].packets_sent = 4;
+        let report = TransferReport::new(Role::Sender, 10_000, 1000, 500, &streams);
+
+        assert_eq!(report.streams, 2);
+        assert_eq!(report.goodput_bytes_per_sec, 20_000);
+        assert_eq!(report.stats.packets_sent, 7);
+        assert_eq!(report.stats.retransmissions, 1);
+    }
+
+    #[test]
+    fn test_report_json() {
+        let report = TransferReport::new(Role::Receiver, 100, 10, 0, &[TransferStats::default()]);
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+
+        assert_eq!(json["role"], "receiver");
+        assert_eq!(json["goodput_bytes_per_sec"], 100_000);
+        assert_eq!(json["packets_received"], 0);
+        assert!(json["rtt_mean_micros"].is_null());
+        assert!(json.get("rtt_total_micros").is_none());
+    }
+}
@@ -1,8 +1,9 @@
 use serde::{Serialize};
 use serde::de::DeserializeOwned;
-use std::net::UdpSocket;
+use std::net::{SocketAddr, UdpSocket};
 
 use crate::error::{NudgeError, Result};
+use crate::utils::socket::mapped_for;
 
 /// Serializes the given data and sends it over the provided UDP socket with the specified prefix.
 ///
@@ -22,6 +23,18 @@ pub fn serialize_and_send(socket: &UdpSocket, prefix: &str, data: &impl Serializ
     Ok(())
 }
 
+/// Serializes the given data and sends it to `addr` with the specified prefix, for sockets which are not connected.
+///
+/// # Errors
+///
+/// Returns `NudgeError` if serialization fails or if sending the message fails.
+pub fn serialize_and_send_to(socket: &UdpSocket, addr: SocketAddr, prefix: &str, data: &impl Serialize) -> Result<()> {
+    let serialized_data = serde_json::to_string(data)?;
+    let message = format!("{} {}", prefix, serialized_data);
+    socket.send_to(message.as_bytes(), mapped_for(socket, addr))?;
+    Ok(())
+}
+
 /// Receives a message from the UDP socket, parses it, and checks if it matches the expected prefix.
 ///
 /// # Arguments
@@ -39,8 +52,25 @@ pub fn receive_and_parse_and_expect<T>(connection: &UdpSocket, expected_prefix:
 {
     let mut buffer = [0u8; 1024];
     connection.recv(&mut buffer)?;
+    parse_and_expect(&buffer, expected_prefix)
+}
 
-    let message = String::from_utf8_lossy(&buffer);
+/// Parses a received message and checks if it matches the expected prefix.
+///
+/// # Arguments
+///
+/// * `buffer` - The received bytes, trailing zero bytes are ignored.
+/// * `expected_prefix` - The expected prefix of the message.
+///
+/// # Errors
+///
+/// Returns `NudgeError` if the message contains an error, if the prefix does not match,
+/// or if deserialization fails.
+pub fn parse_and_expect<T>(buffer: &[u8], expected_prefix: &str) -> Result<T>
+    where
+        T: DeserializeOwned
+{
+    let message = String::from_utf8_lossy(buffer);
     let message_trimmed = message.trim_end_matches(char::from(0));
 
     if message_trimmed.starts_with("ERROR ") {
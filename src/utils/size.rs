@@ -0,0 +1,81 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use humansize::{DECIMAL, format_size};
+use serde::{Deserialize, Deserializer};
+
+use crate::error::NudgeError;
+
+/// A file size like `10GB` or `500MiB`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+/// Bytes per unit, for the decimal and binary units shared by sizes and rates.
+pub fn byte_multiplier(unit: &str) -> Option<f64> {
+    match unit {
+        "" | "b" => Some(1.0),
+        "k" | "kb" => Some(1e3),
+        "m" | "mb" => Some(1e6),
+        "g" | "gb" => Some(1e9),
+        "t" | "tb" => Some(1e12),
+        "kib" => Some(1024.0),
+        "mib" => Some(1024.0 * 1024.0),
+        "gib" => Some(1024.0 * 1024.0 * 1024.0),
+        "tib" => Some(1024.0 * 1024.0 * 1024.0 * 1024.0),
+        _ => None,
+    }
+}
+
+/// Splits `5mb` into the number and the (trimmed) unit.
+pub fn split_unit(value: &str) -> (&str, &str) {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    (number, unit.trim())
+}
+
+impl FromStr for ByteSize {
+    type Err = NudgeError;
+
+    /// Parses sizes like `10GB`, `500 MiB` or `1024`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let normalized = value.trim().to_lowercase();
+        let (number, unit) = split_unit(&normalized);
+
+        let multiplier = byte_multiplier(unit).ok_or_else(|| NudgeError::InvalidSize(value.to_string()))?;
+        let number: f64 = number.parse().map_err(|_| NudgeError::InvalidSize(value.to_string()))?;
+        Ok(ByteSize((number * multiplier) as u64))
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format_size(self.0, DECIMAL))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!("10GB".parse::<ByteSize>().unwrap(), ByteSize(10_000_000_000));
+        assert_eq!("500 MiB".parse::<ByteSize>().unwrap(), ByteSize(500 * 1024 * 1024));
+        assert_eq!("1.5kb".parse::<ByteSize>().unwrap(), ByteSize(1_500));
+        assert_eq!("1024".parse::<ByteSize>().unwrap(), ByteSize(1024));
+        assert_eq!("0".parse::<ByteSize>().unwrap(), ByteSize(0));
+
+        for invalid in ["", "big", "5XB", "GB", "5MB/s"] {
+            assert!(matches!(invalid.parse::<ByteSize>(), Err(NudgeError::InvalidSize(_))), "{}", invalid);
+        }
+    }
+}
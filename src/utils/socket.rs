@@ -1,18 +1,429 @@
-use std::net::UdpSocket;
-use std::time::Duration;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
 use std::thread;
 
-use crate::error::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::error::{NudgeError, Result};
+use crate::models::{
+    LocalCandidates, P2XPongMessage, P2XProbeMessage, P2XPunchReadyMessage, P2XRequestRelayMessage, PunchAttempt,
+    X2PPingMessage, X2PRelayAcceptedMessage, X2PStartPunchingMessage,
+};
 use crate::utils::current_unix_millis;
+use crate::utils::passphrase::Passphrase;
+use crate::utils::prediction::{punch_predicted, spray_offset, Prediction, PROBE_SOCKETS};
+use crate::utils::serialize::{parse_and_expect, serialize_and_send};
+
+/// How often binding is retried if another socket took the port in the meantime
+const BIND_ATTEMPTS: usize = 5;
+
+/// Punch attempts, each one runs if the ones before failed.
+///
+/// The later attempts send faster and then slower, to catch a peer which started a little too early or late.
+pub const PUNCH_ATTEMPTS: [PunchAttempt; 3] = [
+    PunchAttempt { offset_ms: 0, packets: 40, interval_ms: 50 },
+    PunchAttempt { offset_ms: 3_500, packets: 100, interval_ms: 20 },
+    PunchAttempt { offset_ms: 7_000, packets: 40, interval_ms: 150 },
+];
+
+/// How long to wait for the relay-server before punching on the local clock
+const COORDINATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a message of the relay-server before telling it again that we are ready
+const READY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handshake through the relay-server, repeated until the peer falls back to the relay-server as well
+const RELAY_HANDSHAKE: PunchAttempt = PunchAttempt { offset_ms: 0, packets: 10, interval_ms: 50 };
+
+/// How often the handshake through the relay-server is repeated
+const RELAY_HANDSHAKE_ROUNDS: usize = 10;
+
+/// Punch attempt on a local address of the peer, which answers quickly if it is in the same network
+const LAN_ATTEMPT: PunchAttempt = PunchAttempt { offset_ms: 0, packets: 5, interval_ms: 20 };
+
+/// How long to wait for the peer after a punch attempt on a local address
+const LAN_WAIT: Duration = Duration::from_millis(300);
+
+/// Time reserved for every punch attempt on a local address, before the public addresses are tried
+const LAN_SLOT: Duration = Duration::from_millis(500);
+
+/// Without the relay-server, both peers start on the next boundary of this interval of their clocks
+const LOCAL_BOUNDARY_MILLIS: u64 = 500;
+
+/// When both peers start punching and what they do if it fails.
+#[derive(Debug, Clone)]
+pub struct PunchPlan {
+    start: Instant,
+    attempts: Vec<PunchAttempt>,
+    /// How the NATs of both peers allocate ports, for spraying predicted ports once the attempts failed
+    prediction: Option<Prediction>,
+}
+
+impl PunchPlan {
+    /// Starts `start_in_ms` from now, a negative value means the punching started already.
+    fn starting_in(start_in_ms: i64, attempts: Vec<PunchAttempt>) -> Self {
+        let now = Instant::now();
+        let start = if start_in_ms >= 0 {
+            now + Duration::from_millis(start_in_ms as u64)
+        } else {
+            now.checked_sub(Duration::from_millis(start_in_ms.unsigned_abs())).unwrap_or(now)
+        };
+        PunchPlan { start, attempts, prediction: None }
+    }
+
+    /// The same attempts, starting `delay` later.
+    fn delayed(&self, delay: Duration) -> Self {
+        PunchPlan { start: self.start + delay, ..self.clone() }
+    }
+
+    /// Starts right away, for peers which agreed on the start directly.
+    pub fn immediate() -> Self {
+        PunchPlan::starting_in(0, PUNCH_ATTEMPTS.to_vec())
+    }
+
+    /// Starts on the next boundary of the local clock, which only works if the clocks of both peers agree.
+    fn from_local_clock() -> Self {
+        let delay = LOCAL_BOUNDARY_MILLIS - (current_unix_millis() % LOCAL_BOUNDARY_MILLIS);
+        PunchPlan::starting_in(delay as i64, PUNCH_ATTEMPTS.to_vec())
+    }
+}
+
+/// Where the peer's socket of a stream can be reached.
+#[derive(Debug, Clone)]
+pub struct PeerCandidates {
+    /// Index of the stream
+    stream_index: u8,
+    /// The address the relay-server saw
+    public: SocketAddr,
+    /// Local addresses of the peer, one per attempt, in the order both peers agree on
+    local: Vec<SocketAddr>,
+}
+
+impl PeerCandidates {
+    /// Orders the local addresses of the peer, so both peers try every pair of their addresses at the same time.
+    ///
+    /// The pairs are ordered by the sender's address first, then by the receiver's address.
+    /// Pairs of an IPv4 and an IPv6 address cannot reach each other and are left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `public` - The address the relay-server saw.
+    /// * `local` - The local addresses of the peer's socket of the stream.
+    /// * `own_ips` - Our local addresses, as the peer got them.
+    /// * `sender` - Whether we are the sender.
+    pub fn new(public: SocketAddr, local: Vec<SocketAddr>, own_ips: &[IpAddr], sender: bool) -> Self {
+        let same_family = |own: &IpAddr, peer: &SocketAddr| own.is_ipv4() == peer.is_ipv4();
+        let local = if sender {
+            own_ips.iter()
+                .flat_map(|own| local.iter().filter(move |peer| same_family(own, peer)).copied())
+                .collect()
+        } else {
+            local.iter()
+                .flat_map(|peer| own_ips.iter().filter(move |own| same_family(own, peer)).map(move |_| *peer))
+                .collect()
+        };
+        PeerCandidates { stream_index: 0, public, local }
+    }
+}
+
+/// The addresses of the peer for every stream.
+///
+/// # Arguments
+///
+/// * `public_addrs` - The addresses the relay-server saw, by stream.
+/// * `local` - The local addresses of the peer the relay-server passed on.
+/// * `own_ips` - Our local addresses, as the relay-server passed them on to the peer.
+/// * `sender` - Whether we are the sender.
+pub fn peer_candidates(
+    public_addrs: &[SocketAddr],
+    local: &LocalCandidates,
+    own_ips: &[IpAddr],
+    sender: bool,
+) -> Vec<PeerCandidates> {
+    public_addrs.iter()
+        .enumerate()
+        .map(|(stream_index, public)| PeerCandidates {
+            stream_index: stream_index as u8,
+            ..PeerCandidates::new(*public, local.stream_addrs(stream_index), own_ips, sender)
+        })
+        .collect()
+}
+
+/// What the streams need to connect to the peer.
+pub struct Rendezvous {
+    pub passphrase: Passphrase<'static>,
+    /// When to punch, agreed on through the relay-server
+    pub plan: PunchPlan,
+    /// The relay-server which relays the data if hole punching fails, none if the peers found each other without it
+    pub relay_addr: Option<SocketAddr>,
+    /// Whether the peers reach each other without hole punching, e.g. within a data centre
+    pub direct: bool,
+    /// The addresses of the peer, by stream
+    pub peers: Vec<PeerCandidates>,
+}
+
+/// How the packets of a stream reach the peer.
+#[derive(Debug, Clone)]
+pub enum PeerRoute {
+    /// Through the punched hole
+    Direct,
+    /// Straight to a local address of the peer in the same network
+    Local(SocketAddr),
+    /// Through a mapping of the peer's NAT whose port was predicted
+    Predicted(SocketAddr),
+    /// Through the relay-server, within the quota and bandwidth it granted
+    Relayed(X2PRelayAcceptedMessage),
+}
+
+impl PeerRoute {
+    pub fn relay_grant(&self) -> Option<&X2PRelayAcceptedMessage> {
+        match self {
+            PeerRoute::Direct | PeerRoute::Local(_) | PeerRoute::Predicted(_) => None,
+            PeerRoute::Relayed(grant) => Some(grant),
+        }
+    }
+}
+
+/// Binds a UDP socket which reaches IPv4 and IPv6 peers, or only IPv4 ones where IPv6 is unavailable.
+///
+/// The kernel picks a free port, but the socket is bound to it explicitly:
+/// a socket bound to port 0 loses its port when it is disconnected, and with it the NAT mapping.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if binding fails
+pub fn bind_dual_stack() -> Result<UdpSocket> {
+    match bind_keeping_port(IpAddr::V6(Ipv6Addr::UNSPECIFIED)) {
+        Ok(socket) => Ok(socket),
+        Err(err) => {
+            debug!("Cannot bind an IPv6 socket, only IPv4 is used: {}", err);
+            Ok(bind_keeping_port(IpAddr::V4(Ipv4Addr::UNSPECIFIED))?)
+        }
+    }
+}
+
+fn bind_keeping_port(ip: IpAddr) -> io::Result<UdpSocket> {
+    let mut result = Err(io::Error::from(io::ErrorKind::AddrInUse));
+    for _ in 0..BIND_ATTEMPTS {
+        let port = {
+            let probe = new_socket(ip)?;
+            probe.bind(&SocketAddr::new(ip, 0).into())?;
+            probe.local_addr()?.as_socket().map_or(0, |addr| addr.port())
+        };
+        let socket = new_socket(ip)?;
+        result = socket.bind(&SocketAddr::new(ip, port).into()).map(|_| socket.into());
+        // another socket may have taken the port in the meantime
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
 
-/// Synchronizes the thread to the next boundary of the specified interval in milliseconds.
+/// Binds a UDP socket to `addr`, an IPv6 socket accepts IPv4 as well.
+///
+/// # Errors
+///
+/// Returns `io::Error` if the address cannot be bound
+pub fn bind_to(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = new_socket(addr.ip())?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// A UDP socket of the family of `ip`, IPv6 sockets accept IPv4 as well
+pub fn new_socket(ip: IpAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(SocketAddr::new(ip, 0)), Type::DGRAM, Some(Protocol::UDP))?;
+    if ip.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    Ok(socket)
+}
+
+/// `addr` in the form the socket can send to: IPv4 addresses are mapped into IPv6 for an IPv6 socket.
+pub fn mapped_for(socket: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), addr) {
+        (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        _ => addr,
+    }
+}
+
+/// `addr` with an IPv4 address mapped into IPv6 turned back into IPv4, so it compares equal to the plain one.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Connects the socket to `addr`, after disconnecting it if its previous peer has the other address family.
+///
+/// Linux keeps the source address of the first connect, so without disconnecting,
+/// a socket which talked to an IPv4 address could never reach an IPv6 one.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the socket cannot reach the address
+pub fn reconnect(socket: &UdpSocket, addr: SocketAddr) -> Result<()> {
+    if socket.peer_addr().is_ok_and(|peer| canonical(peer).is_ipv4() != addr.ip().to_canonical().is_ipv4()) {
+        disconnect(socket);
+    }
+    Ok(socket.connect(mapped_for(socket, addr))?)
+}
+
+/// Connects the socket to the first address of `host` (a name, an IPv4 or an IPv6 address) of a family the socket supports.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the host cannot be resolved or reached
+pub fn connect_to_host(socket: &UdpSocket, host: &str, port: u16) -> Result<()> {
+    reconnect(socket, reachable_addr(socket, host, port)?)
+}
+
+/// The first address of `host` of a family the socket supports.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the host cannot be resolved or has no such address
+pub fn reachable_addr(socket: &UdpSocket, host: &str, port: u16) -> Result<SocketAddr> {
+    let ipv6 = socket.local_addr()?.is_ipv6();
+    let addr = resolve(host, port)?
+        .into_iter()
+        .find(|addr| ipv6 || addr.is_ipv4())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, format!("{} has no IPv4 address", host)))?;
+    Ok(addr)
+}
+
+/// Splits `host:port`, the host may be a name, an IPv4 address or an IPv6 address in brackets.
+///
+/// # Errors
+///
+/// Returns `NudgeError::InvalidAddress` if there is no port
+pub fn split_host_port(text: &str) -> Result<(&str, u16)> {
+    text.rsplit_once(':')
+        .filter(|(host, _)| !host.is_empty())
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        .ok_or_else(|| NudgeError::InvalidAddress(text.to_string()))
+}
+
+/// The addresses of `host` and `port`, IPv6 addresses may be given with or without brackets.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the host cannot be resolved
+pub fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+/// Disconnects the socket from its peer, so it receives from any address again.
+#[cfg(unix)]
+pub fn disconnect(socket: &UdpSocket) {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: an all-zero sockaddr is valid, and the socket is valid for the duration of the call
+    let result = unsafe {
+        let mut addr: libc::sockaddr = std::mem::zeroed();
+        addr.sa_family = libc::AF_UNSPEC as libc::sa_family_t;
+        libc::connect(socket.as_raw_fd(), &addr, size_of::<libc::sockaddr>() as libc::socklen_t)
+    };
+    if result != 0 {
+        debug!("Cannot disconnect the socket: {}", io::Error::last_os_error());
+    }
+}
+
+/// Other platforms replace the previous peer on connect
+#[cfg(not(unix))]
+pub fn disconnect(_socket: &UdpSocket) {}
+
+/// Agrees with the peer on when to punch, through the relay-server.
+///
+/// The relay-server measures the round trip time to both peers and tells each of them
+/// when to start, so the packets of both meet regardless of the clocks of the peers.
+/// If the relay-server does not coordinate, the peers punch on the boundaries of their local clocks.
+///
+/// Probe sockets show the relay-server how our NAT allocates ports, so the peer can predict
+/// the port of our mapping towards it if the punch attempts fail.
 ///
 /// # Arguments
 ///
-/// * `interval_ms` - The interval in milliseconds to synchronize to.
-fn sync_to_next_boundary(interval_ms: u64) {
-    let delay = interval_ms - (current_unix_millis() % interval_ms);
-    thread::sleep(Duration::from_millis(delay));
+/// * `socket` - The socket connected to the relay-server.
+/// * `passphrase` - The passphrase of the transfer.
+/// * `sender` - Whether we are the sender.
+///
+/// # Errors
+///
+/// Returns `NudgeError::Io` if the socket fails
+pub fn coordinate_punch(socket: &UdpSocket, passphrase: &Passphrase<'static>, sender: bool) -> Result<PunchPlan> {
+    let probes = send_probes(socket, passphrase, sender);
+    socket.set_read_timeout(Some(READY_INTERVAL))?;
+    let deadline = Instant::now() + COORDINATION_TIMEOUT;
+    let mut buffer = [0u8; 1024];
+
+    while Instant::now() < deadline {
+        let len = match socket.recv(&mut buffer) {
+            Ok(len) => len,
+            Err(_) => {
+                // the ping or our answer may be lost, the relay-server pings again
+                serialize_and_send(socket, "P2X_PR", &P2XPunchReadyMessage { passphrase: passphrase.clone() })?;
+                continue;
+            }
+        };
+        let message = &buffer[..len];
+        match message.split(|byte| *byte == b' ').next().unwrap_or_default() {
+            b"X2P_PING" => {
+                let ping: X2PPingMessage = parse_and_expect(message, "X2P_PING")?;
+                serialize_and_send(socket, "P2X_PONG", &P2XPongMessage { passphrase: ping.passphrase, probes })?;
+            }
+            b"X2P_SP" => {
+                let start: X2PStartPunchingMessage = parse_and_expect(message, "X2P_SP")?;
+                debug!("Relay-server says to start punching in {}ms", start.start_in_ms);
+                let prediction = Prediction::new(start.allocation, start.peer_allocation);
+                debug!("Port allocation of the NATs: {:?}", prediction);
+                return Ok(PunchPlan { prediction, ..PunchPlan::starting_in(start.start_in_ms, start.attempts) });
+            }
+            b"ERROR" => {
+                warn!("Relay-server cannot coordinate the hole punching: {}", String::from_utf8_lossy(message));
+                return Ok(PunchPlan::from_local_clock());
+            }
+            _ => {}
+        }
+    }
+
+    warn!("Relay-server did not coordinate the hole punching, relying on the local clock");
+    Ok(PunchPlan::from_local_clock())
+}
+
+/// Sends a probe to the relay-server from each of `PROBE_SOCKETS` new sockets, one after the other.
+///
+/// Every probe is sent twice, so a single lost packet does not hide a mapping.
+///
+/// # Returns
+///
+/// `u8` - The number of probe sockets, the relay-server waits for their probes.
+fn send_probes(socket: &UdpSocket, passphrase: &Passphrase<'static>, sender: bool) -> u8 {
+    let Ok(relay_addr) = socket.peer_addr() else {
+        return 0;
+    };
+    let mut probe_sockets = Vec::new();
+    for index in 0..PROBE_SOCKETS {
+        let probe = bind_dual_stack().and_then(|probe| {
+            reconnect(&probe, canonical(relay_addr))?;
+            let local_port = probe.local_addr()?.port();
+            Ok((probe, P2XProbeMessage { passphrase: passphrase.clone(), sender, index, local_port }))
+        });
+        match probe {
+            Ok(probe) => probe_sockets.push(probe),
+            Err(err) => {
+                debug!("Cannot open probe socket {}: {}", index, err);
+                return 0;
+            }
+        }
+    }
+    for (probe, message) in probe_sockets.iter().chain(&probe_sockets) {
+        if let Err(err) = serialize_and_send(probe, "P2X_PROBE", message) {
+            debug!("Cannot send probe {}: {}", message.index, err);
+        }
+    }
+    PROBE_SOCKETS
 }
 
 /// Sends a specified number of packets at the given interval in milliseconds.
@@ -45,48 +456,419 @@ fn send_packets(socket: &UdpSocket, count: usize, interval_ms: u64) -> Result<()
 ///
 /// # Returns
 ///
-/// * `Result<()>` - An `Ok` result when the condition is met, or an error if a socket operation fails.
-fn wait_for_condition<F>(socket: &UdpSocket, condition: F) -> Result<()>
+/// * `usize` - The number of packets received from the peer.
+fn wait_for_condition<F>(socket: &UdpSocket, condition: F) -> usize
     where
         F: Fn(usize) -> bool,
 {
-    let mut buffer = [0; 2];
+    // larger than any handshake packet, so data packets of the peer are not mistaken for them
+    let mut buffer = [0; 8];
+    let mut received_packets = 0;
     while let Ok(received) = socket.recv(&mut buffer) {
+        received_packets += 1;
         if !condition(received) {
             break;
         }
     }
-    Ok(())
+    received_packets
 }
 
-/// Initializes a UDP socket by setting timeouts, synchronizing to a boundary,
-/// sending initial packets, and waiting for a connection to be established.
+/// Initializes a UDP socket by punching a hole to the peer, as agreed on in `plan`.
+///
+/// Every attempt sends a burst of packets and waits for those of the peer, the next attempt
+/// only runs if nothing of the peer arrived.
 ///
 /// # Arguments
 ///
-/// * `socket` - A reference to the `UdpSocket`.
+/// * `socket` - A reference to the `UdpSocket`, connected to the peer.
+/// * `plan` - When to start and the attempts to make.
 ///
 /// # Returns
 ///
 /// * `Result<()>` - An `Ok` result if the initialization succeeds, or an error otherwise.
-pub fn init_socket(socket: &UdpSocket) -> Result<()> {
+///
+/// # Errors
+///
+/// Returns `NudgeError::PunchFailed` if no attempt reached the peer
+pub fn init_socket(socket: &UdpSocket, plan: &PunchPlan) -> Result<()> {
     // Set socket read and write timeouts
     socket.set_read_timeout(Some(Duration::from_secs(1)))?;
     socket.set_write_timeout(Some(Duration::from_secs(1)))?;
 
-    // Synchronize to the next 500ms boundary
-    sync_to_next_boundary(500);
+    for (index, attempt) in plan.attempts.iter().enumerate() {
+        // Synchronize to the start of the attempt, both peers start it at the same time
+        let start = plan.start + Duration::from_millis(attempt.offset_ms);
+        thread::sleep(start.saturating_duration_since(Instant::now()));
+
+        if punch(socket, attempt)? {
+            return Ok(());
+        }
+        debug!("Punch attempt {} of {} did not reach the peer", index + 1, plan.attempts.len());
+    }
+    Err(NudgeError::PunchFailed)
+}
+
+/// Connects the socket to the peer, through the relay-server if hole punching fails.
+///
+/// The local addresses of the peer are tried first, then the public one, then the ports its NAT probably allocated.
+/// Peers which reach each other directly skip all of this.
+///
+/// # Arguments
+///
+/// * `socket` - The socket of the stream.
+/// * `peer` - The addresses of the peer's socket of the stream.
+/// * `rendezvous` - When to punch, and the relay-server to fall back to.
+///
+/// # Errors
+///
+/// Returns `NudgeError::RelayRefused` if hole punching failed and the relay-server does not relay,
+/// or `NudgeError::PunchFailed` if the peer cannot be reached through the relay-server either
+pub fn connect_to_peer(socket: &mut UdpSocket, peer: &PeerCandidates, rendezvous: &Rendezvous) -> Result<PeerRoute> {
+    if rendezvous.direct {
+        // a packet of ours lets the peer through a stateful firewall in front of us
+        reconnect(socket, peer.public)?;
+        let _ = socket.send(&[0]);
+        return Ok(PeerRoute::Direct);
+    }
+
+    let plan = &rendezvous.plan;
+    for (slot, local_addr) in peer.local.iter().enumerate() {
+        let start = plan.start + LAN_SLOT * slot as u32;
+        thread::sleep(start.saturating_duration_since(Instant::now()));
+
+        reconnect(socket, *local_addr)?;
+        socket.set_read_timeout(Some(LAN_WAIT))?;
+        debug!("Trying the local address {}...", local_addr);
+        if punch(socket, &LAN_ATTEMPT)? {
+            return Ok(PeerRoute::Local(*local_addr));
+        }
+    }
+
+    let peer_addr = peer.public;
+    let plan = plan.delayed(LAN_SLOT * peer.local.len() as u32);
+    reconnect(socket, peer_addr)?;
+    debug!("Initializing socket connection to {}...", peer_addr);
+    match init_socket(socket, &plan) {
+        Err(NudgeError::PunchFailed) => {}
+        result => return result.map(|_| PeerRoute::Direct),
+    }
+
+    if let Some(prediction) = &plan.prediction {
+        let start = plan.start + spray_offset(&plan.attempts);
+        thread::sleep(start.saturating_duration_since(Instant::now()));
+        let streams = rendezvous.peers.len();
+        if let Some(predicted_addr) = punch_predicted(socket, peer_addr, peer.stream_index, prediction, streams)? {
+            return Ok(PeerRoute::Predicted(predicted_addr));
+        }
+    }
+
+    let Some(relay_addr) = rendezvous.relay_addr else {
+        return Err(NudgeError::PunchFailed);
+    };
+    warn!("Hole punching to {} failed, asking the relay-server to relay the data", peer_addr);
+    reconnect(socket, relay_addr)?;
+    let grant = request_relay(socket, &rendezvous.passphrase)?;
+    for _ in 0..RELAY_HANDSHAKE_ROUNDS {
+        if punch(socket, &RELAY_HANDSHAKE)? {
+            return Ok(PeerRoute::Relayed(grant));
+        }
+    }
+    Err(NudgeError::PunchFailed)
+}
+
+/// Asks the relay-server to forward the packets of the stream to the peer.
+///
+/// # Errors
+///
+/// Returns `NudgeError::RelayRefused` if the relay-server answers with an error, or `NudgeError::PunchFailed` if it does not answer
+fn request_relay(socket: &UdpSocket, passphrase: &Passphrase<'static>) -> Result<X2PRelayAcceptedMessage> {
+    socket.set_read_timeout(Some(READY_INTERVAL))?;
+    let deadline = Instant::now() + COORDINATION_TIMEOUT;
+    let mut requested_at: Option<Instant> = None;
+    let mut buffer = [0u8; 1024];
+
+    while Instant::now() < deadline {
+        // the request or its answer may be lost
+        if requested_at.is_none_or(|requested_at| requested_at.elapsed() >= READY_INTERVAL) {
+            serialize_and_send(socket, "P2X_RR", &P2XRequestRelayMessage { passphrase: passphrase.clone() })?;
+            requested_at = Some(Instant::now());
+        }
+        let Ok(len) = socket.recv(&mut buffer) else {
+            continue;
+        };
+        let message = &buffer[..len];
+        match message.split(|byte| *byte == b' ').next().unwrap_or_default() {
+            b"X2P_RA" => return parse_and_expect(message, "X2P_RA"),
+            b"ERROR" => {
+                let error = String::from_utf8_lossy(&message[len.min(6)..]).trim().to_string();
+                return Err(NudgeError::RelayRefused(error));
+            }
+            // the handshake of the peer, if the relay-server forwards its packets already
+            _ => {}
+        }
+    }
+    Err(NudgeError::PunchFailed)
+}
 
+/// Sends a burst of packets and completes the handshake, if the peer was heard.
+///
+/// # Returns
+///
+/// * `Result<bool>` - Whether packets of the peer arrived.
+pub fn punch(socket: &UdpSocket, attempt: &PunchAttempt) -> Result<bool> {
     // Send packets to establish the connection
-    send_packets(socket, 40, 50)?;
+    send_packets(socket, attempt.packets as usize, attempt.interval_ms)?;
 
     // Wait for the connection to be established
-    wait_for_condition(socket, |received| received == 1)?;
+    if wait_for_condition(socket, |received| received == 1) == 0 {
+        return Ok(false);
+    }
     socket.send(&[0, 0])?;
     socket.send(&[0, 0])?;
 
-    wait_for_condition(socket, |received| received != 2)?;
-    wait_for_condition(socket, |received| received == 2)?;
+    wait_for_condition(socket, |received| received != 2);
+    wait_for_condition(socket, |received| received == 2);
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::PortAllocation;
+
+    fn connected_pair() -> (UdpSocket, UdpSocket) {
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        first.connect(second.local_addr().unwrap()).unwrap();
+        second.connect(first.local_addr().unwrap()).unwrap();
+        (first, second)
+    }
+
+    #[test]
+    fn test_plan_starting_in() {
+        let before = Instant::now();
+        let plan = PunchPlan::starting_in(200, Vec::new());
+        assert!(plan.start >= before + Duration::from_millis(200));
+
+        // the punching started already, the attempts keep their times
+        let plan = PunchPlan::starting_in(-200, Vec::new());
+        assert!(plan.start <= Instant::now() - Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_coordinate_punch_answers_pings() {
+        let (peer_socket, relay_socket) = connected_pair();
+        let passphrase = Passphrase::from("a-b-c".to_string());
+
+        let relay = thread::spawn(move || {
+            serialize_and_send(&relay_socket, "X2P_PING", &X2PPingMessage { passphrase: Passphrase::from("a-b-c".to_string()) }).unwrap();
+            let mut buffer = [0u8; 1024];
+            let len = relay_socket.recv(&mut buffer).unwrap();
+            let pong: P2XPongMessage = parse_and_expect(&buffer[..len], "P2X_PONG").unwrap();
+            assert_eq!(pong.passphrase, Passphrase::from("a-b-c".to_string()));
+            assert_eq!(pong.probes, PROBE_SOCKETS);
+            serialize_and_send(&relay_socket, "X2P_SP", &X2PStartPunchingMessage {
+                start_in_ms: 300,
+                attempts: PUNCH_ATTEMPTS[..1].to_vec(),
+                allocation: Some(PortAllocation::Random),
+                peer_allocation: Some(PortAllocation::Preserving),
+            }).unwrap();
+        });
+
+        let before = Instant::now();
+        let plan = coordinate_punch(&peer_socket, &passphrase, true).unwrap();
+        relay.join().unwrap();
+        assert!(plan.start >= before + Duration::from_millis(300));
+        assert_eq!(plan.attempts, PUNCH_ATTEMPTS[..1].to_vec());
+        assert_eq!(plan.prediction, Some(Prediction { own: PortAllocation::Random, peer: PortAllocation::Preserving }));
+    }
+
+    #[test]
+    fn test_sends_probes_from_new_sockets() {
+        let (peer_socket, _) = connected_pair();
+        let relay_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        relay_socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        peer_socket.connect(relay_socket.local_addr().unwrap()).unwrap();
+
+        let passphrase = Passphrase::from("a-b-c".to_string());
+        assert_eq!(send_probes(&peer_socket, &passphrase, false), PROBE_SOCKETS);
+        let mut buffer = [0u8; 1024];
+        let mut probes: Vec<(u8, SocketAddr)> = Vec::new();
+        while let Ok((len, addr)) = relay_socket.recv_from(&mut buffer) {
+            let probe: P2XProbeMessage = parse_and_expect(&buffer[..len], "P2X_PROBE").unwrap();
+            assert!(!probe.sender);
+            assert_eq!(probe.local_port, canonical(addr).port());
+            assert_ne!(canonical(addr).port(), peer_socket.local_addr().unwrap().port());
+            probes.push((probe.index, canonical(addr)));
+        }
+        // every probe twice, each socket has its own port
+        assert_eq!(probes.len(), 2 * PROBE_SOCKETS as usize);
+        probes.sort();
+        probes.dedup();
+        assert_eq!(probes.len(), PROBE_SOCKETS as usize);
+    }
+
+    #[test]
+    fn test_punch_retries_with_the_next_attempt() {
+        let (early_socket, late_socket) = connected_pair();
+        let attempts = vec![
+            PunchAttempt { offset_ms: 0, packets: 5, interval_ms: 20 },
+            PunchAttempt { offset_ms: 1_500, packets: 5, interval_ms: 20 },
+        ];
+
+        // the late peer misses the first attempt of the early one, their next attempts meet
+        let early_plan = PunchPlan::starting_in(0, attempts);
+        let late_plan = early_plan.delayed(Duration::from_millis(1_500));
+        let late = thread::spawn(move || init_socket(&late_socket, &late_plan));
+
+        init_socket(&early_socket, &early_plan).unwrap();
+        late.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_falls_back_to_the_relay_server() {
+        let relay_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay_addr = relay_socket.local_addr().unwrap();
+        // never answers, so the hole punching fails
+        let silent_peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent_peer.local_addr().unwrap();
+
+        let mut peers: Vec<UdpSocket> = (0..2).map(|_| {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.connect(relay_addr).unwrap();
+            socket
+        }).collect();
+        let peer_addrs: Vec<SocketAddr> = peers.iter().map(|peer| peer.local_addr().unwrap()).collect();
+
+        // grants every request and forwards everything else to the other peer
+        let relay = thread::spawn(move || {
+            relay_socket.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+            let mut buffer = [0u8; 1024];
+            while let Ok((len, addr)) = relay_socket.recv_from(&mut buffer) {
+                if buffer.starts_with(b"P2X_RR ") {
+                    let grant = X2PRelayAcceptedMessage { quota: 1_000, limit: None };
+                    let response = format!("X2P_RA {}", serde_json::to_string(&grant).unwrap());
+                    relay_socket.send_to(response.as_bytes(), addr).unwrap();
+                } else {
+                    let other = if addr == peer_addrs[0] { peer_addrs[1] } else { peer_addrs[0] };
+                    relay_socket.send_to(&buffer[..len], other).unwrap();
+                }
+            }
+        });
+
+        let attempts = vec![PunchAttempt { offset_ms: 0, packets: 2, interval_ms: 10 }];
+        let rendezvous = Rendezvous {
+            passphrase: Passphrase::from("a-b-c".to_string()),
+            plan: PunchPlan::starting_in(0, attempts),
+            relay_addr: Some(relay_addr),
+            direct: false,
+            peers: Vec::new(),
+        };
+        let silent = &PeerCandidates::new(silent_addr, Vec::new(), &[], true);
+        let rendezvous = &rendezvous;
+        let routes: Vec<PeerRoute> = thread::scope(|scope| {
+            let handles: Vec<_> = peers.iter_mut()
+                .map(|peer| scope.spawn(move || connect_to_peer(peer, silent, rendezvous).unwrap()))
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        relay.join().unwrap();
+
+        for (peer, route) in peers.iter().zip(routes) {
+            assert_eq!(route.relay_grant().map(|grant| grant.quota), Some(1_000));
+            assert_eq!(peer.peer_addr().unwrap(), relay_addr);
+        }
+    }
+
+    #[test]
+    fn test_fails_without_a_relay_server() {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // never answers, and there is no relay-server to fall back to
+        let silent_peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let rendezvous = Rendezvous {
+            passphrase: Passphrase::from("a-b-c".to_string()),
+            plan: PunchPlan::starting_in(0, vec![PunchAttempt { offset_ms: 0, packets: 2, interval_ms: 10 }]),
+            relay_addr: None,
+            direct: false,
+            peers: Vec::new(),
+        };
+        let silent = PeerCandidates::new(silent_peer.local_addr().unwrap(), Vec::new(), &[], false);
+        assert!(matches!(connect_to_peer(&mut socket, &silent, &rendezvous), Err(NudgeError::PunchFailed)));
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("relay.example.com:4000").unwrap(), ("relay.example.com", 4000));
+        assert_eq!(split_host_port("10.0.0.1:9000").unwrap(), ("10.0.0.1", 9000));
+        assert_eq!(split_host_port("[::1]:9000").unwrap(), ("[::1]", 9000));
+        assert!(matches!(split_host_port("relay.example.com"), Err(NudgeError::InvalidAddress(_))));
+        assert!(matches!(split_host_port(":9000"), Err(NudgeError::InvalidAddress(_))));
+        assert!(matches!(split_host_port("host:99999"), Err(NudgeError::InvalidAddress(_))));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let v6: SocketAddr = "[::1]:4000".parse().unwrap();
+        assert_eq!(resolve("::1", 4000).unwrap(), vec![v6]);
+        assert_eq!(resolve("[::1]", 4000).unwrap(), vec![v6]);
+        assert_eq!(resolve("127.0.0.1", 4000).unwrap(), vec!["127.0.0.1:4000".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_both_peers_try_the_same_pairs() {
+        let addr = |text: &str| text.parse::<SocketAddr>().unwrap();
+        let public = addr("1.2.3.4:5");
+        let (s0, s1, s6) = (addr("10.0.0.1:100"), addr("192.168.0.1:100"), addr("[2a01:4f8::1]:100"));
+        let (r0, r1, r6) = (addr("10.0.0.2:200"), addr("192.168.0.2:200"), addr("[2a01:4f8::2]:200"));
+        let ips = |addrs: &[SocketAddr]| addrs.iter().map(SocketAddr::ip).collect::<Vec<IpAddr>>();
+
+        // pair k consists of the sender's address k / 2 and the receiver's address k % 2
+        let sender = PeerCandidates::new(public, vec![r0, r1], &ips(&[s0, s1]), true);
+        assert_eq!(sender.local, vec![r0, r1, r0, r1]);
+        let receiver = PeerCandidates::new(public, vec![s0, s1], &ips(&[r0, r1]), false);
+        assert_eq!(receiver.local, vec![s0, s0, s1, s1]);
+
+        // IPv4 addresses are not paired with IPv6 ones
+        let sender = PeerCandidates::new(public, vec![r0, r6], &ips(&[s0, s6]), true);
+        assert_eq!(sender.local, vec![r0, r6]);
+        let receiver = PeerCandidates::new(public, vec![s0, s6], &ips(&[r0, r6]), false);
+        assert_eq!(receiver.local, vec![s0, s6]);
+
+        // a peer without local addresses has no pairs
+        assert!(PeerCandidates::new(public, vec![r0, r1], &[], true).local.is_empty());
+        assert!(PeerCandidates::new(public, Vec::new(), &ips(&[r0, r1]), false).local.is_empty());
+    }
+
+    #[test]
+    fn test_prefers_local_addresses() {
+        let (mut first, mut second) = connected_pair();
+        let local_addrs = [second.local_addr().unwrap(), first.local_addr().unwrap()];
+        // never answers, so only the local addresses work
+        let silent_peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let public = silent_peer.local_addr().unwrap();
+
+        let rendezvous = Rendezvous {
+            passphrase: Passphrase::from("a-b-c".to_string()),
+            plan: PunchPlan::starting_in(0, PUNCH_ATTEMPTS[..1].to_vec()),
+            relay_addr: None,
+            direct: false,
+            peers: Vec::new(),
+        };
+        let routes: Vec<PeerRoute> = thread::scope(|scope| {
+            let handles: Vec<_> = [&mut first, &mut second].into_iter()
+                .zip(local_addrs)
+                .enumerate()
+                .map(|(index, (socket, local_addr))| {
+                    let peer = PeerCandidates::new(public, vec![local_addr], &[local_addr.ip()], index == 0);
+                    let rendezvous = &rendezvous;
+                    scope.spawn(move || connect_to_peer(socket, &peer, rendezvous).unwrap())
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        assert!(matches!(routes[0], PeerRoute::Local(addr) if addr == local_addrs[0]));
+        assert!(matches!(routes[1], PeerRoute::Local(addr) if addr == local_addrs[1]));
+    }
+}
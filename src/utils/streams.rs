@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::thread::ScopedJoinHandle;
+
+use crate::error::{NudgeError, Result};
+
+/// Maximum number of parallel streams a transfer may use
+pub const MAX_STREAMS: u8 = 16;
+
+/// Stream ranges start at multiples of this many bytes, independent of the chunk size of either peer
+pub const RANGE_ALIGNMENT: u64 = 64 * 1024;
+
+/// Splits a file into one contiguous byte range per stream.
+///
+/// Both peers compute the ranges independently, so the result only depends on the arguments.
+///
+/// # Arguments
+///
+/// * `file_size` - Size of the file in bytes.
+/// * `streams` - Number of streams (at least 1).
+/// * `alignment` - Range boundaries are multiples of this value.
+///
+/// # Returns
+///
+/// `Vec<Range<u64>>` - One range per stream, empty ranges if the file is too small to give every stream a block.
+pub fn split_ranges(file_size: u64, streams: u8, alignment: u64) -> Vec<Range<u64>> {
+    let streams = streams.max(1) as u64;
+    let alignment = alignment.max(1);
+    let blocks = file_size.div_ceil(alignment);
+    let blocks_per_stream = blocks.div_ceil(streams);
+
+    (0..streams)
+        .map(|index| {
+            let start = (index * blocks_per_stream * alignment).min(file_size);
+            let end = ((index + 1) * blocks_per_stream * alignment).min(file_size);
+            start..end
+        })
+        .collect()
+}
+
+/// Waits for all stream threads and collects their results in stream order, or returns the first error.
+pub fn join_streams<T>(handles: Vec<ScopedJoinHandle<Result<T>>>) -> Result<Vec<T>> {
+    handles.into_iter()
+        .map(|handle| handle.join().unwrap_or(Err(NudgeError::StreamPanicked)))
+        .collect()
+}
+
+/// Reads from `file` at `offset` without moving the shared file cursor.
+pub fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_at(file, buffer, offset)
+    }
+    #[cfg(windows)]
+    {
+        std::os::windows::fs::FileExt::seek_read(file, buffer, offset)
+    }
+}
+
+/// Fills the whole `buffer` from `file` at `offset` without moving the shared file cursor.
+pub fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match read_at(file, &mut buffer[read..], offset + read as u64) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the whole `buffer` into `file` at `offset` without moving the shared file cursor.
+pub fn write_all_at(file: &File, buffer: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::write_all_at(file, buffer, offset)
+    }
+    #[cfg(windows)]
+    {
+        let mut written = 0;
+        while written < buffer.len() {
+            match std::os::windows::fs::FileExt::seek_write(file, &buffer[written..], offset + written as u64) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_ranges_single_stream() {
+        assert_eq!(split_ranges(10_000, 1, 4096), vec![0..10_000]);
+    }
+
+    #[test]
+    fn test_split_ranges_aligned() {
+        let ranges = split_ranges(10 * 4096 + 1, 3, 4096);
+        assert_eq!(ranges, vec![0..4 * 4096, 4 * 4096..8 * 4096, 8 * 4096..10 * 4096 + 1]);
+    }
+
+    #[test]
+    fn test_split_ranges_more_streams_than_blocks() {
+        let ranges = split_ranges(100, 4, 4096);
+        assert_eq!(ranges, vec![0..100, 100..100, 100..100, 100..100]);
+    }
+
+    #[test]
+    fn test_split_ranges_empty_file() {
+        assert_eq!(split_ranges(0, 2, 4096), vec![0..0, 0..0]);
+    }
+}
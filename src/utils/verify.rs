@@ -0,0 +1,270 @@
+use std::fs::File;
+use std::ops::Range;
+
+use blake3::hazmat::{ChainingValue, HasherExt, left_subtree_len, merge_subtrees_non_root, merge_subtrees_root, Mode};
+use blake3::Hasher;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::streams::{RANGE_ALIGNMENT, read_exact_at};
+
+/// Size of the parts of the file which are verified on their own.
+///
+/// Stream ranges start at multiples of it, so every stream verifies whole groups.
+pub const GROUP_SIZE: u64 = RANGE_ALIGNMENT;
+
+const CV_SIZE: usize = size_of::<ChainingValue>();
+
+/// Chaining values of every group of a file, the inner nodes of the BLAKE3 tree above them
+/// can be derived from these (like a Bao outboard cut off at the group level).
+///
+/// The root of that tree is the ordinary BLAKE3 hash of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outboard {
+    file_size: u64,
+    /// Empty if the file is a single group, its hash is the root hash then
+    cvs: Vec<ChainingValue>,
+    root: blake3::Hash,
+}
+
+impl Outboard {
+    /// Hashes the file group by group, in a single pass.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::Io` if the file cannot be read
+    pub fn compute(file: &File, mut on_progress: impl FnMut(u64)) -> Result<Outboard> {
+        let file_size = file.metadata()?.len();
+        let mut buffer = vec![0u8; GROUP_SIZE as usize];
+
+        if file_size <= GROUP_SIZE {
+            let buffer = &mut buffer[..file_size as usize];
+            read_exact_at(file, buffer, 0)?;
+            on_progress(file_size);
+            return Ok(Outboard { file_size, cvs: Vec::new(), root: blake3::hash(buffer) });
+        }
+
+        let cvs = (0..file_size).step_by(GROUP_SIZE as usize)
+            .map(|offset| {
+                let buffer = &mut buffer[..group_len(file_size, offset) as usize];
+                read_exact_at(file, buffer, offset)?;
+                on_progress(buffer.len() as u64);
+                Ok(Hasher::new().set_input_offset(offset).update(buffer).finalize_non_root())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let root = root_of(&cvs, file_size);
+        Ok(Outboard { file_size, cvs, root })
+    }
+
+    /// Decodes the root hash and the chaining values sent by the sender and checks that they belong together.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::InvalidOutboard` if the number of values does not fit the file size,
+    /// or `NudgeError::HashMismatch` if they do not belong to the root hash.
+    pub fn decode(file_size: u64, bytes: &[u8]) -> Result<Outboard> {
+        let groups = if file_size <= GROUP_SIZE { 0 } else { file_size.div_ceil(GROUP_SIZE) };
+        if bytes.len() as u64 != (groups + 1) * CV_SIZE as u64 {
+            return Err(NudgeError::InvalidOutboard);
+        }
+
+        let (root, cvs) = bytes.split_at(CV_SIZE);
+        let root = blake3::Hash::from_bytes(root.try_into().unwrap());
+        let cvs: Vec<ChainingValue> = cvs.chunks_exact(CV_SIZE)
+            .map(|cv| cv.try_into().unwrap())
+            .collect();
+        if !cvs.is_empty() {
+            let actual = root_of(&cvs, file_size);
+            if actual != root {
+                return Err(NudgeError::HashMismatch(root.to_hex().to_string(), actual.to_hex().to_string()));
+            }
+        }
+        Ok(Outboard { file_size, cvs, root })
+    }
+
+    /// The root hash followed by the chaining values of the groups.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.root.as_bytes().to_vec();
+        bytes.extend(self.cvs.iter().flatten());
+        bytes
+    }
+
+    /// The BLAKE3 hash of the whole file.
+    pub fn root(&self) -> blake3::Hash {
+        self.root
+    }
+}
+
+/// Length of the group starting at `offset`, only the last group of a file may be shorter.
+fn group_len(file_size: u64, offset: u64) -> u64 {
+    (file_size - offset).min(GROUP_SIZE)
+}
+
+/// Merges the chaining values of the groups into the root hash, the same way BLAKE3 builds its tree.
+fn root_of(cvs: &[ChainingValue], file_size: u64) -> blake3::Hash {
+    let left_len = left_subtree_len(file_size);
+    merge_subtrees_root(&subtree_cv(cvs, 0, left_len), &subtree_cv(cvs, left_len, file_size - left_len), Mode::Hash)
+}
+
+/// Chaining value of the subtree over `len` bytes from `start`, which is a multiple of `GROUP_SIZE`.
+fn subtree_cv(cvs: &[ChainingValue], start: u64, len: u64) -> ChainingValue {
+    if len <= GROUP_SIZE {
+        return cvs[(start / GROUP_SIZE) as usize];
+    }
+    // left subtrees are powers of two larger than a group, so the split is at a group boundary
+    let left_len = left_subtree_len(len);
+    merge_subtrees_non_root(&subtree_cv(cvs, start, left_len), &subtree_cv(cvs, start + left_len, len - left_len), Mode::Hash)
+}
+
+/// Verifies the data of one stream group by group while it is received.
+pub struct RangeVerifier<'a> {
+    outboard: &'a Outboard,
+    range: Range<u64>,
+    offset: u64,
+    hasher: Hasher,
+}
+
+impl<'a> RangeVerifier<'a> {
+    /// Creates a verifier for `range`, which has to start at a group boundary.
+    pub fn new(outboard: &'a Outboard, range: Range<u64>) -> Self {
+        debug_assert_eq!(range.start % GROUP_SIZE, 0);
+        RangeVerifier { outboard, offset: range.start, hasher: group_hasher(outboard, range.start), range }
+    }
+
+    /// Adds the next data of the range, every group is checked as soon as it is complete.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::CorruptData` if a group does not match its hash.
+    pub fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if self.offset >= self.range.end {
+                return Err(NudgeError::CorruptData(self.offset));
+            }
+            let group_start = self.offset - self.offset % GROUP_SIZE;
+            let group_end = (group_start + GROUP_SIZE).min(self.outboard.file_size);
+            let len = (group_end - self.offset).min(data.len() as u64) as usize;
+
+            self.hasher.update(&data[..len]);
+            self.offset += len as u64;
+            data = &data[len..];
+
+            if self.offset == group_end {
+                self.check_group(group_start)?;
+                if self.offset < self.outboard.file_size {
+                    self.hasher = group_hasher(self.outboard, self.offset);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure the whole range was received.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::CorruptData` if data is missing at the end of the range.
+    pub fn finish(self) -> Result<()> {
+        if self.offset != self.range.end {
+            return Err(NudgeError::CorruptData(self.offset));
+        }
+        Ok(())
+    }
+
+    fn check_group(&self, group_start: u64) -> Result<()> {
+        let matches = if self.outboard.cvs.is_empty() {
+            self.hasher.finalize() == self.outboard.root
+        } else {
+            let expected = &self.outboard.cvs[(group_start / GROUP_SIZE) as usize];
+            // compared in constant time, like `blake3::Hash`
+            blake3::Hash::from_bytes(self.hasher.finalize_non_root()) == blake3::Hash::from_bytes(*expected)
+        };
+        if matches { Ok(()) } else { Err(NudgeError::CorruptData(group_start)) }
+    }
+}
+
+fn group_hasher(outboard: &Outboard, offset: u64) -> Hasher {
+    let mut hasher = Hasher::new();
+    if !outboard.cvs.is_empty() {
+        hasher.set_input_offset(offset);
+    }
+    hasher
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index * 31 % 251) as u8).collect()
+    }
+
+    fn outboard_of(name: &str, content: &[u8]) -> Outboard {
+        let path = std::env::temp_dir().join(format!("nudge-verify-{}-{}", name, std::process::id()));
+        File::create(&path).unwrap().write_all(content).unwrap();
+        let outboard = Outboard::compute(&File::open(&path).unwrap(), |_| {}).unwrap();
+        std::fs::remove_file(path).unwrap();
+        outboard
+    }
+
+    #[test]
+    fn test_root_is_the_file_hash() {
+        for len in [0, 1, 1024, GROUP_SIZE as usize, GROUP_SIZE as usize + 1, 3 * GROUP_SIZE as usize + 5000, 8 * GROUP_SIZE as usize] {
+            let content = content(len);
+            let outboard = outboard_of("root", &content);
+            assert_eq!(outboard.root(), blake3::hash(&content), "{} bytes", len);
+
+            let decoded = Outboard::decode(len as u64, &outboard.encode()).unwrap();
+            assert_eq!(decoded, outboard);
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_wrong_values() {
+        let outboard = outboard_of("decode", &content(3 * GROUP_SIZE as usize));
+        let mut encoded = outboard.encode();
+
+        assert!(matches!(Outboard::decode(3 * GROUP_SIZE, &encoded[1..]), Err(NudgeError::InvalidOutboard)));
+        assert!(matches!(Outboard::decode(GROUP_SIZE, &encoded), Err(NudgeError::InvalidOutboard)));
+        encoded[CV_SIZE] ^= 1;
+        assert!(matches!(Outboard::decode(3 * GROUP_SIZE, &encoded), Err(NudgeError::HashMismatch(_, _))));
+    }
+
+    #[test]
+    fn test_verifier_accepts_ranges_in_any_packet_size() {
+        let content = content(5 * GROUP_SIZE as usize + 777);
+        let outboard = outboard_of("ranges", &content);
+        let len = content.len() as u64;
+
+        for range in [0..2 * GROUP_SIZE, 2 * GROUP_SIZE..len] {
+            let mut verifier = RangeVerifier::new(&outboard, range.clone());
+            for packet in content[range.start as usize..range.end as usize].chunks(1400) {
+                verifier.update(packet).unwrap();
+            }
+            verifier.finish().unwrap();
+        }
+
+        // a single group file is verified against the root hash
+        let small = content[..5000].to_vec();
+        let outboard = outboard_of("small", &small);
+        let mut verifier = RangeVerifier::new(&outboard, 0..5000);
+        verifier.update(&small).unwrap();
+        verifier.finish().unwrap();
+    }
+
+    #[test]
+    fn test_verifier_rejects_corrupt_group_at_once() {
+        let mut content = content(3 * GROUP_SIZE as usize);
+        let outboard = outboard_of("corrupt", &content);
+        content[GROUP_SIZE as usize + 10] ^= 0xff;
+
+        let mut verifier = RangeVerifier::new(&outboard, 0..3 * GROUP_SIZE);
+        verifier.update(&content[..GROUP_SIZE as usize]).unwrap();
+        let result = verifier.update(&content[GROUP_SIZE as usize..2 * GROUP_SIZE as usize]);
+        assert!(matches!(result, Err(NudgeError::CorruptData(offset)) if offset == GROUP_SIZE));
+
+        let verifier = RangeVerifier::new(&outboard, 0..3 * GROUP_SIZE);
+        assert!(matches!(verifier.finish(), Err(NudgeError::CorruptData(0))));
+    }
+}
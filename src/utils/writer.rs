@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::sync::mpsc::{channel, Receiver, sync_channel, SyncSender};
+use std::thread::{Scope, ScopedJoinHandle};
+
+use indicatif::ProgressBar;
+
+use crate::error::{NudgeError, Result};
+use crate::utils::streams::write_all_at;
+
+/// Number of chunks which may wait for the disk before receiving blocks
+pub const WRITE_QUEUE_DEPTH: usize = 1024;
+
+/// Writes chunks at their offsets on a dedicated thread,
+/// so a slow disk does not keep the receiving thread from acknowledging packets.
+///
+/// Written buffers are handed back through `buffer`, so the receiver can reuse them.
+pub struct ChunkWriter<'scope> {
+    queue: SyncSender<(u64, Vec<u8>)>,
+    recycled: Receiver<Vec<u8>>,
+    handle: ScopedJoinHandle<'scope, Result<()>>,
+}
+
+impl<'scope> ChunkWriter<'scope> {
+    /// Starts the writer thread in `scope`.
+    ///
+    /// # Arguments
+    ///
+    /// * `scope` - The scope the writer thread lives in.
+    /// * `file` - The file to write into.
+    /// * `progress_bar` - Advanced once a chunk was written.
+    pub fn spawn<'env>(
+        scope: &'scope Scope<'scope, 'env>,
+        file: &'env File,
+        progress_bar: &'env ProgressBar,
+    ) -> Self {
+        let (queue, chunks) = sync_channel::<(u64, Vec<u8>)>(WRITE_QUEUE_DEPTH);
+        let (recycle, recycled) = channel();
+        let handle = scope.spawn(move || {
+            for (offset, buffer) in chunks {
+                write_all_at(file, &buffer, offset)?;
+                progress_bar.inc(buffer.len() as u64);
+                // the receiver may already be done
+                let _ = recycle.send(buffer);
+            }
+            Ok(())
+        });
+        ChunkWriter { queue, recycled, handle }
+    }
+
+    /// Returns a buffer the writer is done with, or a new one if none is available.
+    pub fn buffer(&self) -> Vec<u8> {
+        self.recycled.try_recv().unwrap_or_default()
+    }
+
+    /// Queues `buffer` to be written at `offset`, blocks while the queue is full.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NudgeError::WriterStopped` if the writer thread failed, `finish` returns the cause.
+    pub fn write(&self, offset: u64, buffer: Vec<u8>) -> Result<()> {
+        self.queue.send((offset, buffer)).map_err(|_| NudgeError::WriterStopped)
+    }
+
+    /// Waits until all queued chunks were written.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error which stopped the writer thread, if any.
+    pub fn finish(self) -> Result<()> {
+        drop(self.queue);
+        self.handle.join().unwrap_or(Err(NudgeError::StreamPanicked))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Read;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_writes_chunks_at_offsets() {
+        let path = std::env::temp_dir().join(format!("nudge-writer-{}", std::process::id()));
+        let file = OpenOptions::new().create(true).truncate(true).read(true).write(true).open(&path).unwrap();
+        let progress_bar = ProgressBar::hidden();
+
+        thread::scope(|scope| {
+            let writer = ChunkWriter::spawn(scope, &file, &progress_bar);
+            // out of order on purpose
+            writer.write(3, b"def".to_vec()).unwrap();
+            writer.write(0, b"abc".to_vec()).unwrap();
+            writer.finish().unwrap();
+        });
+
+        let mut content = String::new();
+        File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(content, "abcdef");
+        assert_eq!(progress_bar.position(), 6);
+    }
+
+    #[test]
+    fn test_reports_write_errors() {
+        let path = std::env::temp_dir().join(format!("nudge-writer-ro-{}", std::process::id()));
+        File::create(&path).unwrap();
+        // read-only, every write fails
+        let file = File::open(&path).unwrap();
+        let progress_bar = ProgressBar::hidden();
+
+        let result = thread::scope(|scope| {
+            let writer = ChunkWriter::spawn(scope, &file, &progress_bar);
+            let _ = writer.write(0, b"abc".to_vec());
+            writer.finish()
+        });
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(NudgeError::Io(_))));
+    }
+}